@@ -0,0 +1,6 @@
+CREATE TABLE sessions (
+    id INTEGER NOT NULL PRIMARY KEY,
+    token TEXT NOT NULL UNIQUE,
+    user_id INTEGER NOT NULL REFERENCES users(id),
+    created_at TIMESTAMP NOT NULL
+)
@@ -0,0 +1,11 @@
+CREATE TEMPORARY TABLE sessions_bk(id, token, user_id, created_at);
+INSERT INTO sessions_bk SELECT id, token, user_id, created_at FROM sessions;
+DROP TABLE sessions;
+CREATE TABLE sessions (
+    id INTEGER NOT NULL PRIMARY KEY,
+    token TEXT NOT NULL UNIQUE,
+    user_id INTEGER NOT NULL REFERENCES users(id),
+    created_at TIMESTAMP NOT NULL
+);
+INSERT INTO sessions SELECT id, token, user_id, created_at FROM sessions_bk;
+DROP TABLE sessions_bk;
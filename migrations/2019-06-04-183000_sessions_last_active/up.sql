@@ -0,0 +1,2 @@
+ALTER TABLE sessions ADD COLUMN last_active TIMESTAMP DEFAULT '1970-01-01 00:00:00' NOT NULL;
+UPDATE sessions SET last_active = created_at;
@@ -0,0 +1,13 @@
+DROP TABLE refresh_tokens;
+CREATE TEMPORARY TABLE sessions_bk(id, token, user_id, created_at, last_active);
+INSERT INTO sessions_bk SELECT id, token, user_id, created_at, last_active FROM sessions;
+DROP TABLE sessions;
+CREATE TABLE sessions (
+    id INTEGER NOT NULL PRIMARY KEY,
+    token TEXT NOT NULL UNIQUE,
+    user_id INTEGER NOT NULL REFERENCES users(id),
+    created_at TIMESTAMP NOT NULL,
+    last_active TIMESTAMP DEFAULT '1970-01-01 00:00:00' NOT NULL
+);
+INSERT INTO sessions SELECT id, token, user_id, created_at, last_active FROM sessions_bk;
+DROP TABLE sessions_bk;
@@ -0,0 +1,11 @@
+CREATE TABLE refresh_tokens (
+    id INTEGER NOT NULL PRIMARY KEY,
+    token TEXT NOT NULL UNIQUE,
+    user_id INTEGER NOT NULL REFERENCES users(id),
+    family TEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL,
+    expires_at TIMESTAMP NOT NULL,
+    used BOOLEAN DEFAULT FALSE NOT NULL
+);
+CREATE INDEX refresh_tokens_family ON refresh_tokens(family);
+ALTER TABLE sessions ADD COLUMN family TEXT;
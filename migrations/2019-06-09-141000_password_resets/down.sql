@@ -0,0 +1,13 @@
+DROP TABLE password_resets;
+CREATE TEMPORARY TABLE users_bk(id, login, hash, role, active);
+INSERT INTO users_bk SELECT id, login, hash, role, active FROM users;
+DROP TABLE users;
+CREATE TABLE users (
+    id INTEGER NOT NULL PRIMARY KEY,
+    login TEXT NOT NULL,
+    hash TEXT NOT NULL,
+    role TEXT NOT NULL,
+    active BOOLEAN DEFAULT TRUE NOT NULL
+);
+INSERT INTO users SELECT id, login, hash, role, active FROM users_bk;
+DROP TABLE users_bk;
@@ -0,0 +1,3 @@
+DROP INDEX logs_ip_addr_time;
+DROP INDEX logs_login_time;
+DROP TABLE lockout_clears;
@@ -0,0 +1,8 @@
+CREATE TABLE lockout_clears (
+    id INTEGER NOT NULL PRIMARY KEY,
+    login TEXT,
+    ip_addr TEXT,
+    cleared_at TIMESTAMP NOT NULL
+);
+CREATE INDEX logs_login_time ON logs(login, logging_time);
+CREATE INDEX logs_ip_addr_time ON logs(ip_addr, logging_time);
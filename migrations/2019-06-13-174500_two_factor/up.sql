@@ -0,0 +1,21 @@
+CREATE TABLE two_factor (
+    user_id INTEGER NOT NULL PRIMARY KEY REFERENCES users(id),
+    secret TEXT NOT NULL,
+    enabled BOOLEAN DEFAULT FALSE NOT NULL,
+    last_step BIGINT
+);
+CREATE TABLE recovery_codes (
+    id INTEGER NOT NULL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users(id),
+    code TEXT NOT NULL,
+    used BOOLEAN DEFAULT FALSE NOT NULL
+);
+CREATE INDEX recovery_codes_user_id ON recovery_codes(user_id);
+CREATE TABLE login_challenges (
+    id INTEGER NOT NULL PRIMARY KEY,
+    token TEXT NOT NULL UNIQUE,
+    user_id INTEGER NOT NULL REFERENCES users(id),
+    created_at TIMESTAMP NOT NULL,
+    expires_at TIMESTAMP NOT NULL,
+    attempts INTEGER DEFAULT 0 NOT NULL
+);
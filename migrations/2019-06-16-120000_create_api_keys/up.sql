@@ -0,0 +1,10 @@
+CREATE TABLE api_keys (
+    id INTEGER NOT NULL PRIMARY KEY,
+    name TEXT NOT NULL,
+    key TEXT NOT NULL UNIQUE,
+    prefix TEXT NOT NULL,
+    user_id INTEGER NOT NULL REFERENCES users(id),
+    scopes TEXT,
+    created_at TIMESTAMP NOT NULL,
+    last_used TIMESTAMP
+);
@@ -0,0 +1,4 @@
+CREATE TABLE revoked_tokens (
+    jti TEXT NOT NULL PRIMARY KEY,
+    expires_at TIMESTAMP NOT NULL
+);
@@ -0,0 +1,14 @@
+CREATE TABLE role_permissions (
+    role TEXT NOT NULL,
+    permission TEXT NOT NULL,
+    PRIMARY KEY (role, permission)
+);
+
+INSERT INTO role_permissions (role, permission) VALUES
+    ('ADMIN', 'songs:write'),
+    ('ADMIN', 'history:read'),
+    ('ADMIN', 'users:read'),
+    ('ADMIN', 'users:write'),
+    ('ADMIN', 'logs:read'),
+    ('ADMIN', 'api_keys:manage'),
+    ('EDITOR', 'songs:write');
@@ -0,0 +1,15 @@
+DROP TABLE oidc_logins;
+DROP INDEX users_oidc;
+CREATE TEMPORARY TABLE users_bk(id, login, hash, role, active, email);
+INSERT INTO users_bk SELECT id, login, hash, role, active, email FROM users;
+DROP TABLE users;
+CREATE TABLE users (
+    id INTEGER NOT NULL PRIMARY KEY,
+    login TEXT NOT NULL,
+    hash TEXT NOT NULL,
+    role TEXT NOT NULL,
+    active BOOLEAN DEFAULT TRUE NOT NULL,
+    email TEXT
+);
+INSERT INTO users SELECT id, login, hash, role, active, email FROM users_bk;
+DROP TABLE users_bk;
@@ -0,0 +1,12 @@
+ALTER TABLE users ADD COLUMN oidc_issuer TEXT;
+ALTER TABLE users ADD COLUMN oidc_subject TEXT;
+CREATE UNIQUE INDEX users_oidc ON users(oidc_issuer, oidc_subject);
+
+CREATE TABLE oidc_logins (
+    id INTEGER NOT NULL PRIMARY KEY,
+    state TEXT NOT NULL UNIQUE,
+    verifier TEXT NOT NULL,
+    nonce TEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL,
+    expires_at TIMESTAMP NOT NULL
+);
@@ -0,0 +1,2 @@
+ALTER TABLE sessions ADD COLUMN ip_addr TEXT DEFAULT '' NOT NULL;
+ALTER TABLE sessions ADD COLUMN user_agent TEXT DEFAULT '' NOT NULL;
@@ -0,0 +1,2 @@
+DROP TABLE songs_search_vocab;
+DROP TABLE songs_search;
@@ -0,0 +1,10 @@
+-- Artist, title and genre with folded diacritics, rowid is the song ID. Filled by the server.
+CREATE VIRTUAL TABLE songs_search USING fts5(
+    artist,
+    title,
+    genre,
+    tokenize = 'unicode61 remove_diacritics 2'
+);
+
+-- Indexed words, used to correct misspelled queries
+CREATE VIRTUAL TABLE songs_search_vocab USING fts5vocab(songs_search, 'row');
@@ -0,0 +1,13 @@
+CREATE TEMPORARY TABLE songs_bk(id, artist, title, genre, url, featured);
+INSERT INTO songs_bk SELECT id, artist, title, genre, url, featured FROM songs;
+DROP TABLE songs;
+CREATE TABLE songs (
+    id INTEGER NOT NULL PRIMARY KEY,
+    artist TEXT NOT NULL,
+    title TEXT NOT NULL,
+    genre TEXT NOT NULL,
+    url TEXT NOT NULL,
+    featured BOOLEAN DEFAULT FALSE NOT NULL
+);
+INSERT INTO songs SELECT id, artist, title, genre, url, featured FROM songs_bk;
+DROP TABLE songs_bk;
@@ -0,0 +1,2 @@
+-- Deleted songs are kept, so history entries still point to them
+ALTER TABLE songs ADD COLUMN deleted BOOLEAN DEFAULT FALSE NOT NULL;
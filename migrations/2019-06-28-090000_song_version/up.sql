@@ -0,0 +1,2 @@
+-- Incremented on every change, used for optimistic concurrency (ETag and If-Match)
+ALTER TABLE songs ADD COLUMN version INTEGER DEFAULT 1 NOT NULL;
@@ -0,0 +1,15 @@
+-- Values of the song before the change made by `user_id` at `changed_at`
+CREATE TABLE song_revisions (
+    id INTEGER PRIMARY KEY NOT NULL,
+    song_id INTEGER NOT NULL REFERENCES songs(id),
+    version INTEGER NOT NULL,
+    artist TEXT NOT NULL,
+    title TEXT NOT NULL,
+    genre TEXT NOT NULL,
+    url TEXT NOT NULL,
+    featured BOOLEAN NOT NULL,
+    user_id INTEGER REFERENCES users(id),
+    changed_at TIMESTAMP NOT NULL
+);
+
+CREATE INDEX song_revisions_song_id ON song_revisions(song_id);
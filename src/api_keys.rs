@@ -0,0 +1,170 @@
+use actix_web::error::{ErrorBadRequest, ErrorInternalServerError};
+use actix_web::web::{Data, Json, Path};
+use actix_web::Error;
+use chrono::NaiveDateTime;
+use futures::{
+    future::{self, Either},
+    Future,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::auth::{generate_token, ApiKey};
+use crate::db::api_keys::{CreateApiKey, GetApiKeys, RevokeApiKey};
+use crate::permissions::{self, ApiKeysManage, Authorized};
+use crate::Actors;
+
+/// Number of characters of the key stored in plain text, so admins can tell keys apart.
+const PREFIX_LENGTH: usize = 8;
+
+/// Dane nowego klucza API. Bez pola `scopes` klucz ma wszystkie uprawnienia właściciela. Zakresy
+/// są nazwami uprawnień: `songs:write`, `history:read`, `users:read`, `users:write`, `logs:read`,
+/// `api_keys:manage`. Zakres `history:read` jest też potrzebny do odczytu własnej historii
+/// (`GET /history`).
+#[derive(Debug, Deserialize)]
+pub struct ApiKeyData {
+    pub name: String,
+    #[serde(default)]
+    pub scopes: Option<Vec<String>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ApiKeyInfo {
+    pub id: i32,
+    pub name: String,
+    /// Początek klucza, pozwalający go rozpoznać.
+    pub prefix: String,
+    /// ID użytkownika, który utworzył klucz.
+    pub user_id: i32,
+    pub scopes: Option<Vec<String>>,
+    /// ISO 8601 / RFC 3339 format
+    pub created_at: NaiveDateTime,
+    /// ISO 8601 / RFC 3339 format
+    pub last_used: Option<NaiveDateTime>,
+}
+
+impl From<ApiKey> for ApiKeyInfo {
+    fn from(key: ApiKey) -> Self {
+        Self {
+            id: key.id,
+            name: key.name,
+            prefix: key.prefix,
+            user_id: key.user_id,
+            scopes: key
+                .scopes
+                .map(|s| s.split_whitespace().map(String::from).collect()),
+            created_at: key.created_at,
+            last_used: key.last_used,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct CreatedApiKey {
+    /// Klucz, który należy podać w nagłówku `Authorization: ApiKey <klucz>` lub
+    /// `X-Api-Key: <klucz>`. Jest zwracany tylko raz.
+    pub key: String,
+    #[serde(flatten)]
+    pub info: ApiKeyInfo,
+}
+
+/// `POST /api_keys`
+///
+/// Tworzy nowy klucz API należący do aktualnego użytkownika. Wymaga uprawnienia `api_keys:manage`
+/// i tokenu sesji. Zwraca BadRequest w przypadku pustej nazwy lub nieznanego zakresu.
+pub fn create_key(
+    data: Json<ApiKeyData>,
+    auth: Authorized<ApiKeysManage>,
+    actors: Data<Actors>,
+) -> impl Future<Item = Json<CreatedApiKey>, Error = Error> {
+    if let Err(e) = auth.require_session() {
+        return Either::A(future::err(e));
+    }
+
+    let data = data.into_inner();
+    if data.name.trim().is_empty() {
+        return Either::A(future::err(ErrorBadRequest("Missing name")));
+    }
+    if let Some(scopes) = &data.scopes {
+        if scopes.is_empty() {
+            return Either::A(future::err(ErrorBadRequest("Empty list of scopes")));
+        }
+        if let Some(unknown) = scopes
+            .iter()
+            .find(|s| !permissions::ALL.contains(&s.as_str()))
+        {
+            return Either::A(future::err(ErrorBadRequest(format!(
+                "Unknown scope {}",
+                unknown
+            ))));
+        }
+    }
+
+    let key = generate_token();
+    let encoded = base64::encode_config(&key, base64::URL_SAFE_NO_PAD);
+
+    let msg = CreateApiKey {
+        name: data.name.trim().to_string(),
+        key,
+        prefix: encoded[..PREFIX_LENGTH].to_string(),
+        user_id: auth.id,
+        scopes: data.scopes,
+    };
+
+    Either::B(
+        actors
+            .db
+            .send(msg)
+            .map_err(ErrorInternalServerError)
+            .and_then(|r| r.map_err(Error::from))
+            .map(move |api_key| {
+                Json(CreatedApiKey {
+                    key: encoded,
+                    info: api_key.into(),
+                })
+            }),
+    )
+}
+
+/// `GET /api_keys`
+///
+/// Zwraca wszystkie klucze API (bez samych kluczy). Wymaga uprawnienia `api_keys:manage` i
+/// tokenu sesji.
+pub fn keys(
+    auth: Authorized<ApiKeysManage>,
+    actors: Data<Actors>,
+) -> impl Future<Item = Json<Vec<ApiKeyInfo>>, Error = Error> {
+    if let Err(e) = auth.require_session() {
+        return Either::A(future::err(e));
+    }
+
+    Either::B(
+        actors
+            .db
+            .send(GetApiKeys)
+            .map_err(ErrorInternalServerError)
+            .and_then(|r| r.map_err(Error::from))
+            .map(|keys| Json(keys.into_iter().map(ApiKeyInfo::from).collect())),
+    )
+}
+
+/// `DELETE /api_keys/{id}`
+///
+/// Unieważnia klucz API. Wymaga uprawnienia `api_keys:manage` i tokenu sesji. Jeśli klucz nie
+/// istnieje, zwraca Not Found.
+pub fn revoke_key(
+    id: Path<i32>,
+    auth: Authorized<ApiKeysManage>,
+    actors: Data<Actors>,
+) -> impl Future<Item = (), Error = Error> {
+    if let Err(e) = auth.require_session() {
+        return Either::A(future::err(e));
+    }
+
+    Either::B(
+        actors
+            .db
+            .send(RevokeApiKey { id: *id })
+            .map_err(ErrorInternalServerError)
+            .and_then(|r| r.map_err(Error::from)),
+    )
+}
@@ -1,29 +1,43 @@
-use parking_lot::Mutex;
-use std::collections::HashMap;
-use std::sync::Arc;
-
 use actix_web::{
     dev::Payload,
     error::{ErrorBadRequest, ErrorForbidden, ErrorInternalServerError, ErrorUnauthorized},
-    web::{Data, Json},
+    http::{header, HeaderMap},
+    web::{Data, Json, Query},
     Error, FromRequest, HttpRequest,
 };
 use futures::{future, Future};
 use log::error;
 use rand::Rng;
 use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
 
-use crate::db::auth::GetUsers;
-use crate::db::auth::{CreateUser, DeleteAccount, Login};
-use crate::Actors;
+use crate::db::api_keys::GetApiKey;
+use crate::db::auth::{
+    ChangePassword, CreateUser, DeleteAccount, EraseAccount, FinishLogin, GetPasswordHash,
+    ReactivateAccount, SetRole, StartLogin,
+};
+use crate::db::auth::{Error as DbError, GetUsers};
+use crate::db::lockout::ClearLockout;
+use crate::db::passwords::{GetHashParamsUsage, HashParamsUsage};
+use crate::db::permissions::AuthInfo;
+use crate::db::sessions::{
+    CreateSession, DeleteSession, GetSession, IsTokenRevoked, RefreshSession, RevokeSignedToken,
+};
+use crate::db::two_factor::CreateLoginChallenge;
+use crate::db::DbExecutor;
+use crate::pagination::{Page, PageQuery};
+use crate::permissions::{Authorized, UsersRead, UsersWrite};
+use crate::signed_tokens::{self, Claims};
+use crate::{Actors, Config, SessionMode};
+use actix::Addr;
+use chrono::NaiveDateTime;
 use futures::future::Either;
 
-pub use crate::db::models::User;
+pub use crate::db::models::{ApiKey, User};
 use actix_web::web::Path;
 
 const TOKEN_SIZE: usize = 32;
-
-pub type Sessions = Arc<Mutex<HashMap<[u8; 32], User>>>;
+const API_KEY_SCHEME: &str = "ApiKey";
 
 /// Dane potrzebne do przeprowadzenia logowania.
 #[derive(Debug, Deserialize)]
@@ -32,39 +46,105 @@ pub struct LoginData {
     pub password: String,
 }
 
+/// Dane potrzebne do założenia konta. Adres email jest opcjonalny, ale bez niego nie można
+/// zresetować zapomnianego hasła.
+#[derive(Debug, Deserialize)]
+pub struct SignupData {
+    pub login: String,
+    pub password: String,
+    #[serde(default)]
+    pub email: Option<String>,
+}
+
 #[derive(Debug, Serialize)]
 pub struct LoginResponse {
     pub token: String,
+    /// Jednorazowy token pozwalający uzyskać nowy token sesji, patrz `refresh_token`. Nie jest
+    /// zwracany gdy `session_mode = "signed"`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub refresh_token: Option<String>,
     pub is_admin: bool,
+    /// Uprawnienia użytkownika, np. `songs:write`, patrz `GET /roles`.
+    pub permissions: Vec<String>,
+}
+
+/// Odpowiedź na `POST /login`: `LoginResponse` albo, jeśli użytkownik ma włączone
+/// uwierzytelnianie dwuskładnikowe, `TwoFactorChallenge`.
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum LoginResult {
+    Session(LoginResponse),
+    TwoFactorRequired(TwoFactorChallenge),
+}
+
+/// Token, który razem z kodem z aplikacji uwierzytelniającej należy wysłać na `POST /login/2fa`.
+#[derive(Debug, Serialize)]
+pub struct TwoFactorChallenge {
+    /// Zawsze `true`.
+    pub two_factor_required: bool,
+    pub challenge: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChangePasswordData {
+    pub old_password: String,
+    pub new_password: String,
+}
+
+/// Konto lub adres IP do odblokowania. Należy podać przynajmniej jedno z pól.
+#[derive(Debug, Deserialize)]
+pub struct LockoutData {
+    #[serde(default)]
+    pub login: Option<String>,
+    #[serde(default)]
+    pub ip_addr: Option<String>,
+}
+
+/// Nowa rola użytkownika: `CUSTOMER` lub rola z przypisanymi uprawnieniami, np. `ADMIN` albo
+/// `EDITOR`.
+#[derive(Debug, Deserialize)]
+pub struct RoleData {
+    pub role: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RefreshData {
+    pub refresh_token: String,
 }
 
 /// `POST /login`
 ///
 /// Zaloguj się. Zwraca token który należy wrzucić do nagłówka Authorization. W przypadku
 /// nieprawidłowej nazwy użytkownika lub hasła, albo złego formatu zapytania zwraca BadRequest.
+///
+/// Jeśli użytkownik ma włączone uwierzytelnianie dwuskładnikowe, zamiast tokenu zwracany jest
+/// `TwoFactorChallenge`, a logowanie należy dokończyć przez `POST /login/2fa`. Gdy w sekcji
+/// `[two_factor]` pliku konfiguracyjnego ustawiono `required_for_admins`, administrator bez
+/// uwierzytelniania dwuskładnikowego ma uprawnienia zwykłego użytkownika (`is_admin` jest
+/// `false`, a `permissions` są puste), dopóki go nie włączy.
+///
+/// Po zbyt wielu nieudanych próbach logowania na dane konto lub z danego adresu IP zwraca Too Many
+/// Requests z nagłówkiem Retry-After (w sekundach), patrz sekcja `[lockout]` pliku
+/// konfiguracyjnego. Gdy zbyt wiele zapytań czeka na sprawdzenie hasła, zwraca Service Unavailable
+/// (patrz sekcja `[hasher]`).
 pub fn login(
     login: Json<LoginData>,
-    data: Data<Sessions>,
     actors: Data<Actors>,
+    config: Data<Config>,
     request: HttpRequest,
-) -> impl Future<Item = Json<LoginResponse>, Error = Error> {
+) -> impl Future<Item = Json<LoginResult>, Error = Error> {
     let login = login.into_inner();
-    let msg = Login {
+    let password: Vec<u8> = login.password.into();
+    let client = client_info(&request, &config);
+    let msg = StartLogin {
         name: login.login,
-        password: login.password.into(),
-        ip_addr: request
-            .peer_addr()
-            .map(|addr| addr.ip().to_string())
-            .unwrap_or(String::new()),
-        user_agent: request
-            .headers()
-            .get("User-Agent")
-            .and_then(|h| h.to_str().ok())
-            .map(String::from)
-            .unwrap_or(String::new()),
+        ip_addr: client.ip_addr.clone(),
+        user_agent: client.user_agent.clone(),
+        lockout: config.lockout,
     };
 
-    let sessions = (*data).clone();
+    let db = actors.db.clone();
+    let hasher = actors.hasher.clone();
 
     actors
         .db
@@ -74,168 +154,931 @@ pub fn login(
 
             ErrorInternalServerError("")
         })
-        .and_then(move |res| match res {
-            Ok(user) => {
-                let mut buf = [0u8; TOKEN_SIZE];
-                let mut rng = rand::thread_rng();
-                rng.fill(&mut buf);
-                sessions.lock().insert(buf, user.clone());
-
-                Ok(Json(LoginResponse {
-                    token: base64::encode(&buf),
-                    is_admin: user.role == User::ROLE_ADMIN,
-                }))
-            }
-            Err(e) => Err(e.into()),
+        .and_then(|res| res.map_err(Error::from))
+        .and_then(move |attempt| {
+            let verify = if attempt.user.active {
+                Either::A(hasher.verify(attempt.user.hash.clone(), password.clone()))
+            } else {
+                Either::B(future::ok(false))
+            };
+
+            verify.from_err().and_then(move |success| {
+                let hash_config = config.password_hash;
+                // Failed rehash doesn't prevent the login, it's retried the next time
+                let rehash = if success && hash_config.is_outdated(&attempt.user.hash) {
+                    Either::A(hasher.hash(password, hash_config).then(|res| {
+                        Ok(res
+                            .map_err(|e| error!("Failed to rehash password: {}", e))
+                            .ok())
+                    }))
+                } else {
+                    Either::B(future::ok(None))
+                };
+
+                rehash
+                    .and_then(move |new_hash| {
+                        db.send(FinishLogin {
+                            attempt,
+                            success,
+                            new_hash,
+                        })
+                        .map_err(ErrorInternalServerError)
+                        .and_then(|res| res.map_err(Error::from))
+                        .and_then(move |info| finish_login(db, info, client, &config))
+                    })
+                    .map(Json)
+            })
         })
 }
 
+/// IP address and User-Agent of the client, recorded in the logs and sessions.
+#[derive(Clone, Debug)]
+pub(crate) struct ClientInfo {
+    pub ip_addr: String,
+    pub user_agent: String,
+}
+
+pub(crate) fn client_info(request: &HttpRequest, config: &Config) -> ClientInfo {
+    let ip_addr = request
+        .peer_addr()
+        .map(|addr| client_ip(addr.ip(), request.headers(), &config.trusted_proxies).to_string())
+        .unwrap_or_default();
+    let user_agent = request
+        .headers()
+        .get("User-Agent")
+        .and_then(|h| h.to_str().ok())
+        .map(String::from)
+        .unwrap_or_default();
+
+    ClientInfo {
+        ip_addr,
+        user_agent,
+    }
+}
+
+/// Address of the client. If the request came from a trusted proxy, it's taken from the
+/// `Forwarded` or `X-Forwarded-For` header. Addresses are read from the right and the first one
+/// which isn't a trusted proxy is used, because the client can put anything on the left.
+fn client_ip(peer: IpAddr, headers: &HeaderMap, trusted_proxies: &[IpAddr]) -> IpAddr {
+    let mut client = peer;
+    if !trusted_proxies.contains(&client) {
+        return client;
+    }
+
+    for addr in forwarded_addrs(headers).into_iter().rev() {
+        match addr {
+            Some(addr) => client = addr,
+            // Obfuscated or unknown address, nothing on the left can be trusted
+            None => break,
+        }
+        if !trusted_proxies.contains(&client) {
+            break;
+        }
+    }
+
+    client
+}
+
+/// Client addresses from the `Forwarded` header, or `X-Forwarded-For` if it's missing, from the
+/// original client to the last proxy. `None` stands for an address which couldn't be parsed.
+fn forwarded_addrs(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    let values = |name| {
+        headers
+            .get_all(name)
+            .filter_map(|h| h.to_str().ok())
+            .flat_map(|h| h.split(','))
+            .collect::<Vec<_>>()
+    };
+
+    if headers.contains_key(header::FORWARDED) {
+        values(header::FORWARDED.as_str())
+            .into_iter()
+            .map(|element| {
+                element
+                    .split(';')
+                    .filter_map(|pair| {
+                        let mut pair = pair.splitn(2, '=');
+                        match (pair.next(), pair.next()) {
+                            (Some(name), Some(value))
+                                if name.trim().eq_ignore_ascii_case("for") =>
+                            {
+                                Some(value)
+                            }
+                            _ => None,
+                        }
+                    })
+                    .next()
+                    .and_then(parse_node)
+            })
+            .collect()
+    } else {
+        values("X-Forwarded-For")
+            .into_iter()
+            .map(parse_node)
+            .collect()
+    }
+}
+
+/// Parses an address with an optional port, e.g. `192.0.2.1`, `"192.0.2.1:1234"`, `2001:db8::1`
+/// or `"[2001:db8::1]:1234"`.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+
+    if node.starts_with('[') {
+        let end = node.find(']')?;
+        return node[1..end].parse().ok();
+    }
+
+    node.parse().ok().or_else(|| {
+        let mut parts = node.rsplitn(2, ':');
+        parts.next();
+        parts.next()?.parse().ok()
+    })
+}
+
+/// Creates a session for the authenticated user, or a challenge which has to be completed with
+/// `POST /login/2fa` if the user has two-factor authentication enabled.
+pub(crate) fn finish_login(
+    db: Addr<DbExecutor>,
+    info: AuthInfo,
+    client: ClientInfo,
+    config: &Config,
+) -> impl Future<Item = LoginResult, Error = Error> {
+    if !info.two_factor {
+        return Either::A(create_session(db, info, client, config).map(LoginResult::Session));
+    }
+
+    let challenge = generate_token();
+    let msg = CreateLoginChallenge {
+        token: challenge,
+        user_id: info.user.id,
+        lifetime: chrono::Duration::seconds(config.two_factor.challenge_lifetime),
+    };
+
+    Either::B(
+        db.send(msg)
+            .map_err(ErrorInternalServerError)
+            .and_then(|r| r.map_err(Error::from))
+            .map(move |_| {
+                LoginResult::TwoFactorRequired(TwoFactorChallenge {
+                    two_factor_required: true,
+                    challenge: base64::encode(&challenge),
+                })
+            }),
+    )
+}
+
+/// Creates a new session for the user who passed all authentication steps. In signed token mode
+/// nothing is stored in the database.
+pub(crate) fn create_session(
+    db: Addr<DbExecutor>,
+    info: AuthInfo,
+    client: ClientInfo,
+    config: &Config,
+) -> impl Future<Item = LoginResponse, Error = Error> {
+    let required_for_admins = config.two_factor.required_for_admins;
+    let is_admin = has_admin_rights(&info.user, info.two_factor, required_for_admins);
+    let permissions = effective_permissions(&info, required_for_admins);
+    let user = info.user;
+
+    if config.session_mode == SessionMode::Signed {
+        let claims = Claims::new(
+            user.id,
+            user.login,
+            user.role,
+            permissions.clone(),
+            config.signed_tokens.lifetime,
+        );
+
+        return Either::A(future::result(
+            signed_tokens::sign(&config.signed_tokens, &claims)
+                .map(|token| LoginResponse {
+                    token,
+                    refresh_token: None,
+                    is_admin,
+                    permissions,
+                })
+                .map_err(|e| {
+                    error!("Failed to sign token: {}", e);
+
+                    ErrorInternalServerError("")
+                }),
+        ));
+    }
+
+    let token = generate_token();
+    let refresh_token = generate_token();
+
+    let msg = CreateSession {
+        token,
+        refresh_token,
+        user_id: user.id,
+        timeouts: config.session_timeouts(),
+        ip_addr: client.ip_addr,
+        user_agent: client.user_agent,
+    };
+
+    Either::B(
+        db.send(msg)
+            .map_err(ErrorInternalServerError)
+            .and_then(|r| r.map_err(Error::from))
+            .map(move |_| LoginResponse {
+                token: base64::encode(&token),
+                refresh_token: Some(base64::encode(&refresh_token)),
+                is_admin,
+                permissions,
+            }),
+    )
+}
+
+/// Admins are required to use two-factor authentication if `required_for_admins` is set.
+fn has_admin_rights(user: &User, two_factor: bool, required_for_admins: bool) -> bool {
+    user.role == User::ROLE_ADMIN && (two_factor || !required_for_admins)
+}
+
+/// Permissions of the user's role. Admins who don't meet the two-factor requirement have none.
+fn effective_permissions(info: &AuthInfo, required_for_admins: bool) -> Vec<String> {
+    if info.user.role == User::ROLE_ADMIN
+        && !has_admin_rights(&info.user, info.two_factor, required_for_admins)
+    {
+        Vec::new()
+    } else {
+        info.permissions.clone()
+    }
+}
+
+/// `POST /token/refresh`
+///
+/// Wymienia token odświeżania na nowy token sesji i nowy token odświeżania. Każdy token
+/// odświeżania może zostać użyty tylko raz, poprzedni token sesji przestaje być ważny. Ponowne
+/// użycie tokenu odświeżania unieważnia wszystkie tokeny pochodzące z tego samego logowania.
+///
+/// W przypadku nieprawidłowego, wygasłego lub ponownie użytego tokenu zwraca Unauthorized. Gdy
+/// `session_mode = "signed"` tokeny odświeżania nie są używane i zwracany jest BadRequest.
+pub fn refresh_token(
+    data: Json<RefreshData>,
+    actors: Data<Actors>,
+    config: Data<Config>,
+    request: HttpRequest,
+) -> impl Future<Item = Json<LoginResponse>, Error = Error> {
+    if config.session_mode == SessionMode::Signed {
+        return Either::A(future::err(ErrorBadRequest(
+            "Refresh tokens are not used with signed tokens",
+        )));
+    }
+
+    let refresh_token = match decode_token(&data.refresh_token, base64::STANDARD) {
+        Some(token) => token,
+        None => return Either::A(future::err(ErrorBadRequest("Invalid refresh token"))),
+    };
+
+    let client = client_info(&request, &config);
+    let msg = RefreshSession {
+        refresh_token,
+        new_token: generate_token(),
+        new_refresh_token: generate_token(),
+        timeouts: config.session_timeouts(),
+        ip_addr: client.ip_addr,
+        user_agent: client.user_agent,
+    };
+    let (token, refresh_token) = (msg.new_token, msg.new_refresh_token);
+
+    Either::B(
+        actors
+            .db
+            .send(msg)
+            .map_err(ErrorInternalServerError)
+            .and_then(|r| r.map_err(Error::from))
+            .map(move |info| {
+                let required_for_admins = config.two_factor.required_for_admins;
+
+                Json(LoginResponse {
+                    token: base64::encode(&token),
+                    refresh_token: Some(base64::encode(&refresh_token)),
+                    is_admin: has_admin_rights(&info.user, info.two_factor, required_for_admins),
+                    permissions: effective_permissions(&info, required_for_admins),
+                })
+            }),
+    )
+}
+
+pub(crate) fn generate_token() -> [u8; TOKEN_SIZE] {
+    let mut buf = [0u8; TOKEN_SIZE];
+    rand::thread_rng().fill(&mut buf);
+
+    buf
+}
+
+pub(crate) fn decode_token(token: &str, config: base64::Config) -> Option<[u8; TOKEN_SIZE]> {
+    let decoded = base64::decode_config(token, config).ok()?;
+    if decoded.len() != TOKEN_SIZE {
+        return None;
+    }
+
+    let mut buf = [0u8; TOKEN_SIZE];
+    buf.copy_from_slice(&decoded);
+
+    Some(buf)
+}
+
 /// `POST /signup`
 ///
 /// Tworzy nowego uzytkownika. W przypadku złego formatu zapytania lub gdy użytkownik z identyczną
-/// nazwą istnieje zwraca BadRequest.
+/// nazwą istnieje zwraca BadRequest. Jeśli login lub hasło nie spełniają reguł z sekcji `[policy]`
+/// pliku konfiguracyjnego zwraca BadRequest z listą naruszonych reguł, np. `{"violations":
+/// [{"field": "password", "rule": "min_length", "message": "..."}]}`. Gdy zbyt wiele zapytań czeka
+/// na obliczenie skrótu hasła, zwraca Service Unavailable.
 pub fn signup(
-    login: Json<LoginData>,
+    signup: Json<SignupData>,
+    config: Data<Config>,
     actors: Data<Actors>,
 ) -> impl Future<Item = (), Error = Error> {
-    let login = login.into_inner();
-    let msg = CreateUser {
-        name: login.login,
-        password: login.password.into(),
+    let signup = signup.into_inner();
+    if let Err(e) = config.policy.check_signup(&signup.login, &signup.password) {
+        return Either::A(future::err(e.into()));
+    }
+
+    let (name, email) = (signup.login, signup.email);
+    let db = actors.db.clone();
+
+    Either::B(
+        actors
+            .hasher
+            .hash(signup.password.into(), config.password_hash)
+            .from_err()
+            .and_then(move |hash| {
+                db.send(CreateUser { name, hash, email })
+                    .map_err(|x| {
+                        error!("{}", x);
+
+                        ErrorInternalServerError("")
+                    })
+                    .and_then(|res| res.map_err(Error::from))
+            }),
+    )
+}
+
+/// `POST /account/password`
+///
+/// Zmienia hasło aktualnego użytkownika i kończy wszystkie jego sesje poza aktualną. W przypadku
+/// nieprawidłowego starego hasła zwraca BadRequest. Nowe hasło musi spełniać reguły z sekcji
+/// `[policy]`, błędy mają taki sam format jak w `POST /signup`.
+///
+/// Podpisane tokeny (`session_mode = "signed"`) pozostają ważne do czasu ich wygaśnięcia.
+pub fn change_password(
+    data: Json<ChangePasswordData>,
+    auth: Auth,
+    config: Data<Config>,
+    actors: Data<Actors>,
+) -> impl Future<Item = (), Error = Error> {
+    let token = match auth.credential {
+        Credential::Session(token) => Some(token),
+        Credential::Signed { .. } => None,
+        Credential::ApiKey { .. } => return Either::A(future::err(session_required())),
     };
 
-    actors
-        .db
-        .send(msg)
-        .map_err(|x| {
-            error!("{}", x);
+    let data = data.into_inner();
+    if let Err(e) = config
+        .policy
+        .check_password(&data.new_password, Some(&auth.username))
+    {
+        return Either::A(future::err(e.into()));
+    }
 
-            ErrorInternalServerError("")
-        })
-        .and_then(|res| res.map_err(Error::from))
+    let user_id = auth.id;
+    let (old_password, new_password) = (data.old_password, data.new_password);
+    let db = actors.db.clone();
+    let hasher = actors.hasher.clone();
+
+    Either::B(
+        actors
+            .db
+            .send(GetPasswordHash { user_id })
+            .map_err(ErrorInternalServerError)
+            .and_then(|r| r.map_err(Error::from))
+            .and_then(move |old_hash| {
+                hasher
+                    .verify(old_hash.clone(), old_password.into())
+                    .from_err()
+                    .and_then(move |valid| {
+                        if !valid {
+                            return Either::A(future::err(DbError::InvalidCredentials.into()));
+                        }
+
+                        Either::B(
+                            hasher
+                                .hash(new_password.into(), config.password_hash)
+                                .from_err()
+                                .and_then(move |new_hash| {
+                                    db.send(ChangePassword {
+                                        user_id,
+                                        old_hash,
+                                        new_hash,
+                                        token,
+                                    })
+                                    .map_err(ErrorInternalServerError)
+                                    .and_then(|r| r.map_err(Error::from))
+                                }),
+                        )
+                    })
+            }),
+    )
 }
 
 /// `DELETE /account`
 ///
-/// Usuwa aktualne konto użytkownika i jego sesję.
-pub fn delete_account(
-    auth: Auth,
-    data: Data<Sessions>,
+/// Usuwa aktualne konto użytkownika i wszystkie jego sesje. Ostatni aktywny administrator nie
+/// może usunąć swojego konta, zwracany jest wtedy Conflict.
+pub fn delete_account(auth: Auth, actors: Data<Actors>) -> impl Future<Item = (), Error = Error> {
+    if let Err(e) = auth.require_session() {
+        Either::A(future::err(e))
+    } else {
+        Either::B(delete_user(auth.id, actors))
+    }
+}
+
+/// `DELETE /account/{id}`
+///
+/// Usuwa wybrane konto użytkownika i wszystkie jego sesje. Wymaga uprawnienia `users:write`.
+///
+/// Zwraca Not Found, jeśli użytkownik nie istnieje i Conflict przy próbie usunięcia ostatniego
+/// aktywnego administratora.
+pub fn delete_account_admin(
+    id: Path<i32>,
+    _auth: Authorized<UsersWrite>,
     actors: Data<Actors>,
 ) -> impl Future<Item = (), Error = Error> {
-    let msg = DeleteAccount { id: auth.id };
+    delete_user(*id, actors)
+}
 
+/// `POST /users/{id}/reactivate`
+///
+/// Przywraca usunięte wcześniej konto użytkownika. Wymaga uprawnienia `users:write`. Jeśli
+/// użytkownik nie istnieje, zwraca Not Found.
+pub fn reactivate_account(
+    id: Path<i32>,
+    _auth: Authorized<UsersWrite>,
+    actors: Data<Actors>,
+) -> impl Future<Item = (), Error = Error> {
     actors
         .db
-        .send(msg)
+        .send(ReactivateAccount { id: *id })
         .map_err(ErrorInternalServerError)
         .and_then(|r| r.map_err(Error::from))
-        .and_then(|_| logout(auth, data))
 }
 
-/// `DELETE /account/{id}`
+/// `DELETE /users/{id}`
 ///
-/// Usuwa wybrane konto użytkownika i jego sesję, jeśłi istnieje.
-pub fn delete_account_admin(
+/// Trwale usuwa konto użytkownika wraz z jego sesjami i logami logowania. Wpisy w historii
+/// wyszukiwania zostają zachowane, ale nie są już powiązane z użytkownikiem. Nazwa użytkownika
+/// staje się ponownie dostępna. Wymaga uprawnienia `users:write`.
+///
+/// Zwraca Not Found, jeśli użytkownik nie istnieje i Conflict przy próbie usunięcia ostatniego
+/// administratora.
+pub fn erase_account(
     id: Path<i32>,
-    auth: Auth,
-    data: Data<Sessions>,
+    _auth: Authorized<UsersWrite>,
     actors: Data<Actors>,
 ) -> impl Future<Item = (), Error = Error> {
-    if !auth.is_admin {
-        Either::A(future::err(ErrorForbidden("not admin")))
-    } else {
-        let msg = DeleteAccount { id: *id };
+    actors
+        .db
+        .send(EraseAccount { id: *id })
+        .map_err(ErrorInternalServerError)
+        .and_then(|r| r.map_err(Error::from))
+}
 
-        Either::B(
-            actors
-                .db
-                .send(msg)
-                .map_err(ErrorInternalServerError)
-                .and_then(|r| r.map_err(Error::from))
-                .and_then(move |_| {
-                    let mut lock = data.lock();
-                    if let Some(session) = lock.iter().find(|(_, v)| v.id == *id).map(|(k, _)| *k) {
-                        lock.remove(&session);
-                    }
-
-                    Ok(())
-                }),
-        )
-    }
+fn delete_user(id: i32, actors: Data<Actors>) -> impl Future<Item = (), Error = Error> {
+    actors
+        .db
+        .send(DeleteAccount { id })
+        .map_err(ErrorInternalServerError)
+        .and_then(|r| r.map_err(Error::from))
 }
 
 /// Ekstraktor danych uwierzytelniających.
 ///
+/// Dane użytkownika (w tym uprawnienia jego roli) są odczytywane z bazy przy każdym zapytaniu.
+/// Jeśli uwierzytelnianie dwuskładnikowe jest wymagane dla administratorów, administratorzy, którzy
+/// go nie włączyli, nie mają żadnych uprawnień. Handlery wymagające uprawnienia używają
+/// ekstraktora `permissions::Authorized`.
+///
 /// Ekstraktor spodziewa się tokenu sesji w nagłówku Authorization. Jeśli token nie istnieje, lub
-/// nagłówka nie ma w zapytaniu zwrócony zostanie błąd Unauthorized.
+/// nagłówka nie ma w zapytaniu zwrócony zostanie błąd Unauthorized. Jeśli sesja wygasła, treścią
+/// odpowiedzi Unauthorized będzie `Session expired`.
+///
+/// Token sesji jest ważny przez `access_token_lifetime` sekund od wydania, później należy
+/// wymienić go na nowy przez `POST /token/refresh`. Sesja wygasa po `session_lifetime` sekundach
+/// od wydania tokenu, lub po `session_idle_timeout` sekundach bez aktywności. Każde użycie
+/// tokenu przedłuża termin wygaśnięcia z powodu braku aktywności.
+///
+/// Gdy `session_mode = "signed"`, token sesji jest podpisanym tokenem JWT zawierającym ID, nazwę
+/// i uprawnienia użytkownika. Jego podpis jest weryfikowany bez odczytywania sesji z bazy, więc zmiana
+/// roli, usunięcie konta czy zmiana hasła obowiązują dopiero po wygaśnięciu tokenu. Wylogowanie
+/// unieważnia token od razu.
+///
+/// Zamiast tokenu sesji można podać klucz API w nagłówku `Authorization: ApiKey <klucz>` lub
+/// `X-Api-Key: <klucz>`. Klucz ma uprawnienia swojego właściciela, ograniczone do zakresów
+/// (nazw uprawnień) podanych przy jego tworzeniu. Operacje na własnym koncie (zmiana hasła, wylogowanie, itp.)
+/// wymagają tokenu sesji.
 pub struct Auth {
-    pub token: [u8; 32],
-    pub is_admin: bool,
+    pub credential: Credential,
+    /// Uprawnienia, które ma zapytanie, np. `songs:write`.
+    pub permissions: Vec<String>,
     pub username: String,
     pub id: i32,
 }
 
+/// Sposób w jaki uwierzytelniono zapytanie.
+pub enum Credential {
+    Session([u8; 32]),
+    /// Podpisany token, patrz `session_mode`.
+    Signed {
+        jti: String,
+        expires_at: NaiveDateTime,
+    },
+    /// Klucz API, `scopes` równe `None` oznacza klucz bez ograniczeń.
+    ApiKey {
+        id: i32,
+        scopes: Option<Vec<String>>,
+    },
+}
+
+impl Auth {
+    pub fn has_permission(&self, permission: &str) -> bool {
+        self.permissions.iter().any(|p| p == permission)
+    }
+
+    /// Czy zapytanie ma dostęp do zakresu `scope`, np. `songs:write`. Sesje mają dostęp do
+    /// wszystkich zakresów.
+    pub fn has_scope(&self, scope: &str) -> bool {
+        match &self.credential {
+            Credential::Session(_) | Credential::Signed { .. } => true,
+            Credential::ApiKey { scopes: None, .. } => true,
+            Credential::ApiKey {
+                scopes: Some(scopes),
+                ..
+            } => scopes.iter().any(|s| s == scope),
+        }
+    }
+
+    /// Zwraca Forbidden jeśli zapytanie uwierzytelniono kluczem API.
+    pub fn require_session(&self) -> Result<(), Error> {
+        match self.credential {
+            Credential::ApiKey { .. } => Err(session_required()),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn session_required() -> Error {
+    ErrorForbidden("Session token required")
+}
+
 impl FromRequest for Auth {
     type Error = Error;
-    type Future = Result<Auth, Error>;
+    type Future = Box<dyn Future<Item = Auth, Error = Error>>;
     type Config = ();
 
     fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
-        let sessions: &Sessions = req.app_data().expect("Sessions data is not configured!");
-
-        if let Some(token) = req.headers().get("Authorization") {
-            let token = token.to_str().map_err(|e| ErrorBadRequest(e))?;
-            let mut buf = [0u8; 32];
-            base64::decode_config_slice(token, base64::STANDARD, &mut buf)
-                .map_err(|e| ErrorBadRequest(e))?;
-
-            if let Some(user) = sessions.lock().get(&buf) {
-                Ok(Auth {
-                    token: buf,
-                    is_admin: user.role == User::ROLE_ADMIN,
-                    username: user.login.clone(),
-                    id: user.id,
-                })
-            } else {
-                Err(ErrorUnauthorized("Session doesn't exist"))
+        let actors: &Actors = req.app_data().expect("Actors data is not configured!");
+        let config: &Config = req.app_data().expect("Config data is not configured!");
+
+        let credentials = match parse_credentials(req, config) {
+            Ok(credentials) => credentials,
+            Err(e) => return Box::new(future::err(e)),
+        };
+
+        let required_for_admins = config.two_factor.required_for_admins;
+
+        match credentials {
+            Credentials::Signed(token) => {
+                let claims = match signed_tokens::verify(&config.signed_tokens, &token) {
+                    Ok(claims) => claims,
+                    Err(e) => return Box::new(future::err(e.into())),
+                };
+
+                Box::new(
+                    actors
+                        .db
+                        .send(IsTokenRevoked {
+                            jti: claims.jti.clone(),
+                        })
+                        .map_err(ErrorInternalServerError)
+                        .and_then(|r| r.map_err(Error::from))
+                        .and_then(move |revoked| {
+                            if revoked {
+                                return Err(ErrorUnauthorized("Session expired"));
+                            }
+
+                            Ok(Auth {
+                                credential: Credential::Signed {
+                                    jti: claims.jti,
+                                    expires_at: NaiveDateTime::from_timestamp(claims.exp, 0),
+                                },
+                                permissions: claims.perms,
+                                username: claims.name,
+                                id: claims.sub,
+                            })
+                        }),
+                )
             }
-        } else {
-            Err(ErrorUnauthorized("Missing Authorization header"))
+            Credentials::Session(token) => Box::new(
+                actors
+                    .db
+                    .send(GetSession {
+                        token,
+                        timeouts: config.session_timeouts(),
+                    })
+                    .map_err(ErrorInternalServerError)
+                    .and_then(|r| r.map_err(Error::from))
+                    .map(move |info| Auth {
+                        credential: Credential::Session(token),
+                        permissions: effective_permissions(&info, required_for_admins),
+                        username: info.user.login,
+                        id: info.user.id,
+                    }),
+            ),
+            Credentials::ApiKey(key) => Box::new(
+                actors
+                    .db
+                    .send(GetApiKey { key })
+                    .map_err(ErrorInternalServerError)
+                    .and_then(|r| r.map_err(Error::from))
+                    .map(move |(api_key, info)| {
+                        let scopes: Option<Vec<String>> = api_key
+                            .scopes
+                            .map(|s| s.split_whitespace().map(String::from).collect());
+                        let permissions = effective_permissions(&info, required_for_admins)
+                            .into_iter()
+                            .filter(|p| match &scopes {
+                                Some(scopes) => scopes.contains(p),
+                                None => true,
+                            })
+                            .collect();
+
+                        Auth {
+                            credential: Credential::ApiKey {
+                                id: api_key.id,
+                                scopes,
+                            },
+                            permissions,
+                            username: info.user.login,
+                            id: info.user.id,
+                        }
+                    }),
+            ),
         }
     }
 }
 
-/// `POST /logout`
-///
-/// Kończy aktualną sesję.
-pub fn logout(auth: Auth, sessions: Data<Sessions>) -> Result<(), Error> {
-    sessions.lock().remove(&auth.token);
+/// Credentials presented in the request, not verified yet.
+enum Credentials {
+    Session([u8; 32]),
+    Signed(String),
+    ApiKey([u8; 32]),
+}
+
+fn parse_credentials(req: &HttpRequest, config: &Config) -> Result<Credentials, Error> {
+    let parse_key = |key: &str| {
+        decode_token(key.trim(), base64::URL_SAFE_NO_PAD)
+            .map(Credentials::ApiKey)
+            .ok_or_else(|| ErrorBadRequest("Invalid API key"))
+    };
+
+    if let Some(key) = req.headers().get("X-Api-Key") {
+        parse_key(key.to_str().map_err(ErrorBadRequest)?)
+    } else if let Some(token) = req.headers().get("Authorization") {
+        let token = token.to_str().map_err(ErrorBadRequest)?;
 
-    Ok(())
+        let mut parts = token.splitn(2, ' ');
+        match (parts.next(), parts.next()) {
+            (Some(API_KEY_SCHEME), Some(key)) => parse_key(key),
+            _ if signed_tokens::is_signed_token(token) => {
+                if config.session_mode == SessionMode::Signed {
+                    Ok(Credentials::Signed(token.to_string()))
+                } else {
+                    Err(ErrorBadRequest("Invalid token"))
+                }
+            }
+            _ => decode_token(token, base64::STANDARD)
+                .map(Credentials::Session)
+                .ok_or_else(|| ErrorBadRequest("Invalid token")),
+        }
+    } else {
+        Err(ErrorUnauthorized("Missing Authorization header"))
+    }
 }
 
-/// `GET /users`
+/// `POST /logout`
 ///
-/// Zwraca wszystkich użytkowników. Wymaga uprawnień administratora.
-pub fn users(
-    auth: Auth,
-    actors: Data<Actors>,
-) -> impl Future<Item = Json<Vec<User>>, Error = Error> {
-    if !auth.is_admin {
-        Either::A(future::err(ErrorForbidden("not admin")))
-    } else {
-        Either::B(
+/// Kończy aktualną sesję i unieważnia powiązany z nią token odświeżania. Podpisany token jest
+/// dodawany do listy unieważnionych tokenów.
+pub fn logout(auth: Auth, actors: Data<Actors>) -> impl Future<Item = (), Error = Error> {
+    match auth.credential {
+        Credential::Session(token) => Either::A(Either::A(
             actors
                 .db
-                .send(GetUsers)
+                .send(DeleteSession { token })
                 .map_err(ErrorInternalServerError)
-                .and_then(|r| r.map_err(Error::from).map(Json)),
-        )
+                .and_then(|r| r.map_err(Error::from)),
+        )),
+        Credential::Signed { jti, expires_at } => Either::A(Either::B(
+            actors
+                .db
+                .send(RevokeSignedToken { jti, expires_at })
+                .map_err(ErrorInternalServerError)
+                .and_then(|r| r.map_err(Error::from)),
+        )),
+        Credential::ApiKey { .. } => Either::B(future::err(session_required())),
+    }
+}
+
+/// `GET /users?limit=..&cursor=..`
+///
+/// Zwraca użytkowników w kolejności ID, stronicowanych zgodnie z `PageQuery`. Wymaga uprawnienia
+/// `users:read`.
+pub fn users(
+    query: Query<PageQuery>,
+    _auth: Authorized<UsersRead>,
+    actors: Data<Actors>,
+) -> impl Future<Item = Json<Page<User>>, Error = Error> {
+    let page = match query.page() {
+        Ok(page) => page,
+        Err(e) => return Either::A(future::err(e)),
+    };
+
+    Either::B(
+        actors
+            .db
+            .send(GetUsers { page })
+            .map_err(ErrorInternalServerError)
+            .and_then(|r| r.map_err(Error::from).map(Json)),
+    )
+}
+
+/// `GET /users/password_hashes`
+///
+/// Zwraca liczbę użytkowników dla każdego zestawu parametrów skrótów haseł, np.
+/// `[{"params": "$argon2i$v=19$m=131072,t=2,p=1", "hash_length": 32, "users": 10, "current":
+/// false}]`. Skróty z nieaktualnymi parametrami są zastępowane przy następnym udanym logowaniu.
+/// Wymaga uprawnienia `users:read`.
+pub fn password_hashes(
+    _auth: Authorized<UsersRead>,
+    config: Data<Config>,
+    actors: Data<Actors>,
+) -> impl Future<Item = Json<Vec<HashParamsUsage>>, Error = Error> {
+    actors
+        .db
+        .send(GetHashParamsUsage {
+            config: config.password_hash,
+        })
+        .map_err(ErrorInternalServerError)
+        .and_then(|r| r.map_err(Error::from).map(Json))
+}
+
+/// `PUT /users/{id}/role`
+///
+/// Zmienia rolę wybranego użytkownika. Wymaga uprawnienia `users:write`. Zwraca BadRequest
+/// w przypadku nieznanej roli, Not Found gdy użytkownik nie istnieje i Conflict przy próbie
+/// odebrania uprawnień ostatniemu administratorowi.
+///
+/// Zmiana obowiązuje od razu w sesjach przechowywanych w bazie. Gdy `session_mode = "signed"`,
+/// wydane wcześniej tokeny zawierają uprawnienia poprzedniej roli aż do wygaśnięcia, patrz
+/// `lifetime` w sekcji `[signed_tokens]`.
+pub fn set_role(
+    id: Path<i32>,
+    data: Json<RoleData>,
+    _auth: Authorized<UsersWrite>,
+    actors: Data<Actors>,
+) -> impl Future<Item = (), Error = Error> {
+    let msg = SetRole {
+        id: *id,
+        role: data.into_inner().role,
+    };
+
+    actors
+        .db
+        .send(msg)
+        .map_err(ErrorInternalServerError)
+        .and_then(|r| r.map_err(Error::from))
+}
+
+/// `POST /lockouts/clear`
+///
+/// Usuwa blokadę logowania nałożoną po nieudanych próbach logowania na wybrane konto i/lub adres
+/// IP. Wymaga uprawnienia `users:write`.
+pub fn clear_lockout(
+    data: Json<LockoutData>,
+    _auth: Authorized<UsersWrite>,
+    actors: Data<Actors>,
+) -> impl Future<Item = (), Error = Error> {
+    let data = data.into_inner();
+    if data.login.is_none() && data.ip_addr.is_none() {
+        return Either::A(future::err(ErrorBadRequest("Missing login or ip_addr")));
     }
+
+    let msg = ClearLockout {
+        login: data.login,
+        ip_addr: data.ip_addr,
+    };
+
+    Either::B(
+        actors
+            .db
+            .send(msg)
+            .map_err(ErrorInternalServerError)
+            .and_then(|r| r.map_err(Error::from)),
+    )
 }
 
 /// `GET /check_session`
 ///
 /// Zwraca 200 jeśli sesja jest poprawna.
 pub fn check_session(_: Auth) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::{HeaderName, HeaderValue};
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn headers(values: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in values {
+            headers.append(
+                HeaderName::from_static(name),
+                HeaderValue::from_static(value),
+            );
+        }
+
+        headers
+    }
+
+    #[test]
+    fn untrusted_peer_is_the_client() {
+        let headers = headers(&[("x-forwarded-for", "192.0.2.1")]);
+
+        assert_eq!(client_ip(ip("10.0.0.1"), &headers, &[]), ip("10.0.0.1"));
+    }
+
+    #[test]
+    fn address_is_taken_from_trusted_proxy() {
+        let proxies = [ip("10.0.0.1"), ip("10.0.0.2")];
+        let headers = headers(&[
+            ("x-forwarded-for", "203.0.113.7, 192.0.2.1"),
+            ("x-forwarded-for", "10.0.0.2"),
+        ]);
+
+        // 203.0.113.7 could have been sent by the client itself
+        assert_eq!(
+            client_ip(ip("10.0.0.1"), &headers, &proxies),
+            ip("192.0.2.1")
+        );
+    }
+
+    #[test]
+    fn forwarded_header_is_preferred() {
+        let proxies = [ip("10.0.0.1")];
+        let headers = headers(&[
+            ("x-forwarded-for", "192.0.2.1"),
+            (
+                "forwarded",
+                "for=192.0.2.60;proto=https, For=\"[2001:db8::17]:4711\";by=10.0.0.1",
+            ),
+        ]);
+
+        assert_eq!(
+            forwarded_addrs(&headers),
+            vec![Some(ip("192.0.2.60")), Some(ip("2001:db8::17"))]
+        );
+        assert_eq!(
+            client_ip(ip("10.0.0.1"), &headers, &proxies),
+            ip("2001:db8::17")
+        );
+    }
+
+    #[test]
+    fn unknown_address_stops_the_search() {
+        let proxies = [ip("10.0.0.1"), ip("10.0.0.2")];
+        let headers = headers(&[("forwarded", "for=192.0.2.60, for=unknown, for=10.0.0.2")]);
+
+        assert_eq!(
+            client_ip(ip("10.0.0.1"), &headers, &proxies),
+            ip("10.0.0.2")
+        );
+    }
+
+    #[test]
+    fn nodes_with_ports_are_parsed() {
+        assert_eq!(parse_node(" 192.0.2.1:8080"), Some(ip("192.0.2.1")));
+        assert_eq!(parse_node("2001:db8::1"), Some(ip("2001:db8::1")));
+        assert_eq!(
+            parse_node("\"[2001:db8::1]:8080\""),
+            Some(ip("2001:db8::1"))
+        );
+        assert_eq!(parse_node("_hidden"), None);
+    }
+}
@@ -0,0 +1,161 @@
+//! Creating the first admin account, either with the `create-admin` command or on startup from
+//! the `[bootstrap_admin]` section.
+
+use diesel::prelude::SqliteConnection;
+use failure::{bail, format_err, ResultExt};
+use log::info;
+use serde::Deserialize;
+use std::io::BufRead;
+
+use crate::db::auth::{
+    admin_exists, create_or_promote_admin, user_exists, AdminBootstrap, Error as DbError,
+};
+use crate::policy::PolicyError;
+use crate::Config;
+
+const USAGE: &str = "Usage: szaklon-api create-admin --login <login> [--password <password>] \
+                     [--email <email>]";
+
+/// Admin created on startup, only if there is no active admin yet. Each value can be overridden
+/// with `SZAKLON_ADMIN_LOGIN`, `SZAKLON_ADMIN_PASSWORD` and `SZAKLON_ADMIN_EMAIL` environment
+/// variables, which is enough to bootstrap without this section.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct BootstrapAdminConfig {
+    #[serde(default)]
+    pub login: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+    #[serde(default)]
+    pub email: Option<String>,
+}
+
+impl BootstrapAdminConfig {
+    fn with_env(&self) -> Self {
+        let var = |name, value: &Option<String>| std::env::var(name).ok().or_else(|| value.clone());
+
+        Self {
+            login: var("SZAKLON_ADMIN_LOGIN", &self.login),
+            password: var("SZAKLON_ADMIN_PASSWORD", &self.password),
+            email: var("SZAKLON_ADMIN_EMAIL", &self.email),
+        }
+    }
+}
+
+/// `szaklon-api create-admin --login <login> [--password <password>] [--email <email>]`
+///
+/// Creates the admin, or promotes an existing user (the password is replaced only if given).
+/// Without `--password` it's taken from `SZAKLON_ADMIN_PASSWORD`, or read from the standard
+/// input when creating a new user.
+pub fn create_admin_command(
+    config: &Config,
+    connection: &SqliteConnection,
+    args: &[String],
+) -> Result<(), failure::Error> {
+    let mut login = None;
+    let mut password = std::env::var("SZAKLON_ADMIN_PASSWORD").ok();
+    let mut email = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let value = args
+            .next()
+            .ok_or_else(|| format_err!("Missing value of {}\n{}", arg, USAGE))?
+            .clone();
+        match arg.as_str() {
+            "--login" => login = Some(value),
+            "--password" => password = Some(value),
+            "--email" => email = Some(value),
+            _ => bail!("Unknown argument {}\n{}", arg, USAGE),
+        }
+    }
+    let login = login.ok_or_else(|| format_err!("Missing --login\n{}", USAGE))?;
+
+    if password.is_none() && !user_exists(connection, &login)? {
+        eprintln!("Password for {}:", login);
+        let mut line = String::new();
+        std::io::stdin().lock().read_line(&mut line)?;
+        password = Some(line.trim_end_matches(&['\r', '\n'][..]).to_string());
+    }
+
+    match create_admin(config, connection, &login, password, email)? {
+        AdminBootstrap::Created => println!("Created admin {}", login),
+        AdminBootstrap::Promoted => println!("User {} is now an admin", login),
+    }
+
+    Ok(())
+}
+
+/// Creates the admin from `[bootstrap_admin]` and the environment, unless some admin exists.
+pub fn bootstrap_admin(
+    config: &Config,
+    connection: &SqliteConnection,
+) -> Result<(), failure::Error> {
+    let bootstrap = config.bootstrap_admin.with_env();
+    let login = match bootstrap.login {
+        Some(login) => login,
+        None => return Ok(()),
+    };
+
+    if admin_exists(connection)? {
+        info!("Admin already exists, skipping admin bootstrap");
+
+        return Ok(());
+    }
+
+    match create_admin(
+        config,
+        connection,
+        &login,
+        bootstrap.password,
+        bootstrap.email,
+    )
+    .context("Failed to bootstrap the admin")?
+    {
+        AdminBootstrap::Created => info!("Created admin {}", login),
+        AdminBootstrap::Promoted => info!("User {} is now an admin", login),
+    }
+
+    Ok(())
+}
+
+fn create_admin(
+    config: &Config,
+    connection: &SqliteConnection,
+    login: &str,
+    password: Option<String>,
+    email: Option<String>,
+) -> Result<AdminBootstrap, failure::Error> {
+    // Reserved logins are allowed here, only the password has to match the policy
+    let hash = match password {
+        Some(password) => {
+            config
+                .policy
+                .check_password(&password, Some(login))
+                .map_err(policy_error)?;
+
+            Some(config.password_hash.hash(password.as_bytes())?)
+        }
+        None => None,
+    };
+
+    match create_or_promote_admin(
+        connection,
+        login,
+        hash.as_ref().map(AsRef::as_ref),
+        email.as_ref().map(AsRef::as_ref),
+    ) {
+        Ok(result) => Ok(result),
+        Err(DbError::NotFound) => bail!("User {} doesn't exist and no password was given", login),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn policy_error(e: PolicyError) -> failure::Error {
+    let messages = e
+        .violations
+        .iter()
+        .map(|v| v.message.as_str())
+        .collect::<Vec<_>>();
+
+    format_err!("{}: {}", e, messages.join(", "))
+}
@@ -1,14 +1,84 @@
 use actix::prelude::*;
 use diesel::sqlite::SqliteConnection;
 
+pub mod api_keys;
 pub mod auth;
+pub mod lockout;
 pub mod logs;
 pub mod models;
+pub mod oidc;
+pub mod passwords;
+pub mod permissions;
 pub mod schema;
+pub mod search;
+pub mod sessions;
 pub mod songs;
+pub mod two_factor;
 
 pub struct DbExecutor(pub SqliteConnection);
 
 impl Actor for DbExecutor {
     type Context = SyncContext<Self>;
 }
+
+/// In-memory database with all migrations applied.
+#[cfg(test)]
+pub(crate) fn test_connection() -> SqliteConnection {
+    use diesel::connection::{Connection, SimpleConnection};
+
+    let conn = SqliteConnection::establish(":memory:").unwrap();
+    let mut migrations = std::fs::read_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/migrations"))
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.is_dir())
+        .collect::<Vec<_>>();
+    migrations.sort();
+
+    for migration in migrations {
+        let sql = std::fs::read_to_string(migration.join("up.sql")).unwrap();
+        conn.batch_execute(&sql).unwrap();
+    }
+
+    conn
+}
+
+/// Inserts a user with given role and empty password hash, returns their ID.
+#[cfg(test)]
+pub(crate) fn test_user(conn: &SqliteConnection, login: &str, role: &str) -> i32 {
+    use diesel::prelude::*;
+    use models::NewUser;
+    use schema::users::dsl::{self, users};
+
+    diesel::insert_into(users)
+        .values(&NewUser {
+            login,
+            hash: "",
+            role,
+            email: None,
+            oidc_issuer: None,
+            oidc_subject: None,
+        })
+        .execute(conn)
+        .unwrap();
+
+    users
+        .filter(dsl::login.eq(login))
+        .select(dsl::id)
+        .first(conn)
+        .unwrap()
+}
+
+/// Song with given version, as returned by the database.
+#[cfg(test)]
+pub(crate) fn test_song(version: i32) -> models::Song {
+    models::Song {
+        id: 1,
+        artist: "Artist".to_owned(),
+        title: "Title".to_owned(),
+        genre: "Rock".to_owned(),
+        url: "https://example.com".to_owned(),
+        featured: false,
+        deleted: false,
+        version,
+    }
+}
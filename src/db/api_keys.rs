@@ -0,0 +1,156 @@
+use actix::prelude::*;
+use actix_web::{dev::Body, http::StatusCode, web::HttpResponse, ResponseError};
+use diesel::prelude::*;
+use failure_derive::Fail;
+
+use crate::db::models::{ApiKey, NewApiKey, User};
+use crate::db::permissions::{auth_info, AuthInfo};
+use crate::db::sessions::token_hash;
+use crate::db::DbExecutor;
+
+/// Creates API key owned by given user. Only the hash of the key is stored.
+pub struct CreateApiKey {
+    pub name: String,
+    pub key: [u8; 32],
+    pub prefix: String,
+    pub user_id: i32,
+    pub scopes: Option<Vec<String>>,
+}
+
+pub struct GetApiKeys;
+
+pub struct RevokeApiKey {
+    pub id: i32,
+}
+
+/// Returns the key together with its owner. Keys of deactivated users are treated as nonexistent.
+/// Every use of the key is recorded in `last_used`.
+pub struct GetApiKey {
+    pub key: [u8; 32],
+}
+
+#[derive(Debug, Fail)]
+pub enum Error {
+    #[fail(display = "API key doesn't exist")]
+    NotFound,
+    #[fail(display = "Invalid API key")]
+    InvalidKey,
+    #[fail(display = "Database error: {}", _0)]
+    DbError(#[cause] diesel::result::Error),
+}
+
+impl ResponseError for Error {
+    fn error_response(&self) -> HttpResponse<Body> {
+        match self {
+            Error::NotFound => HttpResponse::new(StatusCode::NOT_FOUND),
+            Error::InvalidKey => HttpResponse::Unauthorized().body("Invalid API key"),
+            Error::DbError(_) => HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR),
+        }
+    }
+}
+
+impl From<diesel::result::Error> for Error {
+    fn from(f: diesel::result::Error) -> Self {
+        Error::DbError(f)
+    }
+}
+
+impl Message for CreateApiKey {
+    type Result = Result<ApiKey, Error>;
+}
+
+impl Handler<CreateApiKey> for DbExecutor {
+    type Result = Result<ApiKey, Error>;
+
+    fn handle(&mut self, msg: CreateApiKey, _: &mut Self::Context) -> Self::Result {
+        use super::schema::api_keys::dsl::{api_keys, key};
+
+        let hash = token_hash(&msg.key);
+        let scopes = msg.scopes.as_ref().map(|s| s.join(" "));
+
+        self.0.transaction(|| {
+            diesel::insert_into(api_keys)
+                .values(&NewApiKey {
+                    name: &msg.name,
+                    key: &hash,
+                    prefix: &msg.prefix,
+                    user_id: msg.user_id,
+                    scopes: scopes.as_ref().map(AsRef::as_ref),
+                    created_at: chrono::offset::Utc::now().naive_utc(),
+                })
+                .execute(&self.0)?;
+
+            Ok(api_keys.filter(key.eq(&hash)).first::<ApiKey>(&self.0)?)
+        })
+    }
+}
+
+impl Message for GetApiKeys {
+    type Result = Result<Vec<ApiKey>, Error>;
+}
+
+impl Handler<GetApiKeys> for DbExecutor {
+    type Result = Result<Vec<ApiKey>, Error>;
+
+    fn handle(&mut self, _msg: GetApiKeys, _: &mut Self::Context) -> Self::Result {
+        use super::schema::api_keys::dsl::api_keys;
+
+        Ok(api_keys.load::<ApiKey>(&self.0)?)
+    }
+}
+
+impl Message for RevokeApiKey {
+    type Result = Result<(), Error>;
+}
+
+impl Handler<RevokeApiKey> for DbExecutor {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, msg: RevokeApiKey, _: &mut Self::Context) -> Self::Result {
+        use super::schema::api_keys::dsl::api_keys;
+
+        match diesel::delete(api_keys.find(msg.id)).execute(&self.0)? {
+            0 => Err(Error::NotFound),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Message for GetApiKey {
+    type Result = Result<(ApiKey, AuthInfo), Error>;
+}
+
+impl Handler<GetApiKey> for DbExecutor {
+    type Result = Result<(ApiKey, AuthInfo), Error>;
+
+    fn handle(&mut self, msg: GetApiKey, _: &mut Self::Context) -> Self::Result {
+        use super::schema::api_keys::dsl::{api_keys, key, last_used};
+        use super::schema::users::dsl::{active, users};
+
+        let (api_key, user) = match api_keys
+            .inner_join(users)
+            .filter(key.eq(token_hash(&msg.key)))
+            .filter(active.eq(true))
+            .first::<(ApiKey, User)>(&self.0)
+        {
+            Ok(r) => r,
+            Err(diesel::result::Error::NotFound) => return Err(Error::InvalidKey),
+            Err(e) => return Err(Error::DbError(e)),
+        };
+
+        diesel::update(api_keys.find(api_key.id))
+            .set(last_used.eq(chrono::offset::Utc::now().naive_utc()))
+            .execute(&self.0)?;
+
+        Ok((api_key, auth_info(&self.0, user)?))
+    }
+}
+
+/// Removes all API keys owned by the user.
+pub(super) fn delete_user_api_keys(conn: &SqliteConnection, user_id: i32) -> QueryResult<()> {
+    use super::schema::api_keys::dsl::{api_keys, user_id as owner};
+
+    diesel::delete(api_keys.filter(owner.eq(user_id))).execute(conn)?;
+
+    Ok(())
+}
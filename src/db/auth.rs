@@ -3,27 +3,22 @@ use diesel::prelude::*;
 use failure_derive::Fail;
 use unicode_normalization::UnicodeNormalization;
 
-use crate::db::models::{NewUser, User, UserLog};
+use crate::db::api_keys::delete_user_api_keys;
+use crate::db::lockout::{retry_after, LockoutConfig};
+use crate::db::models::{NewPasswordReset, NewUser, PasswordReset, User, UserLog};
+use crate::db::permissions::{auth_info, role_exists, AuthInfo};
+use crate::db::sessions::{delete_other_sessions, delete_user_sessions, token_hash};
+use crate::db::two_factor::delete_user_two_factor;
 use crate::db::DbExecutor;
-use crate::utils::PerfLog;
+use crate::pagination::{Page, PageRequest};
 use actix_web::{dev::Body, http::StatusCode, web::HttpResponse, ResponseError};
-use rand::Rng;
-
-const HASH_CONFIG: argon2::Config = argon2::Config {
-    ad: &[],
-    hash_length: 32,
-    lanes: 1,
-    mem_cost: 128 * 1024, // 128 MiB
-    secret: &[],
-    thread_mode: argon2::ThreadMode::Sequential,
-    time_cost: 2,
-    variant: argon2::Variant::Argon2i,
-    version: argon2::Version::Version13,
-};
+use chrono::Duration;
 
 pub struct CreateUser {
     pub name: String,
-    pub password: Vec<u8>,
+    /// Encoded password hash, see `Hasher`.
+    pub hash: String,
+    pub email: Option<String>,
 }
 
 #[derive(Debug, Fail)]
@@ -34,10 +29,16 @@ pub enum Error {
     UserExists,
     #[fail(display = "Requested user not found")]
     NotFound,
+    #[fail(display = "Invalid or expired password reset token")]
+    InvalidResetToken,
+    #[fail(display = "Cannot demote or remove the last active admin")]
+    LastAdmin,
+    #[fail(display = "Unknown role")]
+    UnknownRole,
+    #[fail(display = "Too many failed login attempts, retry after {} seconds", _0)]
+    LockedOut(i64),
     #[fail(display = "Database error occurred")]
     DbError(#[cause] diesel::result::Error),
-    #[fail(display = "Error while hashing")]
-    HashError(#[cause] argon2::Error),
 }
 
 impl ResponseError for Error {
@@ -46,8 +47,17 @@ impl ResponseError for Error {
             Error::InvalidCredentials => HttpResponse::new(StatusCode::BAD_REQUEST),
             Error::UserExists => HttpResponse::new(StatusCode::BAD_REQUEST),
             Error::NotFound => HttpResponse::new(StatusCode::NOT_FOUND),
+            Error::InvalidResetToken => {
+                HttpResponse::BadRequest().body("Invalid or expired password reset token")
+            }
+            Error::LastAdmin => {
+                HttpResponse::Conflict().body("Cannot demote or remove the last active admin")
+            }
+            Error::UnknownRole => HttpResponse::BadRequest().body("Unknown role"),
+            Error::LockedOut(retry_after) => HttpResponse::build(StatusCode::TOO_MANY_REQUESTS)
+                .header("Retry-After", retry_after.to_string())
+                .body(self.to_string()),
             Error::DbError(_) => HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR),
-            Error::HashError(_) => HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR),
         }
     }
 }
@@ -58,12 +68,6 @@ impl From<diesel::result::Error> for Error {
     }
 }
 
-impl From<argon2::Error> for Error {
-    fn from(f: argon2::Error) -> Self {
-        Error::HashError(f)
-    }
-}
-
 impl Message for CreateUser {
     type Result = Result<(), Error>;
 }
@@ -74,9 +78,6 @@ impl Handler<CreateUser> for DbExecutor {
     fn handle(&mut self, msg: CreateUser, _: &mut Self::Context) -> Self::Result {
         use super::schema::users::dsl::{self, users};
 
-        let mut salt = [0u8; 16];
-        rand::thread_rng().fill(&mut salt);
-
         let username = normalize_username(&msg.name);
 
         // Check if user already exists
@@ -89,14 +90,13 @@ impl Handler<CreateUser> for DbExecutor {
             Err(e) => return Err(Error::DbError(e)),
         }
 
-        let p = PerfLog::new();
-        let hash = &argon2::hash_encoded(&msg.password, &salt, &HASH_CONFIG)?;
-        p.log("Hash time");
-
         let new_user = NewUser {
             login: &username,
-            hash,
+            hash: &msg.hash,
             role: User::ROLE_CUSTOMER,
+            email: msg.email.as_ref().map(AsRef::as_ref),
+            oidc_issuer: None,
+            oidc_subject: None,
         };
 
         diesel::insert_into(users)
@@ -107,64 +107,299 @@ impl Handler<CreateUser> for DbExecutor {
     }
 }
 
-pub struct Login {
+/// First step of the login: checks the lockout and finds the user, whose password hash has to be
+/// verified before `FinishLogin`.
+///
+/// The attempt is logged as failed before the password is checked, so attempts waiting for the
+/// check count towards the lock, and `FinishLogin` marks it as successful. Otherwise any number of
+/// parallel attempts would pass the lockout check before the first failure is logged.
+pub struct StartLogin {
     pub name: String,
-    pub password: Vec<u8>,
     pub ip_addr: String,
     pub user_agent: String,
+    pub lockout: LockoutConfig,
 }
 
-impl Message for Login {
-    type Result = Result<User, Error>;
+/// User whose password has to be checked and the log entry of the attempt.
+pub struct LoginAttempt {
+    pub user: User,
+    pub log_id: i32,
 }
 
-impl Handler<Login> for DbExecutor {
-    type Result = Result<User, Error>;
+impl Message for StartLogin {
+    type Result = Result<LoginAttempt, Error>;
+}
 
-    fn handle(&mut self, msg: Login, _: &mut Self::Context) -> Self::Result {
-        use super::schema::logs::dsl::logs;
+impl Handler<StartLogin> for DbExecutor {
+    type Result = Result<LoginAttempt, Error>;
+
+    fn handle(&mut self, msg: StartLogin, _: &mut Self::Context) -> Self::Result {
         use super::schema::users::dsl::{self, users};
 
+        let conn = &self.0;
         let username = normalize_username(&msg.name);
 
-        let mut log_entry = UserLog {
-            login: &username,
+        // Attempts to log in as a user who doesn't exist are returned as `Ok(Err(_))`, so they
+        // stay logged
+        conn.immediate_transaction::<_, Error, _>(|| {
+            let log_id = log_attempt(conn, &msg.lockout, &username, &msg.ip_addr, &msg.user_agent)?;
+
+            match users.filter(dsl::login.eq(&username)).first::<User>(conn) {
+                Ok(user) => Ok(Ok(LoginAttempt { user, log_id })),
+                Err(diesel::result::Error::NotFound) => Ok(Err(Error::InvalidCredentials)),
+                Err(e) => Err(Error::DbError(e)),
+            }
+        })
+        .and_then(|r| r)
+    }
+}
+
+/// Checks the lockout and logs a failed attempt. Returns ID of the log entry. Has to be called in
+/// an immediate transaction, so no other attempt is logged between the check and the insert.
+fn log_attempt(
+    conn: &SqliteConnection,
+    lockout: &LockoutConfig,
+    username: &str,
+    ip_addr: &str,
+    user_agent: &str,
+) -> Result<i32, Error> {
+    use super::schema::logs::dsl::{id, logs};
+
+    // Blocked attempts are not logged, so they don't extend the lock
+    if let Some(secs) = retry_after(conn, lockout, username, ip_addr)? {
+        return Err(Error::LockedOut(secs));
+    }
+
+    diesel::insert_into(logs)
+        .values(&UserLog {
+            login: username,
             logging_time: chrono::offset::Utc::now().naive_utc(),
             logging_succession: false,
-            ip_addr: &msg.ip_addr,
-            user_agent: &msg.user_agent,
-        };
+            ip_addr,
+            user_agent,
+        })
+        .execute(conn)?;
+
+    Ok(logs.select(id).order(id.desc()).first::<i32>(conn)?)
+}
+
+/// Records the result of the password check. On success returns the user and whether the login
+/// has to be completed with the second factor.
+pub struct FinishLogin {
+    pub attempt: LoginAttempt,
+    pub success: bool,
+    /// Replaces password hash created with outdated parameters, unless the password was changed
+    /// in the meantime.
+    pub new_hash: Option<String>,
+}
+
+impl Message for FinishLogin {
+    type Result = Result<AuthInfo, Error>;
+}
+
+impl Handler<FinishLogin> for DbExecutor {
+    type Result = Result<AuthInfo, Error>;
+
+    fn handle(&mut self, msg: FinishLogin, _: &mut Self::Context) -> Self::Result {
+        use super::schema::logs::dsl::{logging_succession, logs};
+        use super::schema::users::dsl::{self, users};
+
+        // The attempt is already logged as failed
+        if !msg.success {
+            return Err(Error::InvalidCredentials);
+        }
+
+        let user = msg.attempt.user;
+        diesel::update(logs.find(msg.attempt.log_id))
+            .set(logging_succession.eq(true))
+            .execute(&self.0)?;
+
+        if let Some(new_hash) = &msg.new_hash {
+            diesel::update(users.find(user.id).filter(dsl::hash.eq(&user.hash)))
+                .set(dsl::hash.eq(new_hash))
+                .execute(&self.0)?;
+        }
+
+        Ok(auth_info(&self.0, user)?)
+    }
+}
+
+/// Returns password hash of the user, which has to be verified before `ChangePassword`.
+pub struct GetPasswordHash {
+    pub user_id: i32,
+}
+
+impl Message for GetPasswordHash {
+    type Result = Result<String, Error>;
+}
+
+impl Handler<GetPasswordHash> for DbExecutor {
+    type Result = Result<String, Error>;
+
+    fn handle(&mut self, msg: GetPasswordHash, _: &mut Self::Context) -> Self::Result {
+        use super::schema::users::dsl::{hash, users};
+
+        match users
+            .find(msg.user_id)
+            .select(hash)
+            .first::<String>(&self.0)
+        {
+            Ok(h) => Ok(h),
+            Err(diesel::result::Error::NotFound) => Err(Error::NotFound),
+            Err(e) => Err(Error::DbError(e)),
+        }
+    }
+}
+
+/// Changes password of the user and ends all of their sessions except the one with `token`.
+/// Fails if the password hash isn't `old_hash` anymore.
+pub struct ChangePassword {
+    pub user_id: i32,
+    pub old_hash: String,
+    pub new_hash: String,
+    pub token: Option<[u8; 32]>,
+}
+
+impl Message for ChangePassword {
+    type Result = Result<(), Error>;
+}
+
+impl Handler<ChangePassword> for DbExecutor {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, msg: ChangePassword, _: &mut Self::Context) -> Self::Result {
+        use super::schema::users::dsl::{hash, users};
+
+        self.0.transaction(|| {
+            let updated = diesel::update(users.find(msg.user_id).filter(hash.eq(&msg.old_hash)))
+                .set(hash.eq(&msg.new_hash))
+                .execute(&self.0)?;
+            if updated == 0 {
+                return Err(Error::InvalidCredentials);
+            }
+
+            match &msg.token {
+                Some(token) => delete_other_sessions(&self.0, msg.user_id, token)?,
+                None => delete_user_sessions(&self.0, msg.user_id)?,
+            };
+
+            Ok(())
+        })
+    }
+}
+
+/// Creates password reset token for the user with given login.
+///
+/// Returns the user if reset token was created, i.e. the user exists, is active and has an email
+/// address the token can be sent to.
+pub struct RequestPasswordReset {
+    pub name: String,
+    pub token: [u8; 32],
+    pub lifetime: Duration,
+}
+
+impl Message for RequestPasswordReset {
+    type Result = Result<Option<User>, Error>;
+}
+
+impl Handler<RequestPasswordReset> for DbExecutor {
+    type Result = Result<Option<User>, Error>;
+
+    fn handle(&mut self, msg: RequestPasswordReset, _: &mut Self::Context) -> Self::Result {
+        use super::schema::password_resets::dsl::{self as r, password_resets};
+        use super::schema::users::dsl::{self, users};
+
+        let username = normalize_username(&msg.name);
 
         let user = match users
             .filter(dsl::login.eq(&username))
+            .filter(dsl::active.eq(true))
+            .filter(dsl::email.is_not_null())
             .first::<User>(&self.0)
         {
             Ok(u) => u,
-            Err(diesel::result::Error::NotFound) => {
-                diesel::insert_into(logs)
-                    .values(&log_entry)
-                    .execute(&self.0)?;
-
-                return Err(Error::InvalidCredentials);
-            }
+            Err(diesel::result::Error::NotFound) => return Ok(None),
             Err(e) => return Err(Error::DbError(e)),
         };
 
-        log_entry.logging_succession =
-            user.active && argon2::verify_encoded(&user.hash, &msg.password)?;
+        let now = chrono::offset::Utc::now().naive_utc();
+        let token = token_hash(&msg.token);
 
-        diesel::insert_into(logs)
-            .values(&log_entry)
-            .execute(&self.0)?;
+        self.0.transaction(|| {
+            // Only the newest token is valid
+            diesel::delete(password_resets.filter(r::user_id.eq(user.id))).execute(&self.0)?;
+
+            diesel::insert_into(password_resets)
+                .values(&NewPasswordReset {
+                    token: &token,
+                    user_id: user.id,
+                    created_at: now,
+                    expires_at: now + msg.lifetime,
+                })
+                .execute(&self.0)
+        })?;
+
+        Ok(Some(user))
+    }
+}
+
+/// Sets new password using password reset token. All sessions of the user are ended.
+pub struct ConfirmPasswordReset {
+    pub token: [u8; 32],
+    /// Encoded hash of the new password.
+    pub hash: String,
+}
+
+impl Message for ConfirmPasswordReset {
+    type Result = Result<(), Error>;
+}
+
+impl Handler<ConfirmPasswordReset> for DbExecutor {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, msg: ConfirmPasswordReset, _: &mut Self::Context) -> Self::Result {
+        use super::schema::password_resets::dsl::{self as r, password_resets};
+        use super::schema::users::dsl::{active, hash, users};
 
-        if log_entry.logging_succession {
-            Ok(user)
-        } else {
-            Err(Error::InvalidCredentials)
+        let now = chrono::offset::Utc::now().naive_utc();
+
+        let reset = match password_resets
+            .filter(r::token.eq(token_hash(&msg.token)))
+            .select((r::id, r::user_id, r::expires_at, r::used))
+            .first::<PasswordReset>(&self.0)
+        {
+            Ok(r) => r,
+            Err(diesel::result::Error::NotFound) => return Err(Error::InvalidResetToken),
+            Err(e) => return Err(Error::DbError(e)),
+        };
+        if reset.used || reset.expires_at <= now {
+            return Err(Error::InvalidResetToken);
         }
+
+        self.0.transaction(|| {
+            // The token could have been used concurrently since it was loaded
+            let marked = diesel::update(password_resets.find(reset.id).filter(r::used.eq(false)))
+                .set(r::used.eq(true))
+                .execute(&self.0)?;
+            if marked == 0 {
+                return Err(Error::InvalidResetToken);
+            }
+
+            let updated = diesel::update(users.find(reset.user_id).filter(active.eq(true)))
+                .set(hash.eq(&msg.hash))
+                .execute(&self.0)?;
+            if updated == 0 {
+                return Err(Error::InvalidResetToken);
+            }
+
+            delete_user_sessions(&self.0, reset.user_id)?;
+
+            Ok(())
+        })
     }
 }
 
+/// Deactivates the user and removes their sessions. The last active admin can't be deactivated.
 pub struct DeleteAccount {
     pub id: i32,
 }
@@ -177,35 +412,403 @@ impl Handler<DeleteAccount> for DbExecutor {
     type Result = Result<(), Error>;
 
     fn handle(&mut self, msg: DeleteAccount, _: &mut Self::Context) -> Self::Result {
-        use super::schema::users::dsl::{active, id, users};
+        self.0.transaction(|| deactivate_user(&self.0, msg.id))
+    }
+}
 
-        match diesel::update(users.filter(id.eq(msg.id)))
-            .set(active.eq(false))
-            .execute(&self.0)
-        {
-            Ok(_) => Ok(()),
-            Err(diesel::result::Error::NotFound) => Err(Error::NotFound),
+fn deactivate_user(conn: &SqliteConnection, user_id: i32) -> Result<(), Error> {
+    use super::schema::users::dsl::{active, users};
+
+    let user = match users.find(user_id).first::<User>(conn) {
+        Ok(u) => u,
+        Err(diesel::result::Error::NotFound) => return Err(Error::NotFound),
+        Err(e) => return Err(Error::DbError(e)),
+    };
+
+    if is_last_admin(conn, &user)? {
+        return Err(Error::LastAdmin);
+    }
+
+    diesel::update(users.find(user.id))
+        .set(active.eq(false))
+        .execute(conn)?;
+    delete_user_sessions(conn, user.id)?;
+
+    Ok(())
+}
+
+/// Changes role of the user. The role has to have some permissions, unless it's the customer
+/// role. The last active admin can't be demoted.
+pub struct SetRole {
+    pub id: i32,
+    pub role: String,
+}
+
+impl Message for SetRole {
+    type Result = Result<(), Error>;
+}
+
+impl Handler<SetRole> for DbExecutor {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, msg: SetRole, _: &mut Self::Context) -> Self::Result {
+        use super::schema::users::dsl::{role, users};
+
+        self.0.transaction(|| {
+            if !role_exists(&self.0, &msg.role)? {
+                return Err(Error::UnknownRole);
+            }
+
+            let user = match users.find(msg.id).first::<User>(&self.0) {
+                Ok(u) => u,
+                Err(diesel::result::Error::NotFound) => return Err(Error::NotFound),
+                Err(e) => return Err(Error::DbError(e)),
+            };
+
+            if msg.role != User::ROLE_ADMIN && is_last_admin(&self.0, &user)? {
+                return Err(Error::LastAdmin);
+            }
+
+            diesel::update(users.find(msg.id))
+                .set(role.eq(&msg.role))
+                .execute(&self.0)?;
+
+            Ok(())
+        })
+    }
+}
+
+pub(super) fn is_last_admin(conn: &SqliteConnection, user: &User) -> QueryResult<bool> {
+    use super::schema::users::dsl::{active, role, users};
+
+    if !user.active || user.role != User::ROLE_ADMIN {
+        return Ok(false);
+    }
+
+    let admins: i64 = users
+        .filter(role.eq(User::ROLE_ADMIN))
+        .filter(active.eq(true))
+        .count()
+        .get_result(conn)?;
+
+    Ok(admins <= 1)
+}
+
+pub(crate) fn admin_exists(conn: &SqliteConnection) -> QueryResult<bool> {
+    use super::schema::users::dsl::{active, role, users};
+
+    let admins: i64 = users
+        .filter(role.eq(User::ROLE_ADMIN))
+        .filter(active.eq(true))
+        .count()
+        .get_result(conn)?;
+
+    Ok(admins > 0)
+}
+
+pub(crate) fn user_exists(conn: &SqliteConnection, name: &str) -> QueryResult<bool> {
+    use super::schema::users::dsl::{login, users};
+
+    let count: i64 = users
+        .filter(login.eq(normalize_username(name)))
+        .count()
+        .get_result(conn)?;
+
+    Ok(count > 0)
+}
+
+/// Whether `create_or_promote_admin` created a new account or promoted an existing one.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum AdminBootstrap {
+    Created,
+    Promoted,
+}
+
+/// Gives the admin role to the user, reactivating the account if it was deleted, and replaces
+/// the password if `hash` is given. The user is created if it doesn't exist, which requires
+/// the `hash`, otherwise `NotFound` is returned.
+pub(crate) fn create_or_promote_admin(
+    conn: &SqliteConnection,
+    name: &str,
+    hash: Option<&str>,
+    email: Option<&str>,
+) -> Result<AdminBootstrap, Error> {
+    use super::schema::users::dsl::{self, users};
+
+    let username = normalize_username(name);
+
+    conn.transaction(
+        || match users.filter(dsl::login.eq(&username)).first::<User>(conn) {
+            Ok(user) => {
+                diesel::update(users.find(user.id))
+                    .set((dsl::role.eq(User::ROLE_ADMIN), dsl::active.eq(true)))
+                    .execute(conn)?;
+                if let Some(hash) = hash {
+                    diesel::update(users.find(user.id))
+                        .set(dsl::hash.eq(hash))
+                        .execute(conn)?;
+                }
+
+                Ok(AdminBootstrap::Promoted)
+            }
+            Err(diesel::result::Error::NotFound) => {
+                let hash = hash.ok_or(Error::NotFound)?;
+                let new_user = NewUser {
+                    login: &username,
+                    hash,
+                    role: User::ROLE_ADMIN,
+                    email,
+                    oidc_issuer: None,
+                    oidc_subject: None,
+                };
+                diesel::insert_into(users).values(&new_user).execute(conn)?;
+
+                Ok(AdminBootstrap::Created)
+            }
             Err(e) => Err(Error::DbError(e)),
+        },
+    )
+}
+
+/// Activates previously deleted (deactivated) account.
+pub struct ReactivateAccount {
+    pub id: i32,
+}
+
+impl Message for ReactivateAccount {
+    type Result = Result<(), Error>;
+}
+
+impl Handler<ReactivateAccount> for DbExecutor {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, msg: ReactivateAccount, _: &mut Self::Context) -> Self::Result {
+        use super::schema::users::dsl::{active, users};
+
+        match diesel::update(users.find(msg.id))
+            .set(active.eq(true))
+            .execute(&self.0)?
+        {
+            0 => Err(Error::NotFound),
+            _ => Ok(()),
         }
     }
 }
 
-pub struct GetUsers;
+/// Permanently removes the user, together with their sessions, tokens, API keys and logs. History
+/// entries are kept for statistics, but are no longer linked to the user.
+pub struct EraseAccount {
+    pub id: i32,
+}
+
+impl Message for EraseAccount {
+    type Result = Result<(), Error>;
+}
+
+impl Handler<EraseAccount> for DbExecutor {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, msg: EraseAccount, _: &mut Self::Context) -> Self::Result {
+        use super::schema::history::dsl::{self as h, history};
+        use super::schema::logs::dsl::{self as l, logs};
+        use super::schema::password_resets::dsl::{self as r, password_resets};
+        use super::schema::song_revisions::dsl::{self as s, song_revisions};
+        use super::schema::users::dsl::users;
+
+        self.0.transaction(|| {
+            let user = match users.find(msg.id).first::<User>(&self.0) {
+                Ok(u) => u,
+                Err(diesel::result::Error::NotFound) => return Err(Error::NotFound),
+                Err(e) => return Err(Error::DbError(e)),
+            };
+
+            if is_last_admin(&self.0, &user)? {
+                return Err(Error::LastAdmin);
+            }
+
+            delete_user_sessions(&self.0, user.id)?;
+            delete_user_two_factor(&self.0, user.id)?;
+            delete_user_api_keys(&self.0, user.id)?;
+            diesel::delete(password_resets.filter(r::user_id.eq(user.id))).execute(&self.0)?;
+            diesel::update(history.filter(h::user_id.eq(user.id)))
+                .set(h::user_id.eq(None::<i32>))
+                .execute(&self.0)?;
+            diesel::update(song_revisions.filter(s::user_id.eq(user.id)))
+                .set(s::user_id.eq(None::<i32>))
+                .execute(&self.0)?;
+            diesel::delete(logs.filter(l::login.eq(&user.login))).execute(&self.0)?;
+            diesel::delete(users.find(user.id)).execute(&self.0)?;
+
+            Ok(())
+        })
+    }
+}
+
+/// Users ordered by ID.
+pub struct GetUsers {
+    pub page: PageRequest,
+}
 
 impl Message for GetUsers {
-    type Result = Result<Vec<User>, Error>;
+    type Result = Result<Page<User>, Error>;
 }
 
 impl Handler<GetUsers> for DbExecutor {
-    type Result = Result<Vec<User>, Error>;
+    type Result = Result<Page<User>, Error>;
+
+    fn handle(&mut self, msg: GetUsers, _: &mut Self::Context) -> Self::Result {
+        use super::schema::users::dsl::{id, users};
+
+        let total = users.count().get_result(&self.0)?;
+        let entries = users
+            .order(id.asc())
+            .offset(msg.page.offset)
+            .limit(msg.page.limit)
+            .load::<User>(&self.0)?;
+
+        Ok(Page::new(entries, total, msg.page))
+    }
+}
+
+pub struct GetUser {
+    pub id: i32,
+}
+
+impl Message for GetUser {
+    type Result = Result<User, Error>;
+}
+
+impl Handler<GetUser> for DbExecutor {
+    type Result = Result<User, Error>;
 
-    fn handle(&mut self, _msg: GetUsers, _: &mut Self::Context) -> Self::Result {
+    fn handle(&mut self, msg: GetUser, _: &mut Self::Context) -> Self::Result {
         use super::schema::users::dsl::users;
 
-        Ok(users.load::<User>(&self.0)?)
+        match users.find(msg.id).first::<User>(&self.0) {
+            Ok(u) => Ok(u),
+            Err(diesel::result::Error::NotFound) => Err(Error::NotFound),
+            Err(e) => Err(Error::DbError(e)),
+        }
     }
 }
 
-fn normalize_username(s: &str) -> String {
+pub(crate) fn normalize_username(s: &str) -> String {
     s.nfkc().collect::<String>().to_lowercase()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{test_connection, test_user};
+
+    #[test]
+    fn pending_attempts_count_towards_lock() {
+        let conn = test_connection();
+        let lockout = LockoutConfig {
+            account_threshold: 3,
+            ..LockoutConfig::default()
+        };
+
+        // Passwords of these attempts are still being checked
+        for _ in 0..3 {
+            log_attempt(&conn, &lockout, "alice", "192.0.2.1", "").unwrap();
+        }
+
+        match log_attempt(&conn, &lockout, "alice", "192.0.2.2", "") {
+            Err(Error::LockedOut(_)) => (),
+            r => panic!("expected lock, got {:?}", r),
+        }
+        assert!(log_attempt(&conn, &lockout, "bob", "192.0.2.2", "").is_ok());
+    }
+
+    #[test]
+    fn attempt_id_is_returned() {
+        use crate::db::schema::logs::dsl::{login, logs};
+
+        let conn = test_connection();
+        let lockout = LockoutConfig::default();
+
+        let first = log_attempt(&conn, &lockout, "alice", "", "").unwrap();
+        let second = log_attempt(&conn, &lockout, "bob", "", "").unwrap();
+
+        assert_ne!(first, second);
+        assert_eq!(
+            logs.find(second)
+                .select(login)
+                .first::<String>(&conn)
+                .unwrap(),
+            "bob"
+        );
+    }
+
+    fn add_session(conn: &SqliteConnection, user_id: i32) {
+        use crate::db::models::NewSession;
+        use crate::db::schema::sessions::dsl::sessions;
+
+        let now = chrono::offset::Utc::now().naive_utc();
+        diesel::insert_into(sessions)
+            .values(&NewSession {
+                token: &format!("token{}", user_id),
+                user_id,
+                created_at: now,
+                last_active: now,
+                family: None,
+                ip_addr: "",
+                user_agent: "",
+            })
+            .execute(conn)
+            .unwrap();
+    }
+
+    fn session_count(conn: &SqliteConnection, user_id: i32) -> i64 {
+        use crate::db::schema::sessions::dsl::{self, sessions};
+
+        sessions
+            .filter(dsl::user_id.eq(user_id))
+            .count()
+            .get_result(conn)
+            .unwrap()
+    }
+
+    #[test]
+    fn last_admin_cant_be_deactivated() {
+        let conn = test_connection();
+        let admin = test_user(&conn, "admin", User::ROLE_ADMIN);
+        add_session(&conn, admin);
+
+        match deactivate_user(&conn, admin) {
+            Err(Error::LastAdmin) => (),
+            r => panic!("expected LastAdmin, got {:?}", r),
+        }
+        assert!(admin_exists(&conn).unwrap());
+        assert_eq!(session_count(&conn, admin), 1);
+    }
+
+    #[test]
+    fn deactivation_removes_sessions() {
+        let conn = test_connection();
+        let first = test_user(&conn, "admin", User::ROLE_ADMIN);
+        let second = test_user(&conn, "admin2", User::ROLE_ADMIN);
+        add_session(&conn, first);
+        add_session(&conn, second);
+
+        deactivate_user(&conn, first).unwrap();
+
+        assert_eq!(session_count(&conn, first), 0);
+        assert_eq!(session_count(&conn, second), 1);
+        match deactivate_user(&conn, second) {
+            Err(Error::LastAdmin) => (),
+            r => panic!("expected LastAdmin, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn deactivating_missing_user_fails() {
+        let conn = test_connection();
+
+        match deactivate_user(&conn, 1) {
+            Err(Error::NotFound) => (),
+            r => panic!("expected NotFound, got {:?}", r),
+        }
+    }
+}
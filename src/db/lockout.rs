@@ -0,0 +1,393 @@
+use actix::prelude::*;
+use actix_web::{dev::Body, http::StatusCode, web::HttpResponse, ResponseError};
+use chrono::{Duration, NaiveDateTime};
+use diesel::expression::dsl::max;
+use diesel::prelude::*;
+use failure_derive::Fail;
+use serde::Deserialize;
+
+use crate::db::auth::normalize_username;
+use crate::db::models::NewLockoutClear;
+use crate::db::DbExecutor;
+
+/// Login attempts are blocked after `threshold` failed attempts within `window` seconds. Every
+/// next failed attempt doubles the lock time, starting at `base_delay` seconds, up to
+/// `max_delay` seconds. Successful login resets the account counter. Threshold equal to 0
+/// disables the lock. Behind a reverse proxy the IP lock works only if the proxy is listed in
+/// `trusted_proxies`.
+#[derive(Clone, Copy, Debug, Deserialize)]
+pub struct LockoutConfig {
+    #[serde(default = "default_account_threshold")]
+    pub account_threshold: u32,
+    #[serde(default = "default_ip_threshold")]
+    pub ip_threshold: u32,
+    #[serde(default = "default_window")]
+    pub window: i64,
+    #[serde(default = "default_base_delay")]
+    pub base_delay: i64,
+    #[serde(default = "default_max_delay")]
+    pub max_delay: i64,
+}
+
+impl Default for LockoutConfig {
+    fn default() -> Self {
+        Self {
+            account_threshold: default_account_threshold(),
+            ip_threshold: default_ip_threshold(),
+            window: default_window(),
+            base_delay: default_base_delay(),
+            max_delay: default_max_delay(),
+        }
+    }
+}
+
+fn default_account_threshold() -> u32 {
+    5
+}
+
+fn default_ip_threshold() -> u32 {
+    20
+}
+
+fn default_window() -> i64 {
+    60 * 60
+}
+
+fn default_base_delay() -> i64 {
+    30
+}
+
+fn default_max_delay() -> i64 {
+    60 * 60
+}
+
+/// Removes the lock of the account, the IP address, or both.
+pub struct ClearLockout {
+    pub login: Option<String>,
+    pub ip_addr: Option<String>,
+}
+
+#[derive(Debug, Fail)]
+pub enum Error {
+    #[fail(display = "Database error: {}", _0)]
+    DbError(#[cause] diesel::result::Error),
+}
+
+impl ResponseError for Error {
+    fn error_response(&self) -> HttpResponse<Body> {
+        match self {
+            Error::DbError(_) => HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR),
+        }
+    }
+}
+
+impl From<diesel::result::Error> for Error {
+    fn from(f: diesel::result::Error) -> Self {
+        Error::DbError(f)
+    }
+}
+
+impl Message for ClearLockout {
+    type Result = Result<(), Error>;
+}
+
+impl Handler<ClearLockout> for DbExecutor {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, msg: ClearLockout, _: &mut Self::Context) -> Self::Result {
+        use super::schema::lockout_clears::dsl::lockout_clears;
+
+        let login = msg.login.as_ref().map(|l| normalize_username(l));
+
+        diesel::insert_into(lockout_clears)
+            .values(&NewLockoutClear {
+                login: login.as_ref().map(AsRef::as_ref),
+                ip_addr: msg.ip_addr.as_ref().map(AsRef::as_ref),
+                cleared_at: chrono::offset::Utc::now().naive_utc(),
+            })
+            .execute(&self.0)?;
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Key<'a> {
+    Login(&'a str),
+    IpAddr(&'a str),
+}
+
+/// Returns number of seconds after which login attempt for given account from given address will
+/// be allowed, or `None` if it's allowed now.
+pub(super) fn retry_after(
+    conn: &SqliteConnection,
+    config: &LockoutConfig,
+    login: &str,
+    ip_addr: &str,
+) -> QueryResult<Option<i64>> {
+    let account = key_retry_after(conn, config, Key::Login(login), config.account_threshold)?;
+    let ip = if ip_addr.is_empty() {
+        None
+    } else {
+        key_retry_after(conn, config, Key::IpAddr(ip_addr), config.ip_threshold)?
+    };
+
+    Ok(account.into_iter().chain(ip).max())
+}
+
+fn key_retry_after(
+    conn: &SqliteConnection,
+    config: &LockoutConfig,
+    key: Key,
+    threshold: u32,
+) -> QueryResult<Option<i64>> {
+    use super::schema::lockout_clears::dsl::{self as c, lockout_clears};
+    use super::schema::logs::dsl::{ip_addr, logging_succession, logging_time, login, logs};
+
+    if threshold == 0 {
+        return Ok(None);
+    }
+
+    let now = chrono::offset::Utc::now().naive_utc();
+
+    let (last_clear, last_success) = match key {
+        Key::Login(l) => (
+            lockout_clears
+                .filter(c::login.eq(l))
+                .select(max(c::cleared_at))
+                .first::<Option<NaiveDateTime>>(conn)?,
+            logs.filter(login.eq(l))
+                .filter(logging_succession.eq(true))
+                .select(max(logging_time))
+                .first::<Option<NaiveDateTime>>(conn)?,
+        ),
+        Key::IpAddr(ip) => (
+            lockout_clears
+                .filter(c::ip_addr.eq(ip))
+                .select(max(c::cleared_at))
+                .first::<Option<NaiveDateTime>>(conn)?,
+            // Attacker could reset the counter by logging into their own account
+            None,
+        ),
+    };
+
+    let since = last_clear
+        .into_iter()
+        .chain(last_success)
+        .fold(now - Duration::seconds(config.window), |a, b| a.max(b));
+
+    let failed_attempts = || {
+        let query = logs
+            .filter(logging_succession.eq(false))
+            .filter(logging_time.gt(since))
+            .into_boxed();
+
+        match key {
+            Key::Login(l) => query.filter(login.eq(l)),
+            Key::IpAddr(ip) => query.filter(ip_addr.eq(ip)),
+        }
+    };
+
+    let failures: i64 = failed_attempts().count().get_result(conn)?;
+    let last_failure = failed_attempts()
+        .select(max(logging_time))
+        .first::<Option<NaiveDateTime>>(conn)?;
+
+    let last_failure = match last_failure {
+        Some(t) if failures >= i64::from(threshold) => t,
+        _ => return Ok(None),
+    };
+
+    let exponent = (failures - i64::from(threshold)).min(30);
+    let delay = config
+        .base_delay
+        .saturating_mul(1 << exponent)
+        .min(config.max_delay);
+    let locked_until = last_failure + Duration::seconds(delay);
+
+    if locked_until > now {
+        Ok(Some((locked_until - now).num_seconds().max(1)))
+    } else {
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::models::UserLog;
+    use crate::db::test_connection;
+
+    const CONFIG: LockoutConfig = LockoutConfig {
+        account_threshold: 3,
+        ip_threshold: 5,
+        window: 3600,
+        base_delay: 30,
+        max_delay: 100,
+    };
+
+    fn attempt(conn: &SqliteConnection, login: &str, ip_addr: &str, success: bool, secs_ago: i64) {
+        use crate::db::schema::logs::dsl::logs;
+
+        diesel::insert_into(logs)
+            .values(&UserLog {
+                login,
+                logging_time: chrono::offset::Utc::now().naive_utc() - Duration::seconds(secs_ago),
+                logging_succession: success,
+                ip_addr,
+                user_agent: "",
+            })
+            .execute(conn)
+            .unwrap();
+    }
+
+    fn clear(conn: &SqliteConnection, login: Option<&str>, ip_addr: Option<&str>) {
+        use crate::db::schema::lockout_clears::dsl::lockout_clears;
+
+        diesel::insert_into(lockout_clears)
+            .values(&NewLockoutClear {
+                login,
+                ip_addr,
+                cleared_at: chrono::offset::Utc::now().naive_utc(),
+            })
+            .execute(conn)
+            .unwrap();
+    }
+
+    fn fail_times(conn: &SqliteConnection, login: &str, ip_addr: &str, n: usize) {
+        for _ in 0..n {
+            attempt(conn, login, ip_addr, false, 1);
+        }
+    }
+
+    /// Asserts that the lock ends in `delay` seconds since the last failure, 1 second ago.
+    fn assert_locked_for(retry_after: Option<i64>, delay: i64) {
+        match retry_after {
+            Some(secs) => assert!(
+                secs >= delay - 2 && secs <= delay,
+                "expected about {}s, got {}s",
+                delay,
+                secs
+            ),
+            None => panic!("expected lock for {}s", delay),
+        }
+    }
+
+    #[test]
+    fn allowed_below_threshold() {
+        let conn = test_connection();
+        fail_times(&conn, "alice", "192.0.2.1", 2);
+
+        assert_eq!(
+            retry_after(&conn, &CONFIG, "alice", "192.0.2.1").unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn delay_doubles_with_every_failure() {
+        let conn = test_connection();
+
+        fail_times(&conn, "alice", "192.0.2.1", 3);
+        assert_locked_for(retry_after(&conn, &CONFIG, "alice", "").unwrap(), 30);
+
+        fail_times(&conn, "alice", "192.0.2.1", 1);
+        assert_locked_for(retry_after(&conn, &CONFIG, "alice", "").unwrap(), 60);
+    }
+
+    #[test]
+    fn delay_is_capped() {
+        let conn = test_connection();
+        fail_times(&conn, "alice", "192.0.2.1", 40);
+
+        assert_locked_for(retry_after(&conn, &CONFIG, "alice", "").unwrap(), 100);
+    }
+
+    #[test]
+    fn lock_expires() {
+        let conn = test_connection();
+        for _ in 0..3 {
+            attempt(&conn, "alice", "192.0.2.1", false, 31);
+        }
+
+        assert_eq!(retry_after(&conn, &CONFIG, "alice", "").unwrap(), None);
+    }
+
+    #[test]
+    fn failures_outside_window_are_ignored() {
+        let conn = test_connection();
+        for _ in 0..10 {
+            attempt(&conn, "alice", "192.0.2.1", false, 3601);
+        }
+        fail_times(&conn, "alice", "192.0.2.1", 2);
+
+        assert_eq!(retry_after(&conn, &CONFIG, "alice", "").unwrap(), None);
+    }
+
+    #[test]
+    fn success_resets_account_counter() {
+        let conn = test_connection();
+        for _ in 0..3 {
+            attempt(&conn, "alice", "192.0.2.1", false, 10);
+        }
+        attempt(&conn, "alice", "192.0.2.1", true, 5);
+        fail_times(&conn, "alice", "192.0.2.1", 2);
+
+        assert_eq!(retry_after(&conn, &CONFIG, "alice", "").unwrap(), None);
+    }
+
+    #[test]
+    fn success_doesnt_reset_ip_counter() {
+        let conn = test_connection();
+        for i in 0..5 {
+            attempt(&conn, &format!("user{}", i), "192.0.2.1", false, 10);
+        }
+        attempt(&conn, "mallory", "192.0.2.1", true, 5);
+        fail_times(&conn, "user9", "192.0.2.1", 1);
+
+        assert_locked_for(
+            retry_after(&conn, &CONFIG, "alice", "192.0.2.1").unwrap(),
+            60,
+        );
+        assert_eq!(
+            retry_after(&conn, &CONFIG, "alice", "192.0.2.2").unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn clear_resets_counters() {
+        let conn = test_connection();
+        for _ in 0..5 {
+            attempt(&conn, "alice", "192.0.2.1", false, 10);
+        }
+
+        clear(&conn, Some("alice"), None);
+        assert_eq!(retry_after(&conn, &CONFIG, "alice", "").unwrap(), None);
+        assert!(retry_after(&conn, &CONFIG, "alice", "192.0.2.1")
+            .unwrap()
+            .is_some());
+
+        clear(&conn, None, Some("192.0.2.1"));
+        assert_eq!(
+            retry_after(&conn, &CONFIG, "alice", "192.0.2.1").unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn zero_threshold_disables_lock() {
+        let conn = test_connection();
+        fail_times(&conn, "alice", "192.0.2.1", 10);
+        let config = LockoutConfig {
+            account_threshold: 0,
+            ip_threshold: 0,
+            ..CONFIG
+        };
+
+        assert_eq!(
+            retry_after(&conn, &config, "alice", "192.0.2.1").unwrap(),
+            None
+        );
+    }
+}
@@ -15,7 +15,10 @@ lazy_static! {
             .expect("Invalid ua_regexes.yaml file");
 }
 
-pub struct GetLogs;
+/// Returns login attempts, only for given login if `login` is set.
+pub struct GetLogs {
+    pub login: Option<String>,
+}
 
 #[derive(Debug, Fail)]
 pub enum Error {
@@ -44,21 +47,18 @@ impl Message for GetLogs {
 impl Handler<GetLogs> for DbExecutor {
     type Result = Result<Vec<LogEntry>, Error>;
 
-    fn handle(&mut self, _msg: GetLogs, _: &mut Self::Context) -> Self::Result {
-        use super::schema::logs::dsl::logs;
+    fn handle(&mut self, msg: GetLogs, _: &mut Self::Context) -> Self::Result {
+        use super::schema::logs::dsl::{login, logs};
 
-        let vlog = logs.load::<Log>(&self.0)?;
+        let vlog = match msg.login {
+            Some(l) => logs.filter(login.eq(l)).load::<Log>(&self.0)?,
+            None => logs.load::<Log>(&self.0)?,
+        };
 
         Ok(vlog
             .into_iter()
             .map(|log| {
-                let ua = UA_PARSER.parse(&log.user_agent);
-                let os = format!("{} {}", ua.os.family, ua.os.major.unwrap_or_default());
-                let browser = format!(
-                    "{} {}",
-                    ua.user_agent.family,
-                    ua.user_agent.major.unwrap_or_default()
-                );
+                let (os, browser) = parse_user_agent(&log.user_agent);
 
                 LogEntry {
                     id: log.id,
@@ -73,3 +73,16 @@ impl Handler<GetLogs> for DbExecutor {
             .collect())
     }
 }
+
+/// Returns operating system and browser (with major versions) from the User-Agent header.
+pub(crate) fn parse_user_agent(user_agent: &str) -> (String, String) {
+    let ua = UA_PARSER.parse(user_agent);
+    let os = format!("{} {}", ua.os.family, ua.os.major.unwrap_or_default());
+    let browser = format!(
+        "{} {}",
+        ua.user_agent.family,
+        ua.user_agent.major.unwrap_or_default()
+    );
+
+    (os, browser)
+}
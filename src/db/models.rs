@@ -1,7 +1,11 @@
-use super::schema::{history, logs, songs, users};
+use super::schema::{
+    api_keys, history, lockout_clears, login_challenges, logs, oidc_logins, password_resets,
+    recovery_codes, refresh_tokens, revoked_tokens, role_permissions, sessions, song_revisions,
+    songs, two_factor, users,
+};
 use chrono::NaiveDateTime;
 use diesel::{Insertable, Queryable};
-use serde::{Deserialize, Serialize};
+use serde::Serialize;
 
 #[derive(Clone, Queryable, Debug, Serialize)]
 pub struct User {
@@ -12,6 +16,11 @@ pub struct User {
     pub hash: String,
     pub role: String,
     pub active: bool,
+    pub email: Option<String>,
+    /// Identity provider of users created by OpenID Connect login.
+    pub oidc_issuer: Option<String>,
+    /// Subject (user ID) at the identity provider.
+    pub oidc_subject: Option<String>,
 }
 
 impl User {
@@ -25,9 +34,12 @@ pub struct NewUser<'a> {
     pub login: &'a str,
     pub hash: &'a str,
     pub role: &'a str,
+    pub email: Option<&'a str>,
+    pub oidc_issuer: Option<&'a str>,
+    pub oidc_subject: Option<&'a str>,
 }
 
-#[derive(Clone, Queryable, Debug, Serialize, Deserialize, AsChangeset, Identifiable)]
+#[derive(Clone, Queryable, Debug, Serialize, Identifiable)]
 pub struct Song {
     pub id: i32,
     pub artist: String,
@@ -35,6 +47,11 @@ pub struct Song {
     pub genre: String,
     pub url: String,
     pub featured: bool,
+    /// Deleted songs are kept for the history, but aren't returned anywhere else.
+    #[serde(skip)]
+    pub deleted: bool,
+    /// Incremented on every change, used by `PATCH /songs/{id}` to detect concurrent edits.
+    pub version: i32,
 }
 
 #[derive(Clone, Queryable, Debug)]
@@ -53,6 +70,20 @@ pub struct NewHistory {
     pub matched_at: NaiveDateTime,
 }
 
+#[derive(Clone, Insertable, Debug)]
+#[table_name = "song_revisions"]
+pub struct NewSongRevision<'a> {
+    pub song_id: i32,
+    pub version: i32,
+    pub artist: &'a str,
+    pub title: &'a str,
+    pub genre: &'a str,
+    pub url: &'a str,
+    pub featured: bool,
+    pub user_id: i32,
+    pub changed_at: NaiveDateTime,
+}
+
 #[derive(Clone, Insertable, Debug)]
 #[table_name = "logs"]
 pub struct UserLog<'a> {
@@ -73,3 +104,177 @@ pub struct Log {
     pub ip_addr: String,
     pub user_agent: String,
 }
+
+/// Session without its owner, which is joined or filtered by when needed, see `SESSION_COLUMNS`.
+#[derive(Clone, Queryable, Debug)]
+pub struct Session {
+    pub id: i32,
+    pub token: String,
+    pub created_at: NaiveDateTime,
+    pub last_active: NaiveDateTime,
+    pub family: Option<String>,
+    pub ip_addr: String,
+    pub user_agent: String,
+}
+
+#[derive(Clone, Insertable, Debug)]
+#[table_name = "sessions"]
+pub struct NewSession<'a> {
+    pub token: &'a str,
+    pub user_id: i32,
+    pub created_at: NaiveDateTime,
+    pub last_active: NaiveDateTime,
+    pub family: Option<&'a str>,
+    pub ip_addr: &'a str,
+    pub user_agent: &'a str,
+}
+
+/// Refresh token looked up by its hash, which is therefore not selected.
+#[derive(Clone, Queryable, Debug)]
+pub struct RefreshToken {
+    pub id: i32,
+    pub user_id: i32,
+    pub family: String,
+    pub expires_at: NaiveDateTime,
+    pub used: bool,
+}
+
+#[derive(Clone, Insertable, Debug)]
+#[table_name = "refresh_tokens"]
+pub struct NewRefreshToken<'a> {
+    pub token: &'a str,
+    pub user_id: i32,
+    pub family: &'a str,
+    pub created_at: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+}
+
+/// Signed token revoked before its expiration, e.g. after logout.
+#[derive(Clone, Insertable, Debug)]
+#[table_name = "revoked_tokens"]
+pub struct RevokedToken<'a> {
+    pub jti: &'a str,
+    pub expires_at: NaiveDateTime,
+}
+
+/// Password reset looked up by its token hash, which is therefore not selected.
+#[derive(Clone, Queryable, Debug)]
+pub struct PasswordReset {
+    pub id: i32,
+    pub user_id: i32,
+    pub expires_at: NaiveDateTime,
+    pub used: bool,
+}
+
+#[derive(Clone, Insertable, Debug)]
+#[table_name = "password_resets"]
+pub struct NewPasswordReset<'a> {
+    pub token: &'a str,
+    pub user_id: i32,
+    pub created_at: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+}
+
+#[derive(Clone, Insertable, Debug)]
+#[table_name = "lockout_clears"]
+pub struct NewLockoutClear<'a> {
+    pub login: Option<&'a str>,
+    pub ip_addr: Option<&'a str>,
+    pub cleared_at: NaiveDateTime,
+}
+
+#[derive(Clone, Queryable, Debug)]
+pub struct TwoFactor {
+    pub user_id: i32,
+    /// Base32 encoded TOTP secret.
+    pub secret: String,
+    /// Secret is enabled after the user confirms it with a valid code.
+    pub enabled: bool,
+    /// Time step of the last accepted code.
+    pub last_step: Option<i64>,
+}
+
+#[derive(Clone, Insertable, Debug)]
+#[table_name = "two_factor"]
+pub struct NewTwoFactor<'a> {
+    pub user_id: i32,
+    pub secret: &'a str,
+}
+
+#[derive(Clone, Insertable, Debug)]
+#[table_name = "recovery_codes"]
+pub struct NewRecoveryCode<'a> {
+    pub user_id: i32,
+    pub code: &'a str,
+}
+
+/// Unexpired login challenge looked up by its token hash, only the columns needed to verify it.
+#[derive(Clone, Queryable, Debug)]
+pub struct LoginChallenge {
+    pub id: i32,
+    pub user_id: i32,
+    pub attempts: i32,
+}
+
+#[derive(Clone, Insertable, Debug)]
+#[table_name = "login_challenges"]
+pub struct NewLoginChallenge<'a> {
+    pub token: &'a str,
+    pub user_id: i32,
+    pub created_at: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+}
+
+#[derive(Clone, Queryable, Debug)]
+pub struct ApiKey {
+    pub id: i32,
+    pub name: String,
+    pub key: String,
+    /// First characters of the key, so it can be recognized.
+    pub prefix: String,
+    /// Owner of the key. The key has at most the rights of its owner.
+    pub user_id: i32,
+    /// Space separated list of permissions, `None` means the key is not restricted.
+    pub scopes: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub last_used: Option<NaiveDateTime>,
+}
+
+#[derive(Clone, Insertable, Debug)]
+#[table_name = "api_keys"]
+pub struct NewApiKey<'a> {
+    pub name: &'a str,
+    pub key: &'a str,
+    pub prefix: &'a str,
+    pub user_id: i32,
+    pub scopes: Option<&'a str>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Clone, Queryable, Insertable, Debug)]
+#[table_name = "role_permissions"]
+pub struct RolePermission {
+    pub role: String,
+    pub permission: String,
+}
+
+#[derive(Clone, Queryable, Debug)]
+pub struct OidcLogin {
+    pub id: i32,
+    pub state: String,
+    /// PKCE code verifier.
+    pub verifier: String,
+    pub nonce: String,
+    pub created_at: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+}
+
+#[derive(Clone, Insertable, Debug)]
+#[table_name = "oidc_logins"]
+pub struct NewOidcLogin<'a> {
+    pub state: &'a str,
+    pub verifier: &'a str,
+    pub nonce: &'a str,
+    pub created_at: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+}
@@ -0,0 +1,308 @@
+use actix::prelude::*;
+use actix_web::{dev::Body, http::StatusCode, web::HttpResponse, ResponseError};
+use chrono::{Duration, NaiveDateTime};
+use diesel::prelude::*;
+use failure_derive::Fail;
+
+use crate::db::auth::{is_last_admin, normalize_username};
+use crate::db::models::{NewOidcLogin, NewUser, OidcLogin, User, UserLog};
+use crate::db::permissions::{auth_info, AuthInfo};
+use crate::db::sessions::token_hash;
+use crate::db::DbExecutor;
+use crate::policy::PolicyConfig;
+
+/// Remembers a login started at the identity provider until the user comes back with `state`.
+pub struct CreateOidcLogin {
+    pub state: [u8; 32],
+    /// PKCE code verifier.
+    pub verifier: String,
+    pub nonce: String,
+    pub lifetime: Duration,
+}
+
+/// Returns and removes the login started with `state`, so every state can be used only once.
+pub struct TakeOidcLogin {
+    pub state: [u8; 32],
+}
+
+/// Signs in the user authenticated by the identity provider. The account is created on the first
+/// login and linked to the issuer and subject of the ID token, the login itself is recorded in
+/// the logs.
+pub struct OidcSignIn {
+    pub issuer: String,
+    pub subject: String,
+    /// Preferred login of a new user, a number is appended if it's already taken. If the policy
+    /// rejects it, `user` is used instead.
+    pub login: String,
+    pub email: Option<String>,
+    /// Whether the provider says the user is an admin, `None` if admin claim isn't configured.
+    pub admin: Option<bool>,
+    pub ip_addr: String,
+    pub user_agent: String,
+    pub policy: PolicyConfig,
+}
+
+#[derive(Debug, Fail)]
+pub enum Error {
+    #[fail(display = "Invalid or expired login state")]
+    InvalidState,
+    #[fail(display = "Account is deactivated")]
+    Deactivated,
+    #[fail(display = "No login allowed by the policy is available for the new account")]
+    NoLoginAvailable,
+    #[fail(display = "Database error: {}", _0)]
+    DbError(#[cause] diesel::result::Error),
+}
+
+impl ResponseError for Error {
+    fn error_response(&self) -> HttpResponse<Body> {
+        match self {
+            Error::InvalidState => HttpResponse::BadRequest().body(self.to_string()),
+            Error::Deactivated => HttpResponse::Forbidden().body(self.to_string()),
+            Error::NoLoginAvailable => HttpResponse::InternalServerError().body(self.to_string()),
+            Error::DbError(_) => HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR),
+        }
+    }
+}
+
+impl From<diesel::result::Error> for Error {
+    fn from(f: diesel::result::Error) -> Self {
+        Error::DbError(f)
+    }
+}
+
+impl Message for CreateOidcLogin {
+    type Result = Result<(), Error>;
+}
+
+impl Handler<CreateOidcLogin> for DbExecutor {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, msg: CreateOidcLogin, _: &mut Self::Context) -> Self::Result {
+        use super::schema::oidc_logins::dsl::oidc_logins;
+
+        let now = chrono::offset::Utc::now().naive_utc();
+
+        diesel::insert_into(oidc_logins)
+            .values(&NewOidcLogin {
+                state: &token_hash(&msg.state),
+                verifier: &msg.verifier,
+                nonce: &msg.nonce,
+                created_at: now,
+                expires_at: now + msg.lifetime,
+            })
+            .execute(&self.0)?;
+
+        Ok(())
+    }
+}
+
+impl Message for TakeOidcLogin {
+    type Result = Result<OidcLogin, Error>;
+}
+
+impl Handler<TakeOidcLogin> for DbExecutor {
+    type Result = Result<OidcLogin, Error>;
+
+    fn handle(&mut self, msg: TakeOidcLogin, _: &mut Self::Context) -> Self::Result {
+        use super::schema::oidc_logins::dsl::{oidc_logins, state};
+
+        let now = chrono::offset::Utc::now().naive_utc();
+
+        self.0.transaction(|| {
+            let login = match oidc_logins
+                .filter(state.eq(token_hash(&msg.state)))
+                .first::<OidcLogin>(&self.0)
+            {
+                Ok(l) => l,
+                Err(diesel::result::Error::NotFound) => return Err(Error::InvalidState),
+                Err(e) => return Err(Error::DbError(e)),
+            };
+
+            diesel::delete(oidc_logins.find(login.id)).execute(&self.0)?;
+
+            if login.expires_at <= now {
+                return Err(Error::InvalidState);
+            }
+
+            Ok(login)
+        })
+    }
+}
+
+impl Message for OidcSignIn {
+    type Result = Result<AuthInfo, Error>;
+}
+
+impl Handler<OidcSignIn> for DbExecutor {
+    type Result = Result<AuthInfo, Error>;
+
+    fn handle(&mut self, msg: OidcSignIn, _: &mut Self::Context) -> Self::Result {
+        use super::schema::logs::dsl::logs;
+        use super::schema::users::dsl::{self, users};
+
+        let conn = &self.0;
+
+        conn.transaction(|| {
+            let user = match users
+                .filter(dsl::oidc_issuer.eq(&msg.issuer))
+                .filter(dsl::oidc_subject.eq(&msg.subject))
+                .first::<User>(conn)
+                .optional()?
+            {
+                Some(user) => user,
+                None => create_user(conn, &msg)?,
+            };
+
+            if !user.active {
+                return Err(Error::Deactivated);
+            }
+
+            // The provider decides who is an admin, but the last admin is never demoted
+            let role = match msg.admin {
+                Some(true) => Some(User::ROLE_ADMIN),
+                Some(false) if user.role == User::ROLE_ADMIN && !is_last_admin(conn, &user)? => {
+                    Some(User::ROLE_CUSTOMER)
+                }
+                _ => None,
+            };
+            let user = match role {
+                Some(role) if role != user.role => {
+                    diesel::update(users.find(user.id))
+                        .set(dsl::role.eq(role))
+                        .execute(conn)?;
+
+                    users.find(user.id).first::<User>(conn)?
+                }
+                _ => user,
+            };
+
+            diesel::insert_into(logs)
+                .values(&UserLog {
+                    login: &user.login,
+                    logging_time: chrono::offset::Utc::now().naive_utc(),
+                    logging_succession: true,
+                    ip_addr: &msg.ip_addr,
+                    user_agent: &msg.user_agent,
+                })
+                .execute(conn)?;
+
+            Ok(auth_info(conn, user)?)
+        })
+    }
+}
+
+/// Creates an account without password for a user who logs in for the first time.
+fn create_user(conn: &SqliteConnection, msg: &OidcSignIn) -> Result<User, Error> {
+    use super::schema::users::dsl::{self, users};
+
+    let preferred = normalize_username(&msg.login);
+    let login = match available_login(conn, &msg.policy, &preferred)? {
+        Some(login) => login,
+        None => available_login(conn, &msg.policy, "user")?.ok_or(Error::NoLoginAvailable)?,
+    };
+
+    let role = match msg.admin {
+        Some(true) => User::ROLE_ADMIN,
+        _ => User::ROLE_CUSTOMER,
+    };
+
+    diesel::insert_into(users)
+        .values(&NewUser {
+            login: &login,
+            hash: "",
+            role,
+            email: msg.email.as_ref().map(AsRef::as_ref),
+            oidc_issuer: Some(&msg.issuer),
+            oidc_subject: Some(&msg.subject),
+        })
+        .execute(conn)?;
+
+    Ok(users.filter(dsl::login.eq(&login)).first::<User>(conn)?)
+}
+
+/// First of `base`, `base2`, `base3`... which isn't taken. Returns `None` if the policy rejects
+/// the login before a free one is found.
+fn available_login(
+    conn: &SqliteConnection,
+    policy: &PolicyConfig,
+    base: &str,
+) -> QueryResult<Option<String>> {
+    use super::schema::users::dsl::{login as user_login, users};
+
+    let mut login = base.to_string();
+    let mut suffix = 1;
+    loop {
+        if policy.check_login(&login).is_err() {
+            return Ok(None);
+        }
+
+        let taken: i64 = users
+            .filter(user_login.eq(&login))
+            .count()
+            .get_result(conn)?;
+        if taken == 0 {
+            return Ok(Some(login));
+        }
+
+        suffix += 1;
+        login = format!("{}{}", base, suffix);
+    }
+}
+
+pub(super) fn purge_oidc_logins(conn: &SqliteConnection, now: NaiveDateTime) -> QueryResult<usize> {
+    use super::schema::oidc_logins::dsl::{expires_at, oidc_logins};
+
+    diesel::delete(oidc_logins.filter(expires_at.le(now))).execute(conn)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_connection;
+
+    fn sign_in(subject: &str, login: &str) -> OidcSignIn {
+        OidcSignIn {
+            issuer: "https://idp.example".to_string(),
+            subject: subject.to_string(),
+            login: login.to_string(),
+            email: None,
+            admin: None,
+            ip_addr: String::new(),
+            user_agent: String::new(),
+            policy: PolicyConfig::default(),
+        }
+    }
+
+    fn created_login(conn: &SqliteConnection, subject: &str, login: &str) -> String {
+        create_user(conn, &sign_in(subject, login)).unwrap().login
+    }
+
+    #[test]
+    fn preferred_login_is_used() {
+        let conn = test_connection();
+
+        assert_eq!(created_login(&conn, "1", "Alice"), "alice");
+        assert_eq!(created_login(&conn, "2", "alice"), "alice2");
+    }
+
+    #[test]
+    fn rejected_login_falls_back_to_user() {
+        let conn = test_connection();
+
+        assert_eq!(created_login(&conn, "1", "admin"), "user");
+        assert_eq!(created_login(&conn, "2", "Root"), "user2");
+        assert_eq!(created_login(&conn, "3", "a"), "user3");
+        assert_eq!(created_login(&conn, "4", "alice smith"), "user4");
+        assert_eq!(created_login(&conn, "5", ""), "user5");
+    }
+
+    #[test]
+    fn suffix_respects_max_length() {
+        let conn = test_connection();
+        let long = "a".repeat(32);
+
+        assert_eq!(created_login(&conn, "1", &long), long);
+        assert_eq!(created_login(&conn, "2", &long), "user");
+    }
+}
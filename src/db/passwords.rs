@@ -0,0 +1,193 @@
+use actix::prelude::*;
+use diesel::prelude::*;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::db::auth::Error;
+use crate::db::DbExecutor;
+
+/// Parameters of new password hashes. Hashes with other parameters are replaced on the next
+/// successful login.
+#[derive(Clone, Copy, Debug, Deserialize)]
+pub struct PasswordHashConfig {
+    #[serde(default = "default_variant")]
+    pub variant: HashVariant,
+    /// Memory cost in KiB.
+    #[serde(default = "default_mem_cost")]
+    pub mem_cost: u32,
+    #[serde(default = "default_time_cost")]
+    pub time_cost: u32,
+    #[serde(default = "default_lanes")]
+    pub lanes: u32,
+    #[serde(default = "default_hash_length")]
+    pub hash_length: u32,
+}
+
+impl Default for PasswordHashConfig {
+    fn default() -> Self {
+        Self {
+            variant: default_variant(),
+            mem_cost: default_mem_cost(),
+            time_cost: default_time_cost(),
+            lanes: default_lanes(),
+            hash_length: default_hash_length(),
+        }
+    }
+}
+
+fn default_variant() -> HashVariant {
+    HashVariant::Argon2id
+}
+
+fn default_mem_cost() -> u32 {
+    128 * 1024 // 128 MiB
+}
+
+fn default_time_cost() -> u32 {
+    2
+}
+
+fn default_lanes() -> u32 {
+    1
+}
+
+fn default_hash_length() -> u32 {
+    32
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum HashVariant {
+    Argon2i,
+    Argon2d,
+    Argon2id,
+}
+
+impl PasswordHashConfig {
+    fn argon2_config(&self) -> argon2::Config<'static> {
+        argon2::Config {
+            ad: &[],
+            hash_length: self.hash_length,
+            lanes: self.lanes,
+            mem_cost: self.mem_cost,
+            secret: &[],
+            thread_mode: argon2::ThreadMode::Sequential,
+            time_cost: self.time_cost,
+            variant: match self.variant {
+                HashVariant::Argon2i => argon2::Variant::Argon2i,
+                HashVariant::Argon2d => argon2::Variant::Argon2d,
+                HashVariant::Argon2id => argon2::Variant::Argon2id,
+            },
+            version: argon2::Version::Version13,
+        }
+    }
+
+    /// Parameters part of hashes created with this config, e.g. `$argon2id$v=19$m=131072,t=2,p=1`.
+    fn params(&self) -> String {
+        let config = self.argon2_config();
+        format!(
+            "${}$v={}$m={},t={},p={}",
+            config.variant, config.version, config.mem_cost, config.time_cost, config.lanes
+        )
+    }
+
+    /// Hashes the password with a random salt.
+    pub fn hash(&self, password: &[u8]) -> Result<String, argon2::Error> {
+        let mut salt = [0u8; 16];
+        rand::thread_rng().fill(&mut salt);
+
+        argon2::hash_encoded(password, &salt, &self.argon2_config())
+    }
+
+    /// Whether the encoded hash was created with other parameters than the current ones.
+    pub fn is_outdated(&self, encoded: &str) -> bool {
+        match split_hash(encoded) {
+            Some((params, hash_length)) => {
+                params != self.params() || hash_length != self.hash_length
+            }
+            None => true,
+        }
+    }
+}
+
+/// Splits encoded hash into its parameters and the length of the hash in bytes.
+fn split_hash(encoded: &str) -> Option<(String, u32)> {
+    // $variant$v=version$m=..,t=..,p=..$salt$hash
+    let parts = encoded.split('$').collect::<Vec<_>>();
+    if parts.len() != 6 || !parts[0].is_empty() {
+        return None;
+    }
+
+    let hash_length = parts[5].len() * 3 / 4;
+
+    Some((parts[..4].join("$"), hash_length as u32))
+}
+
+/// Number of users having password hashes with given parameters.
+#[derive(Debug, Serialize)]
+pub struct HashParamsUsage {
+    /// Parametry w formacie PHC, np. `$argon2id$v=19$m=131072,t=2,p=1`, lub `null` dla
+    /// użytkowników bez hasła (zalogowanych przez OpenID Connect).
+    pub params: Option<String>,
+    pub hash_length: Option<u32>,
+    pub users: usize,
+    /// Czy parametry są zgodne z aktualną konfiguracją.
+    pub current: bool,
+}
+
+/// Counts users by parameters of their password hashes.
+pub struct GetHashParamsUsage {
+    pub config: PasswordHashConfig,
+}
+
+impl Message for GetHashParamsUsage {
+    type Result = Result<Vec<HashParamsUsage>, Error>;
+}
+
+impl Handler<GetHashParamsUsage> for DbExecutor {
+    type Result = Result<Vec<HashParamsUsage>, Error>;
+
+    fn handle(&mut self, msg: GetHashParamsUsage, _: &mut Self::Context) -> Self::Result {
+        use super::schema::users::dsl::{hash, users};
+
+        let mut counts = BTreeMap::new();
+        for encoded in users.select(hash).load::<String>(&self.0)? {
+            let key = if encoded.is_empty() {
+                None
+            } else {
+                split_hash(&encoded)
+            };
+            *counts.entry(key).or_insert(0) += 1;
+        }
+
+        let current_params = msg.config.params();
+
+        Ok(counts
+            .into_iter()
+            .map(|(key, count)| match key {
+                Some((params, hash_length)) => HashParamsUsage {
+                    current: params == current_params && hash_length == msg.config.hash_length,
+                    params: Some(params),
+                    hash_length: Some(hash_length),
+                    users: count,
+                },
+                None => HashParamsUsage {
+                    params: None,
+                    hash_length: None,
+                    users: count,
+                    current: true,
+                },
+            })
+            .collect())
+    }
+}
+
+/// Users created by OpenID Connect login have no password (empty hash) until they reset it.
+pub(crate) fn verify_password(hash: &str, password: &[u8]) -> Result<bool, argon2::Error> {
+    if hash.is_empty() {
+        return Ok(false);
+    }
+
+    argon2::verify_encoded(hash, password)
+}
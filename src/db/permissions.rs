@@ -0,0 +1,136 @@
+use actix::prelude::*;
+use actix_web::{dev::Body, http::StatusCode, web::HttpResponse, ResponseError};
+use diesel::prelude::*;
+use failure_derive::Fail;
+
+use crate::db::models::{RolePermission, User};
+use crate::db::two_factor::is_enabled;
+use crate::db::DbExecutor;
+use crate::permissions::{Permission, UsersWrite};
+
+/// Authenticated user with everything needed to decide what they are allowed to do.
+pub struct AuthInfo {
+    pub user: User,
+    /// Whether the user has two-factor authentication enabled.
+    pub two_factor: bool,
+    /// Permissions of the user's role.
+    pub permissions: Vec<String>,
+}
+
+pub struct GetRolePermissions;
+
+/// Replaces permissions of the role. Role without permissions stops existing. The admin role
+/// can't lose `users:write`, otherwise nobody could manage roles anymore.
+pub struct SetRolePermissions {
+    pub role: String,
+    pub permissions: Vec<String>,
+}
+
+#[derive(Debug, Fail)]
+pub enum Error {
+    #[fail(display = "The admin role has to keep the users:write permission")]
+    ProtectedPermission,
+    #[fail(display = "Database error: {}", _0)]
+    DbError(#[cause] diesel::result::Error),
+}
+
+impl ResponseError for Error {
+    fn error_response(&self) -> HttpResponse<Body> {
+        match self {
+            Error::ProtectedPermission => HttpResponse::Conflict().body(self.to_string()),
+            Error::DbError(_) => HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR),
+        }
+    }
+}
+
+impl From<diesel::result::Error> for Error {
+    fn from(f: diesel::result::Error) -> Self {
+        Error::DbError(f)
+    }
+}
+
+impl Message for GetRolePermissions {
+    type Result = Result<Vec<RolePermission>, Error>;
+}
+
+impl Handler<GetRolePermissions> for DbExecutor {
+    type Result = Result<Vec<RolePermission>, Error>;
+
+    fn handle(&mut self, _msg: GetRolePermissions, _: &mut Self::Context) -> Self::Result {
+        use super::schema::role_permissions::dsl::{permission, role, role_permissions};
+
+        Ok(role_permissions
+            .order((role, permission))
+            .load::<RolePermission>(&self.0)?)
+    }
+}
+
+impl Message for SetRolePermissions {
+    type Result = Result<(), Error>;
+}
+
+impl Handler<SetRolePermissions> for DbExecutor {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, msg: SetRolePermissions, _: &mut Self::Context) -> Self::Result {
+        use super::schema::role_permissions::dsl::{role, role_permissions};
+
+        if msg.role == User::ROLE_ADMIN && !msg.permissions.iter().any(|p| p == UsersWrite::NAME) {
+            return Err(Error::ProtectedPermission);
+        }
+
+        let rows = msg
+            .permissions
+            .iter()
+            .map(|p| RolePermission {
+                role: msg.role.clone(),
+                permission: p.clone(),
+            })
+            .collect::<Vec<_>>();
+
+        self.0.transaction(|| {
+            diesel::delete(role_permissions.filter(role.eq(&msg.role))).execute(&self.0)?;
+            diesel::insert_into(role_permissions)
+                .values(&rows)
+                .execute(&self.0)?;
+
+            Ok(())
+        })
+    }
+}
+
+pub(super) fn auth_info(conn: &SqliteConnection, user: User) -> QueryResult<AuthInfo> {
+    let two_factor = is_enabled(conn, user.id)?;
+    let permissions = role_permissions(conn, &user.role)?;
+
+    Ok(AuthInfo {
+        user,
+        two_factor,
+        permissions,
+    })
+}
+
+fn role_permissions(conn: &SqliteConnection, user_role: &str) -> QueryResult<Vec<String>> {
+    use super::schema::role_permissions::dsl::{permission, role, role_permissions};
+
+    role_permissions
+        .filter(role.eq(user_role))
+        .select(permission)
+        .load(conn)
+}
+
+/// The customer role always exists, other roles exist as long as they have any permissions.
+pub(super) fn role_exists(conn: &SqliteConnection, user_role: &str) -> QueryResult<bool> {
+    use super::schema::role_permissions::dsl::{role, role_permissions};
+
+    if user_role == User::ROLE_CUSTOMER {
+        return Ok(true);
+    }
+
+    let count: i64 = role_permissions
+        .filter(role.eq(user_role))
+        .count()
+        .get_result(conn)?;
+
+    Ok(count > 0)
+}
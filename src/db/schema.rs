@@ -1,3 +1,16 @@
+table! {
+    api_keys (id) {
+        id -> Integer,
+        name -> Text,
+        key -> Text,
+        prefix -> Text,
+        user_id -> Integer,
+        scopes -> Nullable<Text>,
+        created_at -> Timestamp,
+        last_used -> Nullable<Timestamp>,
+    }
+}
+
 table! {
     history (id) {
         id -> Integer,
@@ -7,6 +20,26 @@ table! {
     }
 }
 
+table! {
+    lockout_clears (id) {
+        id -> Integer,
+        login -> Nullable<Text>,
+        ip_addr -> Nullable<Text>,
+        cleared_at -> Timestamp,
+    }
+}
+
+table! {
+    login_challenges (id) {
+        id -> Integer,
+        token -> Text,
+        user_id -> Integer,
+        created_at -> Timestamp,
+        expires_at -> Timestamp,
+        attempts -> Integer,
+    }
+}
+
 table! {
     logs (id) {
         id -> Integer,
@@ -18,6 +51,91 @@ table! {
     }
 }
 
+table! {
+    oidc_logins (id) {
+        id -> Integer,
+        state -> Text,
+        verifier -> Text,
+        nonce -> Text,
+        created_at -> Timestamp,
+        expires_at -> Timestamp,
+    }
+}
+
+table! {
+    password_resets (id) {
+        id -> Integer,
+        token -> Text,
+        user_id -> Integer,
+        created_at -> Timestamp,
+        expires_at -> Timestamp,
+        used -> Bool,
+    }
+}
+
+table! {
+    recovery_codes (id) {
+        id -> Integer,
+        user_id -> Integer,
+        code -> Text,
+        used -> Bool,
+    }
+}
+
+table! {
+    refresh_tokens (id) {
+        id -> Integer,
+        token -> Text,
+        user_id -> Integer,
+        family -> Text,
+        created_at -> Timestamp,
+        expires_at -> Timestamp,
+        used -> Bool,
+    }
+}
+
+table! {
+    revoked_tokens (jti) {
+        jti -> Text,
+        expires_at -> Timestamp,
+    }
+}
+
+table! {
+    role_permissions (role, permission) {
+        role -> Text,
+        permission -> Text,
+    }
+}
+
+table! {
+    sessions (id) {
+        id -> Integer,
+        token -> Text,
+        user_id -> Integer,
+        created_at -> Timestamp,
+        last_active -> Timestamp,
+        family -> Nullable<Text>,
+        ip_addr -> Text,
+        user_agent -> Text,
+    }
+}
+
+table! {
+    song_revisions (id) {
+        id -> Integer,
+        song_id -> Integer,
+        version -> Integer,
+        artist -> Text,
+        title -> Text,
+        genre -> Text,
+        url -> Text,
+        featured -> Bool,
+        user_id -> Nullable<Integer>,
+        changed_at -> Timestamp,
+    }
+}
+
 table! {
     songs (id) {
         id -> Integer,
@@ -26,6 +144,17 @@ table! {
         genre -> Text,
         url -> Text,
         featured -> Bool,
+        deleted -> Bool,
+        version -> Integer,
+    }
+}
+
+table! {
+    two_factor (user_id) {
+        user_id -> Integer,
+        secret -> Text,
+        enabled -> Bool,
+        last_step -> Nullable<BigInt>,
     }
 }
 
@@ -36,10 +165,39 @@ table! {
         hash -> Text,
         role -> Text,
         active -> Bool,
+        email -> Nullable<Text>,
+        oidc_issuer -> Nullable<Text>,
+        oidc_subject -> Nullable<Text>,
     }
 }
 
+joinable!(api_keys -> users (user_id));
 joinable!(history -> songs (song_id));
 joinable!(history -> users (user_id));
+joinable!(login_challenges -> users (user_id));
+joinable!(password_resets -> users (user_id));
+joinable!(recovery_codes -> users (user_id));
+joinable!(refresh_tokens -> users (user_id));
+joinable!(sessions -> users (user_id));
+joinable!(song_revisions -> songs (song_id));
+joinable!(song_revisions -> users (user_id));
+joinable!(two_factor -> users (user_id));
 
-allow_tables_to_appear_in_same_query!(history, logs, songs, users,);
+allow_tables_to_appear_in_same_query!(
+    api_keys,
+    history,
+    lockout_clears,
+    login_challenges,
+    logs,
+    oidc_logins,
+    password_resets,
+    recovery_codes,
+    refresh_tokens,
+    revoked_tokens,
+    role_permissions,
+    sessions,
+    song_revisions,
+    songs,
+    two_factor,
+    users,
+);
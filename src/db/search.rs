@@ -0,0 +1,424 @@
+use actix::prelude::*;
+use diesel::dsl::sql;
+use diesel::prelude::*;
+use diesel::sql_types::{BigInt, Bool, Double, Integer, Text};
+use unicode_normalization::char::is_combining_mark;
+
+use crate::db::models::Song;
+use crate::db::songs::Error;
+use crate::db::DbExecutor;
+use crate::pagination::{Page, PageRequest};
+use crate::songs::{SearchHighlight, SearchResult};
+use crate::utils::fold_diacritics;
+
+/// Full-text search of songs by artist, title and genre. Every word of the query has to match
+/// the beginning of some word of the song. If nothing matches, words are also replaced with
+/// similar indexed words, to find songs despite typos.
+pub struct SearchSongs {
+    pub query: String,
+    pub page: PageRequest,
+}
+
+#[derive(QueryableByName)]
+struct SearchRow {
+    #[sql_type = "Integer"]
+    id: i32,
+    #[sql_type = "Text"]
+    artist: String,
+    #[sql_type = "Text"]
+    title: String,
+    #[sql_type = "Text"]
+    genre: String,
+    #[sql_type = "Text"]
+    url: String,
+    #[sql_type = "Bool"]
+    featured: bool,
+    #[sql_type = "Integer"]
+    version: i32,
+    #[sql_type = "Double"]
+    rank: f64,
+}
+
+#[derive(QueryableByName)]
+struct Count {
+    #[sql_type = "BigInt"]
+    count: i64,
+}
+
+#[derive(QueryableByName)]
+struct Term {
+    #[sql_type = "Text"]
+    term: String,
+}
+
+/// Query word with words it matches: itself as a prefix and similar indexed words exactly.
+struct QueryTerm {
+    prefix: String,
+    similar: Vec<String>,
+}
+
+impl QueryTerm {
+    fn to_fts(&self) -> String {
+        let mut alternatives = vec![format!("\"{}\"*", self.prefix)];
+        alternatives.extend(self.similar.iter().map(|t| format!("\"{}\"", t)));
+
+        format!("({})", alternatives.join(" OR "))
+    }
+
+    fn matches(&self, word: &str) -> bool {
+        word.starts_with(&self.prefix) || self.similar.iter().any(|t| t == word)
+    }
+}
+
+impl Message for SearchSongs {
+    type Result = Result<Page<SearchResult>, Error>;
+}
+
+impl Handler<SearchSongs> for DbExecutor {
+    type Result = Result<Page<SearchResult>, Error>;
+
+    fn handle(&mut self, msg: SearchSongs, _: &mut Self::Context) -> Self::Result {
+        search_songs(&self.0, &msg.query, msg.page)
+    }
+}
+
+fn search_songs(
+    conn: &SqliteConnection,
+    query: &str,
+    page: PageRequest,
+) -> Result<Page<SearchResult>, Error> {
+    let mut terms = words(&fold_diacritics(query))
+        .into_iter()
+        .map(|w| QueryTerm {
+            prefix: w.to_string(),
+            similar: Vec::new(),
+        })
+        .collect::<Vec<_>>();
+    if terms.is_empty() {
+        return Ok(Page::new(Vec::new(), 0, page));
+    }
+
+    let mut total = count_matches(conn, &terms)?;
+    if total == 0 {
+        add_similar_terms(conn, &mut terms)?;
+        total = count_matches(conn, &terms)?;
+    }
+
+    // Title matches are the most important, bm25 is lower for better matches
+    let rows = diesel::sql_query(
+        "SELECT songs.id, songs.artist, songs.title, songs.genre, songs.url, songs.featured,
+            songs.version, bm25(songs_search, 5.0, 10.0, 1.0) rank
+        FROM songs_search
+        JOIN songs ON songs.id = songs_search.rowid
+        WHERE songs_search MATCH ? AND songs.deleted = FALSE
+        ORDER BY rank, songs.id
+        LIMIT ? OFFSET ?;",
+    )
+    .bind::<Text, _>(match_expression(&terms))
+    .bind::<BigInt, _>(page.limit)
+    .bind::<BigInt, _>(page.offset)
+    .load::<SearchRow>(conn)?;
+
+    let results = rows
+        .into_iter()
+        .map(|row| SearchResult {
+            highlight: SearchHighlight {
+                artist: highlight(&row.artist, &terms),
+                title: highlight(&row.title, &terms),
+                genre: highlight(&row.genre, &terms),
+            },
+            score: -row.rank,
+            song: Song {
+                id: row.id,
+                artist: row.artist,
+                title: row.title,
+                genre: row.genre,
+                url: row.url,
+                featured: row.featured,
+                deleted: false,
+                version: row.version,
+            },
+        })
+        .collect();
+
+    Ok(Page::new(results, total, page))
+}
+
+fn match_expression(terms: &[QueryTerm]) -> String {
+    terms
+        .iter()
+        .map(QueryTerm::to_fts)
+        .collect::<Vec<_>>()
+        .join(" AND ")
+}
+
+fn count_matches(conn: &SqliteConnection, terms: &[QueryTerm]) -> QueryResult<i64> {
+    let count =
+        diesel::sql_query("SELECT count(*) count FROM songs_search WHERE songs_search MATCH ?;")
+            .bind::<Text, _>(match_expression(terms))
+            .get_result::<Count>(conn)?;
+
+    Ok(count.count)
+}
+
+fn add_similar_terms(conn: &SqliteConnection, terms: &mut [QueryTerm]) -> QueryResult<()> {
+    let vocabulary =
+        diesel::sql_query("SELECT term FROM songs_search_vocab;").load::<Term>(conn)?;
+
+    for term in terms.iter_mut() {
+        let max_distance = match term.prefix.chars().count() {
+            0..=3 => continue,
+            4..=7 => 1,
+            _ => 2,
+        };
+
+        term.similar = vocabulary
+            .iter()
+            .filter(|t| edit_distance(&t.term, &term.prefix) <= max_distance)
+            .map(|t| t.term.clone())
+            .collect();
+    }
+
+    Ok(())
+}
+
+/// Adds the song to the search index or updates it.
+pub(crate) fn index_song(conn: &SqliteConnection, song: &Song) -> QueryResult<()> {
+    unindex_song(conn, song.id)?;
+    diesel::sql_query(
+        "INSERT INTO songs_search (rowid, artist, title, genre) VALUES (?, ?, ?, ?);",
+    )
+    .bind::<Integer, _>(song.id)
+    .bind::<Text, _>(fold_diacritics(&song.artist))
+    .bind::<Text, _>(fold_diacritics(&song.title))
+    .bind::<Text, _>(fold_diacritics(&song.genre))
+    .execute(conn)?;
+
+    Ok(())
+}
+
+pub(crate) fn unindex_song(conn: &SqliteConnection, song_id: i32) -> QueryResult<()> {
+    diesel::sql_query("DELETE FROM songs_search WHERE rowid = ?;")
+        .bind::<Integer, _>(song_id)
+        .execute(conn)?;
+
+    Ok(())
+}
+
+/// Indexes songs missing in the search index, e.g. added before it was created.
+pub(crate) fn sync_search_index(conn: &SqliteConnection) -> QueryResult<usize> {
+    use super::schema::songs::dsl::{deleted, songs};
+
+    let missing = songs
+        .filter(deleted.eq(false))
+        .filter(sql::<Bool>("id NOT IN (SELECT rowid FROM songs_search)"))
+        .load::<Song>(conn)?;
+
+    conn.transaction(|| {
+        for song in &missing {
+            index_song(conn, song)?;
+        }
+
+        Ok(missing.len())
+    })
+}
+
+/// Words of the folded text, split the same way as by the `unicode61` tokenizer.
+fn words(s: &str) -> Vec<&str> {
+    s.split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .collect()
+}
+
+/// HTML-escaped text with matching words wrapped in `<mark>`.
+fn highlight(text: &str, terms: &[QueryTerm]) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut word = String::new();
+
+    for c in text.chars() {
+        if c.is_alphanumeric() || is_combining_mark(c) {
+            word.push(c);
+        } else {
+            push_word(&mut result, &word, terms);
+            word.clear();
+            result.push_str(&escape_html(c.encode_utf8(&mut [0; 4])));
+        }
+    }
+    push_word(&mut result, &word, terms);
+
+    result
+}
+
+fn push_word(result: &mut String, word: &str, terms: &[QueryTerm]) {
+    let folded = fold_diacritics(word);
+    if words(&folded)
+        .iter()
+        .any(|w| terms.iter().any(|t| t.matches(w)))
+    {
+        result.push_str("<mark>");
+        result.push_str(&escape_html(word));
+        result.push_str("</mark>");
+    } else {
+        result.push_str(&escape_html(word));
+    }
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Levenshtein distance in characters.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut previous = (0..=b.len()).collect::<Vec<_>>();
+
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + if ca == *cb { 0 } else { 1 };
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        previous = current;
+    }
+
+    previous[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_connection;
+
+    fn term(prefix: &str, similar: &[&str]) -> QueryTerm {
+        QueryTerm {
+            prefix: prefix.to_string(),
+            similar: similar.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+
+    fn search(conn: &SqliteConnection, query: &str) -> Page<SearchResult> {
+        search_songs(
+            conn,
+            query,
+            PageRequest {
+                offset: 0,
+                limit: 10,
+            },
+        )
+        .unwrap()
+    }
+
+    fn songs_connection() -> SqliteConnection {
+        let conn = test_connection();
+        diesel::sql_query(
+            "INSERT INTO songs (id, artist, title, genre, url, featured, deleted, version) VALUES
+                (1, 'Maanam', 'Żółta kartka', 'Rock', 'https://example.com/1', FALSE, FALSE, 1),
+                (2, 'Kult', 'Arahja', 'Rock', 'https://example.com/2', FALSE, FALSE, 1),
+                (3, 'Kult', 'Zołta łódź', 'Rock', 'https://example.com/3', FALSE, TRUE, 1);",
+        )
+        .execute(&conn)
+        .unwrap();
+        sync_search_index(&conn).unwrap();
+
+        conn
+    }
+
+    #[test]
+    fn edit_distance_counts_characters() {
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("", "abc"), 3);
+        assert_eq!(edit_distance("abc", ""), 3);
+        assert_eq!(edit_distance("zolta", "zolta"), 0);
+        assert_eq!(edit_distance("żółta", "zolta"), 3);
+        assert_eq!(edit_distance("kartka", "karkta"), 2);
+    }
+
+    #[test]
+    fn words_split_like_tokenizer() {
+        assert_eq!(words("rock'n'roll"), vec!["rock", "n", "roll"]);
+        assert_eq!(words("  ab-cd,  ef 2 "), vec!["ab", "cd", "ef", "2"]);
+        assert_eq!(words("żółta kartka"), vec!["żółta", "kartka"]);
+        assert!(words(" -, ").is_empty());
+    }
+
+    #[test]
+    fn terms_are_quoted_in_match_expression() {
+        assert_eq!(term("zolta", &[]).to_fts(), r#"("zolta"*)"#);
+        assert_eq!(
+            term("zulta", &["zolta", "zulty"]).to_fts(),
+            r#"("zulta"* OR "zolta" OR "zulty")"#
+        );
+        assert_eq!(
+            match_expression(&[term("zolta", &[]), term("kartk", &["kartka"])]),
+            r#"("zolta"*) AND ("kartk"* OR "kartka")"#
+        );
+    }
+
+    #[test]
+    fn highlight_marks_matching_words() {
+        let terms = [term("kar", &[]), term("zulta", &["zolta"])];
+
+        assert_eq!(
+            highlight("Żółta kartka", &terms),
+            "<mark>Żółta</mark> <mark>kartka</mark>"
+        );
+        assert_eq!(highlight("Akarta", &terms), "Akarta");
+        // Combining marks belong to the word
+        assert_eq!(
+            highlight("Z\u{307}o\u{301}łta!", &terms),
+            "<mark>Z\u{307}o\u{301}łta</mark>!"
+        );
+    }
+
+    #[test]
+    fn highlight_escapes_html() {
+        assert_eq!(
+            highlight(r#"Rock & <Roll> "Kartka""#, &[term("roll", &[])]),
+            "Rock &amp; &lt;<mark>Roll</mark>&gt; &quot;Kartka&quot;"
+        );
+        assert_eq!(
+            highlight("<b>", &[term("b", &[])]),
+            "&lt;<mark>b</mark>&gt;"
+        );
+    }
+
+    #[test]
+    fn search_ignores_diacritics() {
+        let conn = songs_connection();
+
+        let page = search(&conn, "zolta");
+        assert_eq!(page.total, 1);
+        assert_eq!(page.items[0].song.id, 1);
+        assert_eq!(page.items[0].highlight.title, "<mark>Żółta</mark> kartka");
+
+        let page = search(&conn, "ŻÓŁ kart");
+        assert_eq!(page.total, 1);
+        assert_eq!(page.items[0].song.id, 1);
+    }
+
+    #[test]
+    fn search_tolerates_typos() {
+        let conn = songs_connection();
+
+        let page = search(&conn, "zulta");
+        assert_eq!(page.total, 1);
+        assert_eq!(page.items[0].song.id, 1);
+        assert_eq!(page.items[0].highlight.title, "<mark>Żółta</mark> kartka");
+
+        // The deleted song isn't indexed
+        assert_eq!(search(&conn, "kilt").total, 1);
+        // Words shorter than four letters have to match exactly
+        assert_eq!(search(&conn, "kul").total, 1);
+        assert_eq!(search(&conn, "klt").total, 0);
+    }
+
+    #[test]
+    fn empty_query_finds_nothing() {
+        let conn = songs_connection();
+
+        assert_eq!(search(&conn, " - ").total, 0);
+    }
+}
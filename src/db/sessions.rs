@@ -0,0 +1,658 @@
+use actix::prelude::*;
+use actix_web::{dev::Body, http::StatusCode, web::HttpResponse, ResponseError};
+use chrono::{Duration, NaiveDateTime};
+use diesel::prelude::*;
+use failure_derive::Fail;
+use rand::Rng;
+
+use crate::db::logs::parse_user_agent;
+use crate::db::models::{NewRefreshToken, NewSession, RefreshToken, RevokedToken, Session, User};
+use crate::db::oidc::purge_oidc_logins;
+use crate::db::permissions::{auth_info, AuthInfo};
+use crate::db::schema::sessions;
+use crate::db::two_factor::purge_login_challenges;
+use crate::db::DbExecutor;
+use crate::sessions::SessionEntry;
+
+/// Creates new session for given user together with a refresh token starting a new token family.
+/// Only hashes of the tokens are stored in the database.
+pub struct CreateSession {
+    pub token: [u8; 32],
+    pub refresh_token: [u8; 32],
+    pub user_id: i32,
+    pub timeouts: SessionTimeouts,
+    pub ip_addr: String,
+    pub user_agent: String,
+}
+
+/// Exchanges refresh token for a new session and a new refresh token from the same family.
+/// Returns owner of the token.
+///
+/// Every refresh token can be used only once. If already used token is presented again, whole
+/// family (refresh tokens and sessions created from them) is revoked, because one of the parties
+/// holding the token is not its rightful owner.
+pub struct RefreshSession {
+    pub refresh_token: [u8; 32],
+    pub new_token: [u8; 32],
+    pub new_refresh_token: [u8; 32],
+    pub timeouts: SessionTimeouts,
+    pub ip_addr: String,
+    pub user_agent: String,
+}
+
+/// Returns owner of the session. Sessions of deactivated users are treated as nonexistent.
+///
+/// Expired sessions are removed, every other access extends the idle deadline of the session.
+/// Session with expired token is kept until it's refreshed, but can't be used.
+pub struct GetSession {
+    pub token: [u8; 32],
+    pub timeouts: SessionTimeouts,
+}
+
+/// Removes session and its token family.
+pub struct DeleteSession {
+    pub token: [u8; 32],
+}
+
+/// Removes all sessions and refresh tokens of given user.
+pub struct DeleteUserSessions {
+    pub user_id: i32,
+}
+
+/// Returns sessions of the user which haven't expired yet, the one with `current` token is marked.
+pub struct GetUserSessions {
+    pub user_id: i32,
+    pub current: Option<[u8; 32]>,
+    pub timeouts: SessionTimeouts,
+}
+
+/// Removes session of the user with given ID and its token family.
+pub struct DeleteUserSession {
+    pub user_id: i32,
+    pub session_id: i32,
+}
+
+/// Adds signed token to the denylist, so it can't be used until it expires.
+pub struct RevokeSignedToken {
+    pub jti: String,
+    pub expires_at: NaiveDateTime,
+}
+
+/// Returns whether signed token is on the denylist.
+pub struct IsTokenRevoked {
+    pub jti: String,
+}
+
+/// Removes all expired sessions, refresh tokens, login challenges, OpenID Connect logins and
+/// denylist entries. Returns number of removed rows.
+pub struct PurgeSessions {
+    pub timeouts: SessionTimeouts,
+}
+
+/// Columns selected into `Session`.
+const SESSION_COLUMNS: (
+    sessions::id,
+    sessions::token,
+    sessions::created_at,
+    sessions::last_active,
+    sessions::family,
+    sessions::ip_addr,
+    sessions::user_agent,
+) = (
+    sessions::id,
+    sessions::token,
+    sessions::created_at,
+    sessions::last_active,
+    sessions::family,
+    sessions::ip_addr,
+    sessions::user_agent,
+);
+
+#[derive(Clone, Copy, Debug)]
+pub struct SessionTimeouts {
+    /// Session is valid for at most this long since it was created.
+    pub lifetime: Duration,
+    /// Session expires if it wasn't used for this long.
+    pub idle_timeout: Duration,
+    /// Token of the session is valid for this long since it was issued. Refreshing the session
+    /// issues a new token.
+    pub access_lifetime: Duration,
+    /// Refresh token is valid for this long since it was issued.
+    pub refresh_lifetime: Duration,
+}
+
+impl SessionTimeouts {
+    fn is_expired(&self, session: &Session, now: NaiveDateTime) -> bool {
+        session.created_at + self.lifetime <= now || session.last_active + self.idle_timeout <= now
+    }
+
+    fn is_access_expired(&self, session: &Session, now: NaiveDateTime) -> bool {
+        session.created_at + self.access_lifetime <= now
+    }
+}
+
+#[derive(Debug, Fail)]
+pub enum Error {
+    #[fail(display = "Session doesn't exist")]
+    NotFound,
+    #[fail(display = "Session expired")]
+    Expired,
+    #[fail(display = "Invalid refresh token")]
+    InvalidRefreshToken,
+    #[fail(display = "Refresh token reused")]
+    RefreshTokenReused,
+    #[fail(display = "Session not found")]
+    SessionNotFound,
+    #[fail(display = "Database error: {}", _0)]
+    DbError(#[cause] diesel::result::Error),
+}
+
+impl ResponseError for Error {
+    fn error_response(&self) -> HttpResponse<Body> {
+        match self {
+            Error::NotFound => HttpResponse::Unauthorized().body("Session doesn't exist"),
+            Error::Expired => HttpResponse::Unauthorized().body("Session expired"),
+            Error::InvalidRefreshToken => {
+                HttpResponse::Unauthorized().body("Invalid refresh token")
+            }
+            Error::RefreshTokenReused => HttpResponse::Unauthorized().body("Refresh token reused"),
+            Error::SessionNotFound => HttpResponse::NotFound().body("Session not found"),
+            Error::DbError(_) => HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR),
+        }
+    }
+}
+
+impl From<diesel::result::Error> for Error {
+    fn from(f: diesel::result::Error) -> Self {
+        Error::DbError(f)
+    }
+}
+
+/// Tokens are kept in the database as base64 encoded SHA-256 hashes, so leaked database doesn't
+/// allow to take over sessions.
+pub fn token_hash(token: &[u8]) -> String {
+    base64::encode(&openssl::sha::sha256(token))
+}
+
+impl Message for CreateSession {
+    type Result = Result<(), Error>;
+}
+
+impl Handler<CreateSession> for DbExecutor {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, msg: CreateSession, _: &mut Self::Context) -> Self::Result {
+        let mut family = [0u8; 16];
+        rand::thread_rng().fill(&mut family);
+        let family = base64::encode(&family);
+
+        self.0.transaction(|| {
+            insert_tokens(
+                &self.0,
+                msg.user_id,
+                &family,
+                &msg.token,
+                &msg.refresh_token,
+                msg.timeouts.refresh_lifetime,
+                (&msg.ip_addr, &msg.user_agent),
+            )
+        })?;
+
+        Ok(())
+    }
+}
+
+impl Message for RefreshSession {
+    type Result = Result<AuthInfo, Error>;
+}
+
+impl Handler<RefreshSession> for DbExecutor {
+    type Result = Result<AuthInfo, Error>;
+
+    fn handle(&mut self, msg: RefreshSession, _: &mut Self::Context) -> Self::Result {
+        use super::schema::refresh_tokens::dsl::{self, refresh_tokens};
+        use super::schema::sessions::dsl::{family, sessions};
+        use super::schema::users::dsl::{active, users};
+
+        let conn = &self.0;
+        let now = chrono::offset::Utc::now().naive_utc();
+
+        // Errors caused by the token are returned as `Ok(Err(_))`, so revocation of the family
+        // isn't rolled back.
+        conn.transaction::<_, Error, _>(|| {
+            let old = match refresh_tokens
+                .filter(dsl::token.eq(token_hash(&msg.refresh_token)))
+                .select((
+                    dsl::id,
+                    dsl::user_id,
+                    dsl::family,
+                    dsl::expires_at,
+                    dsl::used,
+                ))
+                .first::<RefreshToken>(conn)
+            {
+                Ok(t) => t,
+                Err(diesel::result::Error::NotFound) => return Ok(Err(Error::InvalidRefreshToken)),
+                Err(e) => return Err(Error::DbError(e)),
+            };
+
+            if old.used {
+                revoke_family(conn, &old.family)?;
+
+                return Ok(Err(Error::RefreshTokenReused));
+            }
+
+            if old.expires_at <= now {
+                revoke_family(conn, &old.family)?;
+
+                return Ok(Err(Error::Expired));
+            }
+
+            let user = match users
+                .find(old.user_id)
+                .filter(active.eq(true))
+                .first::<User>(conn)
+            {
+                Ok(u) => u,
+                Err(diesel::result::Error::NotFound) => return Ok(Err(Error::InvalidRefreshToken)),
+                Err(e) => return Err(Error::DbError(e)),
+            };
+
+            diesel::update(refresh_tokens.find(old.id))
+                .set(dsl::used.eq(true))
+                .execute(conn)?;
+            // Only the newest access token of the family is valid
+            diesel::delete(sessions.filter(family.eq(&old.family))).execute(conn)?;
+
+            insert_tokens(
+                conn,
+                user.id,
+                &old.family,
+                &msg.new_token,
+                &msg.new_refresh_token,
+                msg.timeouts.refresh_lifetime,
+                (&msg.ip_addr, &msg.user_agent),
+            )?;
+
+            Ok(Ok(auth_info(conn, user)?))
+        })
+        .and_then(|r| r)
+    }
+}
+
+fn insert_tokens(
+    conn: &SqliteConnection,
+    user_id: i32,
+    family: &str,
+    token: &[u8],
+    refresh_token: &[u8],
+    refresh_lifetime: Duration,
+    (ip_addr, user_agent): (&str, &str),
+) -> QueryResult<()> {
+    use super::schema::refresh_tokens::dsl::refresh_tokens;
+    use super::schema::sessions::dsl::sessions;
+
+    let now = chrono::offset::Utc::now().naive_utc();
+    let token = token_hash(token);
+    let refresh_token = token_hash(refresh_token);
+
+    diesel::insert_into(sessions)
+        .values(&NewSession {
+            token: &token,
+            user_id,
+            created_at: now,
+            last_active: now,
+            family: Some(family),
+            ip_addr,
+            user_agent,
+        })
+        .execute(conn)?;
+
+    diesel::insert_into(refresh_tokens)
+        .values(&NewRefreshToken {
+            token: &refresh_token,
+            user_id,
+            family,
+            created_at: now,
+            expires_at: now + refresh_lifetime,
+        })
+        .execute(conn)?;
+
+    Ok(())
+}
+
+/// Removes all sessions and refresh tokens of the user.
+pub(super) fn delete_user_sessions(conn: &SqliteConnection, user_id: i32) -> QueryResult<()> {
+    use super::schema::refresh_tokens::dsl::{self as r, refresh_tokens};
+    use super::schema::sessions::dsl::{self as s, sessions};
+
+    diesel::delete(sessions.filter(s::user_id.eq(user_id))).execute(conn)?;
+    diesel::delete(refresh_tokens.filter(r::user_id.eq(user_id))).execute(conn)?;
+
+    Ok(())
+}
+
+/// Removes all sessions and refresh tokens of the user except the given session and its family.
+pub(super) fn delete_other_sessions(
+    conn: &SqliteConnection,
+    user_id: i32,
+    token: &[u8],
+) -> QueryResult<()> {
+    use super::schema::refresh_tokens::dsl::{self as r, refresh_tokens};
+    use super::schema::sessions::dsl::{self as s, sessions};
+
+    let token = token_hash(token);
+    let current_family = sessions
+        .filter(s::token.eq(&token))
+        .select(s::family)
+        .first::<Option<String>>(conn)
+        .optional()?
+        .and_then(|f| f);
+
+    diesel::delete(
+        sessions
+            .filter(s::user_id.eq(user_id))
+            .filter(s::token.ne(&token)),
+    )
+    .execute(conn)?;
+
+    let other_tokens = refresh_tokens.filter(r::user_id.eq(user_id));
+    if let Some(family) = current_family {
+        diesel::delete(other_tokens.filter(r::family.ne(family))).execute(conn)?;
+    } else {
+        diesel::delete(other_tokens).execute(conn)?;
+    }
+
+    Ok(())
+}
+
+fn revoke_family(conn: &SqliteConnection, family: &str) -> QueryResult<()> {
+    use super::schema::refresh_tokens::dsl::{self, refresh_tokens};
+    use super::schema::sessions::dsl::{self as s, sessions};
+
+    diesel::delete(refresh_tokens.filter(dsl::family.eq(family))).execute(conn)?;
+    diesel::delete(sessions.filter(s::family.eq(family))).execute(conn)?;
+
+    Ok(())
+}
+
+impl Message for GetSession {
+    type Result = Result<AuthInfo, Error>;
+}
+
+impl Handler<GetSession> for DbExecutor {
+    type Result = Result<AuthInfo, Error>;
+
+    fn handle(&mut self, msg: GetSession, _: &mut Self::Context) -> Self::Result {
+        use super::schema::sessions::dsl::{id, last_active, sessions, token};
+        use super::schema::users::dsl::{active, users};
+
+        let (session, user) = match sessions
+            .inner_join(users)
+            .filter(token.eq(token_hash(&msg.token)))
+            .filter(active.eq(true))
+            .select((SESSION_COLUMNS, super::schema::users::all_columns))
+            .first::<(Session, User)>(&self.0)
+        {
+            Ok(r) => r,
+            Err(diesel::result::Error::NotFound) => return Err(Error::NotFound),
+            Err(e) => return Err(Error::DbError(e)),
+        };
+
+        let now = chrono::offset::Utc::now().naive_utc();
+        if msg.timeouts.is_expired(&session, now) {
+            diesel::delete(sessions.filter(id.eq(session.id))).execute(&self.0)?;
+
+            return Err(Error::Expired);
+        }
+        // The session is kept, so it can still be refreshed, listed and revoked
+        if msg.timeouts.is_access_expired(&session, now) {
+            return Err(Error::Expired);
+        }
+
+        diesel::update(sessions.filter(id.eq(session.id)))
+            .set(last_active.eq(now))
+            .execute(&self.0)?;
+
+        Ok(auth_info(&self.0, user)?)
+    }
+}
+
+impl Message for DeleteSession {
+    type Result = Result<(), Error>;
+}
+
+impl Handler<DeleteSession> for DbExecutor {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, msg: DeleteSession, _: &mut Self::Context) -> Self::Result {
+        use super::schema::sessions::dsl::{family, sessions, token};
+
+        let conn = &self.0;
+        let token_family = sessions
+            .filter(token.eq(token_hash(&msg.token)))
+            .select(family)
+            .first::<Option<String>>(conn)
+            .optional()?;
+
+        conn.transaction(|| {
+            diesel::delete(sessions.filter(token.eq(token_hash(&msg.token)))).execute(conn)?;
+
+            match token_family {
+                Some(Some(f)) => revoke_family(conn, &f),
+                _ => Ok(()),
+            }
+        })?;
+
+        Ok(())
+    }
+}
+
+impl Message for DeleteUserSessions {
+    type Result = Result<(), Error>;
+}
+
+impl Handler<DeleteUserSessions> for DbExecutor {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, msg: DeleteUserSessions, _: &mut Self::Context) -> Self::Result {
+        self.0
+            .transaction(|| delete_user_sessions(&self.0, msg.user_id))?;
+
+        Ok(())
+    }
+}
+
+impl Message for GetUserSessions {
+    type Result = Result<Vec<SessionEntry>, Error>;
+}
+
+impl Handler<GetUserSessions> for DbExecutor {
+    type Result = Result<Vec<SessionEntry>, Error>;
+
+    fn handle(&mut self, msg: GetUserSessions, _: &mut Self::Context) -> Self::Result {
+        use super::schema::sessions::dsl::{created_at, sessions, user_id};
+
+        let now = chrono::offset::Utc::now().naive_utc();
+        let current = msg.current.map(|t| token_hash(&t));
+
+        Ok(sessions
+            .filter(user_id.eq(msg.user_id))
+            .order(created_at.desc())
+            .select(SESSION_COLUMNS)
+            .load::<Session>(&self.0)?
+            .into_iter()
+            .filter(|s| !msg.timeouts.is_expired(s, now))
+            .map(|s| {
+                let (os, browser) = parse_user_agent(&s.user_agent);
+
+                SessionEntry {
+                    id: s.id,
+                    created_at: s.created_at,
+                    last_active: s.last_active,
+                    current: current.as_ref() == Some(&s.token),
+                    ip_addr: s.ip_addr,
+                    os,
+                    browser,
+                }
+            })
+            .collect())
+    }
+}
+
+impl Message for DeleteUserSession {
+    type Result = Result<(), Error>;
+}
+
+impl Handler<DeleteUserSession> for DbExecutor {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, msg: DeleteUserSession, _: &mut Self::Context) -> Self::Result {
+        use super::schema::sessions::dsl::{sessions, user_id};
+
+        let conn = &self.0;
+
+        conn.transaction(|| {
+            let session = match sessions
+                .find(msg.session_id)
+                .filter(user_id.eq(msg.user_id))
+                .select(SESSION_COLUMNS)
+                .first::<Session>(conn)
+            {
+                Ok(s) => s,
+                Err(diesel::result::Error::NotFound) => return Err(Error::SessionNotFound),
+                Err(e) => return Err(Error::DbError(e)),
+            };
+
+            diesel::delete(sessions.find(session.id)).execute(conn)?;
+            if let Some(f) = &session.family {
+                revoke_family(conn, f)?;
+            }
+
+            Ok(())
+        })
+    }
+}
+
+impl Message for RevokeSignedToken {
+    type Result = Result<(), Error>;
+}
+
+impl Handler<RevokeSignedToken> for DbExecutor {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, msg: RevokeSignedToken, _: &mut Self::Context) -> Self::Result {
+        use super::schema::revoked_tokens::dsl::revoked_tokens;
+
+        diesel::replace_into(revoked_tokens)
+            .values(&RevokedToken {
+                jti: &msg.jti,
+                expires_at: msg.expires_at,
+            })
+            .execute(&self.0)?;
+
+        Ok(())
+    }
+}
+
+impl Message for IsTokenRevoked {
+    type Result = Result<bool, Error>;
+}
+
+impl Handler<IsTokenRevoked> for DbExecutor {
+    type Result = Result<bool, Error>;
+
+    fn handle(&mut self, msg: IsTokenRevoked, _: &mut Self::Context) -> Self::Result {
+        use super::schema::revoked_tokens::dsl::revoked_tokens;
+
+        let revoked: i64 = revoked_tokens.find(&msg.jti).count().get_result(&self.0)?;
+
+        Ok(revoked > 0)
+    }
+}
+
+impl Message for PurgeSessions {
+    type Result = Result<usize, Error>;
+}
+
+impl Handler<PurgeSessions> for DbExecutor {
+    type Result = Result<usize, Error>;
+
+    fn handle(&mut self, msg: PurgeSessions, _: &mut Self::Context) -> Self::Result {
+        use super::schema::refresh_tokens::dsl::{expires_at, refresh_tokens};
+        use super::schema::revoked_tokens::dsl::{self as d, revoked_tokens};
+        use super::schema::sessions::dsl::{created_at, last_active, sessions};
+
+        let now = chrono::offset::Utc::now().naive_utc();
+
+        let removed_sessions = diesel::delete(
+            sessions.filter(
+                created_at
+                    .le(now - msg.timeouts.lifetime)
+                    .or(last_active.le(now - msg.timeouts.idle_timeout)),
+            ),
+        )
+        .execute(&self.0)?;
+        let removed_tokens =
+            diesel::delete(refresh_tokens.filter(expires_at.le(now))).execute(&self.0)?;
+        let removed_challenges = purge_login_challenges(&self.0, now)?;
+        let removed_revoked =
+            diesel::delete(revoked_tokens.filter(d::expires_at.le(now))).execute(&self.0)?;
+        let removed_oidc_logins = purge_oidc_logins(&self.0, now)?;
+
+        Ok(removed_sessions
+            + removed_tokens
+            + removed_challenges
+            + removed_revoked
+            + removed_oidc_logins)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timeouts() -> SessionTimeouts {
+        SessionTimeouts {
+            lifetime: Duration::days(30),
+            idle_timeout: Duration::days(7),
+            access_lifetime: Duration::minutes(15),
+            refresh_lifetime: Duration::days(60),
+        }
+    }
+
+    fn session(created_at: NaiveDateTime) -> Session {
+        Session {
+            id: 1,
+            token: String::new(),
+            created_at,
+            last_active: created_at,
+            family: Some(String::new()),
+            ip_addr: String::new(),
+            user_agent: String::new(),
+        }
+    }
+
+    #[test]
+    fn token_expires_long_before_session() {
+        let now = chrono::offset::Utc::now().naive_utc();
+        let timeouts = timeouts();
+
+        let fresh = session(now - Duration::minutes(14));
+        assert!(!timeouts.is_access_expired(&fresh, now));
+        assert!(!timeouts.is_expired(&fresh, now));
+
+        let stale = session(now - Duration::minutes(15));
+        assert!(timeouts.is_access_expired(&stale, now));
+        assert!(!timeouts.is_expired(&stale, now));
+    }
+
+    #[test]
+    fn idle_session_expires() {
+        let now = chrono::offset::Utc::now().naive_utc();
+        let mut idle = session(now - Duration::days(8));
+        idle.last_active = now - Duration::days(7);
+
+        assert!(timeouts().is_expired(&idle, now));
+    }
+}
@@ -2,21 +2,26 @@ use actix::prelude::*;
 use actix_web::{dev::Body, http::StatusCode, web::HttpResponse, ResponseError};
 use diesel::prelude::*;
 use diesel::sql_types::Integer;
+use diesel::sqlite::Sqlite;
 use failure_derive::Fail;
 use serde::Deserialize;
 
-use crate::db::models::{NewHistory, Song};
+use crate::db::models::{NewHistory, NewSongRevision, Song};
 use crate::db::schema::songs;
+use crate::db::search::{index_song, unindex_song};
 use crate::db::DbExecutor;
-use crate::songs::{HistoryEntry, TopSong};
+use crate::pagination::{Page, PageRequest};
+use crate::songs::{HistoryEntry, SongDetails, SongRevision, TopSong};
 
 pub struct Recognize {
     pub song_ids: Vec<i32>,
     pub user_id: Option<i32>,
 }
 
+/// Entries are ordered from the oldest. Without `page` all of them are returned.
 pub struct GetHistory {
     pub user_id: Option<i32>,
+    pub page: Option<PageRequest>,
 }
 
 pub struct GetMostPopular {
@@ -25,15 +30,121 @@ pub struct GetMostPopular {
 
 /// Send empty vector to disable filtering.
 #[derive(Deserialize)]
-pub struct GetAllSongs {
+pub struct SongsFilter {
     pub genres: Vec<String>,
     pub artists: Vec<String>,
     #[serde(default)]
     pub featured: Option<bool>,
 }
 
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SongsSort {
+    Id,
+    Artist,
+    Title,
+    Genre,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    Asc,
+    Desc,
+}
+
+/// Songs with equal sort keys are ordered by ID, so pages don't overlap.
+pub struct GetAllSongs {
+    pub filter: SongsFilter,
+    pub sort: SongsSort,
+    pub order: SortOrder,
+    pub page: PageRequest,
+}
+
+/// Every change of the song saves its previous values as a revision made by `user_id`.
 pub struct EditSong {
-    pub song: Song,
+    pub song: EditedSong,
+    pub user_id: i32,
+}
+
+/// Nowe metadane utworu w `POST /edit_song`.
+#[derive(Debug, Deserialize)]
+pub struct EditedSong {
+    pub id: i32,
+    pub artist: String,
+    pub title: String,
+    pub genre: String,
+    pub url: String,
+    pub featured: bool,
+}
+
+/// Changes only the given fields. If `expected_version` is given and the song was changed in
+/// the meantime, returns `VersionMismatch`.
+pub struct PatchSong {
+    pub id: i32,
+    pub patch: SongPatch,
+    pub expected_version: Option<i32>,
+    pub user_id: i32,
+}
+
+/// Revisions of the song, from the newest.
+pub struct GetSongRevisions {
+    pub song_id: i32,
+    pub page: PageRequest,
+}
+
+/// Sets the song to values from the revision, saving the current ones as a new revision.
+pub struct RestoreSongRevision {
+    pub song_id: i32,
+    pub revision_id: i32,
+    pub expected_version: Option<i32>,
+    pub user_id: i32,
+}
+
+/// Pola utworu do zmiany, pominięte pola pozostają bez zmian.
+#[derive(Debug, Default, Deserialize, AsChangeset)]
+#[table_name = "songs"]
+pub struct SongPatch {
+    #[serde(default)]
+    pub artist: Option<String>,
+    #[serde(default)]
+    pub title: Option<String>,
+    #[serde(default)]
+    pub genre: Option<String>,
+    #[serde(default)]
+    pub url: Option<String>,
+    #[serde(default)]
+    pub featured: Option<bool>,
+}
+
+impl SongPatch {
+    fn from_song(song: &EditedSong) -> Self {
+        Self {
+            artist: Some(song.artist.clone()),
+            title: Some(song.title.clone()),
+            genre: Some(song.genre.clone()),
+            url: Some(song.url.clone()),
+            featured: Some(song.featured),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.artist.is_none()
+            && self.title.is_none()
+            && self.genre.is_none()
+            && self.url.is_none()
+            && self.featured.is_none()
+    }
+}
+
+/// Returns the song with its recognition count, unless it's deleted.
+pub struct GetSong {
+    pub id: i32,
+}
+
+/// Marks the song as deleted and removes it from the search index.
+pub struct DeleteSong {
+    pub id: i32,
 }
 
 #[derive(Deserialize, Insertable)]
@@ -53,6 +164,10 @@ pub struct GetAllArtists;
 pub enum Error {
     #[fail(display = "Song was not found")]
     NotFound,
+    #[fail(display = "Song was changed in the meantime")]
+    VersionMismatch,
+    #[fail(display = "Revision was not found")]
+    RevisionNotFound,
     #[fail(display = "Database error: {}", _0)]
     DbError(#[cause] diesel::result::Error),
 }
@@ -62,6 +177,9 @@ impl ResponseError for Error {
         match self {
             Error::DbError(_) => HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR),
             Error::NotFound => HttpResponse::new(StatusCode::NOT_FOUND),
+            Error::VersionMismatch => HttpResponse::build(StatusCode::PRECONDITION_FAILED)
+                .body("Song was changed in the meantime"),
+            Error::RevisionNotFound => HttpResponse::NotFound().body("Revision was not found"),
         }
     }
 }
@@ -85,48 +203,75 @@ impl Handler<Recognize> for DbExecutor {
 
         let mut sgs = Vec::new();
         for song_id in &msg.song_ids {
-            sgs.push(songs.find(song_id).first(&self.0)?);
+            let song: Song = songs.find(song_id).first(&self.0)?;
+            // Deleted songs stay in the populator index
+            if !song.deleted {
+                sgs.push(song);
+            }
         }
 
-        let history_entry = NewHistory {
-            song_id: msg.song_ids[0],
-            user_id: msg.user_id,
-            matched_at: chrono::offset::Utc::now().naive_utc(),
-        };
+        if let Some(best) = sgs.first() {
+            let history_entry = NewHistory {
+                song_id: best.id,
+                user_id: msg.user_id,
+                matched_at: chrono::offset::Utc::now().naive_utc(),
+            };
 
-        diesel::insert_into(history)
-            .values(&history_entry)
-            .execute(&self.0)?;
+            diesel::insert_into(history)
+                .values(&history_entry)
+                .execute(&self.0)?;
+        }
 
         Ok(sgs)
     }
 }
 
 impl Message for GetHistory {
-    type Result = Result<Vec<HistoryEntry>, Error>;
+    type Result = Result<Page<HistoryEntry>, Error>;
 }
 
 impl Handler<GetHistory> for DbExecutor {
-    type Result = Result<Vec<HistoryEntry>, Error>;
+    type Result = Result<Page<HistoryEntry>, Error>;
 
     fn handle(&mut self, msg: GetHistory, _: &mut Self::Context) -> Self::Result {
-        use super::schema::history::dsl::{history, matched_at, song_id, user_id};
+        use super::schema::history::dsl::{history, id, matched_at, song_id, user_id};
         use super::schema::songs::dsl::{artist, genre, songs, title, url};
 
-        let entries = if let Some(uid) = msg.user_id {
-            history
-                .filter(user_id.eq(uid))
-                .inner_join(songs)
-                .select((song_id, artist, title, genre, url, matched_at))
-                .load::<HistoryEntry>(&self.0)?
-        } else {
-            history
-                .inner_join(songs)
-                .select((song_id, artist, title, genre, url, matched_at))
-                .load::<HistoryEntry>(&self.0)?
-        };
+        let mut query = history
+            .inner_join(songs)
+            .select((song_id, artist, title, genre, url, matched_at))
+            .order(id.asc())
+            .into_boxed();
+        let mut count = history.inner_join(songs).count().into_boxed();
 
-        Ok(entries)
+        if let Some(uid) = msg.user_id {
+            query = query.filter(user_id.eq(uid));
+            count = count.filter(user_id.eq(uid));
+        }
+
+        match msg.page {
+            Some(page) => {
+                let entries = query
+                    .offset(page.offset)
+                    .limit(page.limit)
+                    .load::<HistoryEntry>(&self.0)?;
+
+                Ok(Page::new(entries, count.get_result(&self.0)?, page))
+            }
+            None => {
+                let entries = query.load::<HistoryEntry>(&self.0)?;
+                let total = entries.len() as i64;
+
+                Ok(Page::new(
+                    entries,
+                    total,
+                    PageRequest {
+                        offset: 0,
+                        limit: total,
+                    },
+                ))
+            }
+        }
     }
 }
 
@@ -138,10 +283,11 @@ impl Handler<GetMostPopular> for DbExecutor {
     type Result = Result<Vec<TopSong>, Error>;
 
     fn handle(&mut self, msg: GetMostPopular, _: &mut Self::Context) -> Self::Result {
-        use super::schema::songs::dsl::{featured, songs};
+        use super::schema::songs::dsl::{deleted, featured, songs};
 
         let mut featured_songs = songs
             .filter(featured.eq(true))
+            .filter(deleted.eq(false))
             .load::<Song>(&self.0)?
             .into_iter()
             .map(|s| TopSong {
@@ -161,7 +307,7 @@ impl Handler<GetMostPopular> for DbExecutor {
                 SELECT count(song_id) FROM history WHERE songs.id = song_id
             ) cnt
             FROM songs
-            WHERE featured = FALSE
+            WHERE featured = FALSE AND deleted = FALSE
             ORDER BY cnt DESC
             LIMIT ?;",
         )
@@ -175,31 +321,56 @@ impl Handler<GetMostPopular> for DbExecutor {
 }
 
 impl Message for GetAllSongs {
-    type Result = Result<Vec<Song>, Error>;
+    type Result = Result<Page<Song>, Error>;
 }
 
 impl Handler<GetAllSongs> for DbExecutor {
-    type Result = Result<Vec<Song>, Error>;
+    type Result = Result<Page<Song>, Error>;
 
     fn handle(&mut self, msg: GetAllSongs, _: &mut Self::Context) -> Self::Result {
-        use super::schema::songs::dsl::{artist, featured, genre, songs};
+        use super::schema::songs::dsl::{artist, genre, id, title};
+
+        let total = filtered_songs(&msg.filter).count().get_result(&self.0)?;
+
+        let query = filtered_songs(&msg.filter);
+        let query = match (msg.sort, msg.order) {
+            (SongsSort::Id, SortOrder::Asc) => query.order(id.asc()),
+            (SongsSort::Id, SortOrder::Desc) => query.order(id.desc()),
+            (SongsSort::Artist, SortOrder::Asc) => query.order((artist.asc(), id.asc())),
+            (SongsSort::Artist, SortOrder::Desc) => query.order((artist.desc(), id.desc())),
+            (SongsSort::Title, SortOrder::Asc) => query.order((title.asc(), id.asc())),
+            (SongsSort::Title, SortOrder::Desc) => query.order((title.desc(), id.desc())),
+            (SongsSort::Genre, SortOrder::Asc) => query.order((genre.asc(), id.asc())),
+            (SongsSort::Genre, SortOrder::Desc) => query.order((genre.desc(), id.desc())),
+        };
 
-        let mut query = songs.into_boxed();
+        let entries = query
+            .offset(msg.page.offset)
+            .limit(msg.page.limit)
+            .load(&self.0)?;
 
-        if !msg.artists.is_empty() {
-            query = query.filter(artist.eq_any(msg.artists));
-        }
+        Ok(Page::new(entries, total, msg.page))
+    }
+}
 
-        if !msg.genres.is_empty() {
-            query = query.filter(genre.eq_any(msg.genres));
-        }
+fn filtered_songs(filter: &SongsFilter) -> songs::BoxedQuery<'_, Sqlite> {
+    use super::schema::songs::dsl::{artist, deleted, featured, genre, songs};
 
-        if let Some(is_featured) = msg.featured {
-            query = query.filter(featured.eq(is_featured));
-        }
+    let mut query = songs.filter(deleted.eq(false)).into_boxed();
+
+    if !filter.artists.is_empty() {
+        query = query.filter(artist.eq_any(&filter.artists));
+    }
+
+    if !filter.genres.is_empty() {
+        query = query.filter(genre.eq_any(&filter.genres));
+    }
 
-        Ok(query.load(&self.0)?)
+    if let Some(is_featured) = filter.featured {
+        query = query.filter(featured.eq(is_featured));
     }
+
+    query
 }
 
 impl Message for EditSong {
@@ -210,11 +381,215 @@ impl Handler<EditSong> for DbExecutor {
     type Result = Result<(), Error>;
 
     fn handle(&mut self, msg: EditSong, _: &mut Self::Context) -> Self::Result {
-        match msg.song.save_changes::<Song>(&self.0) {
-            Ok(_) => Ok(()),
-            Err(diesel::result::Error::NotFound) => Err(Error::NotFound),
-            Err(e) => Err(Error::DbError(e)),
-        }
+        self.0.transaction(|| {
+            let song = find_song(&self.0, msg.song.id)?;
+            change_song(
+                &self.0,
+                &song,
+                &SongPatch::from_song(&msg.song),
+                msg.user_id,
+            )?;
+
+            Ok(())
+        })
+    }
+}
+
+impl Message for GetSong {
+    type Result = Result<SongDetails, Error>;
+}
+
+impl Handler<GetSong> for DbExecutor {
+    type Result = Result<SongDetails, Error>;
+
+    fn handle(&mut self, msg: GetSong, _: &mut Self::Context) -> Self::Result {
+        use super::schema::history::dsl::{history, song_id};
+
+        let song = find_song(&self.0, msg.id)?;
+        let recognitions = history
+            .filter(song_id.eq(msg.id))
+            .count()
+            .get_result(&self.0)?;
+
+        Ok(SongDetails { song, recognitions })
+    }
+}
+
+impl Message for PatchSong {
+    type Result = Result<Song, Error>;
+}
+
+impl Handler<PatchSong> for DbExecutor {
+    type Result = Result<Song, Error>;
+
+    fn handle(&mut self, msg: PatchSong, _: &mut Self::Context) -> Self::Result {
+        self.0.transaction(|| {
+            let song = find_song(&self.0, msg.id)?;
+            check_version(&song, msg.expected_version)?;
+            if msg.patch.is_empty() {
+                return Ok(song);
+            }
+
+            change_song(&self.0, &song, &msg.patch, msg.user_id)
+        })
+    }
+}
+
+impl Message for GetSongRevisions {
+    type Result = Result<Page<SongRevision>, Error>;
+}
+
+impl Handler<GetSongRevisions> for DbExecutor {
+    type Result = Result<Page<SongRevision>, Error>;
+
+    fn handle(&mut self, msg: GetSongRevisions, _: &mut Self::Context) -> Self::Result {
+        use super::schema::song_revisions::dsl::{self as r, song_revisions};
+        use super::schema::users::dsl::{login, users};
+
+        find_song(&self.0, msg.song_id)?;
+
+        let total = song_revisions
+            .filter(r::song_id.eq(msg.song_id))
+            .count()
+            .get_result(&self.0)?;
+        let entries = song_revisions
+            .left_join(users)
+            .filter(r::song_id.eq(msg.song_id))
+            .select((
+                r::id,
+                r::version,
+                r::artist,
+                r::title,
+                r::genre,
+                r::url,
+                r::featured,
+                r::user_id,
+                login.nullable(),
+                r::changed_at,
+            ))
+            .order(r::id.desc())
+            .offset(msg.page.offset)
+            .limit(msg.page.limit)
+            .load::<SongRevision>(&self.0)?;
+
+        Ok(Page::new(entries, total, msg.page))
+    }
+}
+
+impl Message for RestoreSongRevision {
+    type Result = Result<Song, Error>;
+}
+
+impl Handler<RestoreSongRevision> for DbExecutor {
+    type Result = Result<Song, Error>;
+
+    fn handle(&mut self, msg: RestoreSongRevision, _: &mut Self::Context) -> Self::Result {
+        use super::schema::song_revisions::dsl::{self as r, song_revisions};
+
+        self.0.transaction(|| {
+            let song = find_song(&self.0, msg.song_id)?;
+            check_version(&song, msg.expected_version)?;
+
+            let revision = match song_revisions
+                .find(msg.revision_id)
+                .filter(r::song_id.eq(msg.song_id))
+                .select((r::artist, r::title, r::genre, r::url, r::featured))
+                .first::<(String, String, String, String, bool)>(&self.0)
+            {
+                Ok(r) => r,
+                Err(diesel::result::Error::NotFound) => return Err(Error::RevisionNotFound),
+                Err(e) => return Err(Error::DbError(e)),
+            };
+            let (artist, title, genre, url, featured) = revision;
+            let patch = SongPatch {
+                artist: Some(artist),
+                title: Some(title),
+                genre: Some(genre),
+                url: Some(url),
+                featured: Some(featured),
+            };
+
+            change_song(&self.0, &song, &patch, msg.user_id)
+        })
+    }
+}
+
+/// Returns the song unless it's deleted.
+fn find_song(conn: &SqliteConnection, song_id: i32) -> Result<Song, Error> {
+    use super::schema::songs::dsl::{deleted, songs};
+
+    match songs
+        .find(song_id)
+        .filter(deleted.eq(false))
+        .first::<Song>(conn)
+    {
+        Ok(s) => Ok(s),
+        Err(diesel::result::Error::NotFound) => Err(Error::NotFound),
+        Err(e) => Err(Error::DbError(e)),
+    }
+}
+
+fn check_version(song: &Song, expected_version: Option<i32>) -> Result<(), Error> {
+    match expected_version {
+        Some(expected) if expected != song.version => Err(Error::VersionMismatch),
+        _ => Ok(()),
+    }
+}
+
+/// Saves current values of the song as a revision, applies the patch and updates the search
+/// index. Returns the changed song.
+fn change_song(
+    conn: &SqliteConnection,
+    song: &Song,
+    patch: &SongPatch,
+    user_id: i32,
+) -> Result<Song, Error> {
+    use super::schema::song_revisions::dsl::song_revisions;
+    use super::schema::songs::dsl::{songs, version};
+
+    diesel::insert_into(song_revisions)
+        .values(&NewSongRevision {
+            song_id: song.id,
+            version: song.version,
+            artist: &song.artist,
+            title: &song.title,
+            genre: &song.genre,
+            url: &song.url,
+            featured: song.featured,
+            user_id,
+            changed_at: chrono::offset::Utc::now().naive_utc(),
+        })
+        .execute(conn)?;
+
+    diesel::update(songs.find(song.id))
+        .set((patch, version.eq(version + 1)))
+        .execute(conn)?;
+    let song = songs.find(song.id).first::<Song>(conn)?;
+    index_song(conn, &song)?;
+
+    Ok(song)
+}
+
+impl Message for DeleteSong {
+    type Result = Result<(), Error>;
+}
+
+impl Handler<DeleteSong> for DbExecutor {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, msg: DeleteSong, _: &mut Self::Context) -> Self::Result {
+        use super::schema::songs::dsl::{deleted, songs, version};
+
+        self.0.transaction(|| {
+            let updated = diesel::update(songs.find(msg.id).filter(deleted.eq(false)))
+                .set((deleted.eq(true), version.eq(version + 1)))
+                .execute(&self.0)?;
+            if updated == 0 {
+                return Err(Error::NotFound);
+            }
+
+            Ok(unindex_song(&self.0, msg.id)?)
+        })
     }
 }
 
@@ -228,9 +603,13 @@ impl Handler<AddSong> for DbExecutor {
     fn handle(&mut self, msg: AddSong, _: &mut Self::Context) -> Self::Result {
         use super::schema::songs::dsl::{id, songs};
 
-        diesel::insert_into(songs).values(&msg).execute(&self.0)?;
+        self.0.transaction(|| {
+            diesel::insert_into(songs).values(&msg).execute(&self.0)?;
+            let song = songs.order(id.desc()).first(&self.0)?;
+            index_song(&self.0, &song)?;
 
-        Ok(songs.order(id.desc()).first(&self.0)?)
+            Ok(song)
+        })
     }
 }
 
@@ -242,9 +621,13 @@ impl Handler<GetAllGenres> for DbExecutor {
     type Result = Result<Vec<String>, Error>;
 
     fn handle(&mut self, _msg: GetAllGenres, _: &mut Self::Context) -> Self::Result {
-        use super::schema::songs::dsl::{genre, songs};
+        use super::schema::songs::dsl::{deleted, genre, songs};
 
-        let entries = songs.select(genre).distinct().load(&self.0)?;
+        let entries = songs
+            .filter(deleted.eq(false))
+            .select(genre)
+            .distinct()
+            .load(&self.0)?;
 
         Ok(entries)
     }
@@ -258,10 +641,38 @@ impl Handler<GetAllArtists> for DbExecutor {
     type Result = Result<Vec<String>, Error>;
 
     fn handle(&mut self, _msg: GetAllArtists, _: &mut Self::Context) -> Self::Result {
-        use super::schema::songs::dsl::{artist, songs};
+        use super::schema::songs::dsl::{artist, deleted, songs};
 
-        let entries = songs.select(artist).distinct().load(&self.0)?;
+        let entries = songs
+            .filter(deleted.eq(false))
+            .select(artist)
+            .distinct()
+            .load(&self.0)?;
 
         Ok(entries)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_song as song;
+
+    #[test]
+    fn matching_or_missing_version_passes() {
+        assert!(check_version(&song(3), Some(3)).is_ok());
+        assert!(check_version(&song(3), None).is_ok());
+    }
+
+    #[test]
+    fn stale_version_is_rejected() {
+        match check_version(&song(3), Some(2)) {
+            Err(Error::VersionMismatch) => {}
+            other => panic!("expected VersionMismatch, got {:?}", other),
+        }
+        assert_eq!(
+            Error::VersionMismatch.error_response().status(),
+            StatusCode::PRECONDITION_FAILED
+        );
+    }
+}
@@ -0,0 +1,451 @@
+use actix::prelude::*;
+use actix_web::{dev::Body, http::StatusCode, web::HttpResponse, ResponseError};
+use chrono::{Duration, NaiveDateTime};
+use diesel::prelude::*;
+use failure_derive::Fail;
+
+use crate::db::models::{
+    LoginChallenge, NewLoginChallenge, NewRecoveryCode, NewTwoFactor, TwoFactor, User,
+};
+use crate::db::permissions::{auth_info, AuthInfo};
+use crate::db::sessions::token_hash;
+use crate::db::DbExecutor;
+use crate::totp;
+
+/// Starts enrollment of a new TOTP secret. The secret isn't used until it's confirmed with
+/// `ConfirmTwoFactor`, starting enrollment again replaces unconfirmed secret.
+pub struct EnrollTwoFactor {
+    pub user_id: i32,
+    /// Base32 encoded secret.
+    pub secret: String,
+}
+
+/// Enables pending TOTP secret if the code is valid and replaces recovery codes of the user.
+pub struct ConfirmTwoFactor {
+    pub user_id: i32,
+    pub code: String,
+    pub recovery_codes: Vec<String>,
+}
+
+/// Disables two-factor authentication of the user and removes their recovery codes. The current
+/// TOTP code or an unused recovery code is required, unless `code` is `None` (used by admins).
+pub struct DisableTwoFactor {
+    pub user_id: i32,
+    pub code: Option<String>,
+}
+
+/// Creates a challenge which has to be completed with `VerifyLoginChallenge` to finish logging in
+/// of a user with two-factor authentication enabled.
+pub struct CreateLoginChallenge {
+    pub token: [u8; 32],
+    pub user_id: i32,
+    pub lifetime: Duration,
+}
+
+/// Checks TOTP or recovery code for the login challenge and returns the user on success. The
+/// challenge is removed after it's completed or after `max_attempts` invalid codes.
+pub struct VerifyLoginChallenge {
+    pub token: [u8; 32],
+    pub code: String,
+    pub max_attempts: u32,
+}
+
+#[derive(Debug, Fail)]
+pub enum Error {
+    #[fail(display = "Two-factor authentication is already enabled")]
+    AlreadyEnabled,
+    #[fail(display = "Two-factor authentication is not enabled")]
+    NotEnabled,
+    #[fail(display = "Invalid code")]
+    InvalidCode,
+    #[fail(display = "Invalid or expired login challenge")]
+    InvalidChallenge,
+    #[fail(display = "Database error: {}", _0)]
+    DbError(#[cause] diesel::result::Error),
+    #[fail(display = "Error while computing TOTP: {}", _0)]
+    TotpError(#[cause] openssl::error::ErrorStack),
+}
+
+impl ResponseError for Error {
+    fn error_response(&self) -> HttpResponse<Body> {
+        match self {
+            Error::AlreadyEnabled => {
+                HttpResponse::Conflict().body("Two-factor authentication is already enabled")
+            }
+            Error::NotEnabled => {
+                HttpResponse::BadRequest().body("Two-factor authentication is not enabled")
+            }
+            Error::InvalidCode => HttpResponse::BadRequest().body("Invalid code"),
+            Error::InvalidChallenge => {
+                HttpResponse::Unauthorized().body("Invalid or expired login challenge")
+            }
+            Error::DbError(_) => HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR),
+            Error::TotpError(_) => HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR),
+        }
+    }
+}
+
+impl From<diesel::result::Error> for Error {
+    fn from(f: diesel::result::Error) -> Self {
+        Error::DbError(f)
+    }
+}
+
+impl From<openssl::error::ErrorStack> for Error {
+    fn from(f: openssl::error::ErrorStack) -> Self {
+        Error::TotpError(f)
+    }
+}
+
+impl Message for EnrollTwoFactor {
+    type Result = Result<(), Error>;
+}
+
+impl Handler<EnrollTwoFactor> for DbExecutor {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, msg: EnrollTwoFactor, _: &mut Self::Context) -> Self::Result {
+        use super::schema::two_factor::dsl::two_factor;
+
+        self.0.transaction(|| {
+            if is_enabled(&self.0, msg.user_id)? {
+                return Err(Error::AlreadyEnabled);
+            }
+
+            diesel::replace_into(two_factor)
+                .values(&NewTwoFactor {
+                    user_id: msg.user_id,
+                    secret: &msg.secret,
+                })
+                .execute(&self.0)?;
+
+            Ok(())
+        })
+    }
+}
+
+impl Message for ConfirmTwoFactor {
+    type Result = Result<(), Error>;
+}
+
+impl Handler<ConfirmTwoFactor> for DbExecutor {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, msg: ConfirmTwoFactor, _: &mut Self::Context) -> Self::Result {
+        use super::schema::recovery_codes::dsl::{self as r, recovery_codes};
+        use super::schema::two_factor::dsl::{enabled, last_step, two_factor};
+
+        let conn = &self.0;
+
+        conn.transaction(|| {
+            let pending = match two_factor.find(msg.user_id).first::<TwoFactor>(conn) {
+                Ok(t) => t,
+                Err(diesel::result::Error::NotFound) => return Err(Error::NotEnabled),
+                Err(e) => return Err(Error::DbError(e)),
+            };
+
+            if pending.enabled {
+                return Err(Error::AlreadyEnabled);
+            }
+
+            let secret = totp::decode_secret(&pending.secret).ok_or(Error::InvalidCode)?;
+            let step = totp::verify(&secret, &msg.code, None)?.ok_or(Error::InvalidCode)?;
+
+            diesel::update(two_factor.find(msg.user_id))
+                .set((enabled.eq(true), last_step.eq(step)))
+                .execute(conn)?;
+
+            diesel::delete(recovery_codes.filter(r::user_id.eq(msg.user_id))).execute(conn)?;
+            let codes = msg
+                .recovery_codes
+                .iter()
+                .map(|c| recovery_code_hash(c))
+                .collect::<Vec<_>>();
+            let new_codes = codes
+                .iter()
+                .map(|code| NewRecoveryCode {
+                    user_id: msg.user_id,
+                    code,
+                })
+                .collect::<Vec<_>>();
+            diesel::insert_into(recovery_codes)
+                .values(&new_codes)
+                .execute(conn)?;
+
+            Ok(())
+        })
+    }
+}
+
+impl Message for DisableTwoFactor {
+    type Result = Result<(), Error>;
+}
+
+impl Handler<DisableTwoFactor> for DbExecutor {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, msg: DisableTwoFactor, _: &mut Self::Context) -> Self::Result {
+        use super::schema::two_factor::dsl::{enabled, two_factor};
+
+        let conn = &self.0;
+
+        conn.transaction(|| {
+            let current = match two_factor
+                .find(msg.user_id)
+                .filter(enabled.eq(true))
+                .first::<TwoFactor>(conn)
+            {
+                Ok(t) => t,
+                Err(diesel::result::Error::NotFound) => return Err(Error::NotEnabled),
+                Err(e) => return Err(Error::DbError(e)),
+            };
+
+            if let Some(code) = &msg.code {
+                if !check_code(conn, &current, code)? {
+                    return Err(Error::InvalidCode);
+                }
+            }
+
+            delete_user_two_factor(conn, msg.user_id)?;
+
+            Ok(())
+        })
+    }
+}
+
+impl Message for CreateLoginChallenge {
+    type Result = Result<(), Error>;
+}
+
+impl Handler<CreateLoginChallenge> for DbExecutor {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, msg: CreateLoginChallenge, _: &mut Self::Context) -> Self::Result {
+        use super::schema::login_challenges::dsl::login_challenges;
+
+        let now = chrono::offset::Utc::now().naive_utc();
+
+        diesel::insert_into(login_challenges)
+            .values(&NewLoginChallenge {
+                token: &token_hash(&msg.token),
+                user_id: msg.user_id,
+                created_at: now,
+                expires_at: now + msg.lifetime,
+            })
+            .execute(&self.0)?;
+
+        Ok(())
+    }
+}
+
+impl Message for VerifyLoginChallenge {
+    type Result = Result<AuthInfo, Error>;
+}
+
+impl Handler<VerifyLoginChallenge> for DbExecutor {
+    type Result = Result<AuthInfo, Error>;
+
+    fn handle(&mut self, msg: VerifyLoginChallenge, _: &mut Self::Context) -> Self::Result {
+        use super::schema::login_challenges::dsl::{self as c, login_challenges};
+        use super::schema::two_factor::dsl::{enabled, two_factor};
+        use super::schema::users::dsl::{active, users};
+
+        let conn = &self.0;
+        let now = chrono::offset::Utc::now().naive_utc();
+
+        // Invalid codes are returned as `Ok(Err(_))`, so the attempt counter isn't rolled back.
+        conn.transaction::<_, Error, _>(|| {
+            let challenge = match login_challenges
+                .filter(c::token.eq(token_hash(&msg.token)))
+                .filter(c::expires_at.gt(now))
+                .select((c::id, c::user_id, c::attempts))
+                .first::<LoginChallenge>(conn)
+            {
+                Ok(c) => c,
+                Err(diesel::result::Error::NotFound) => return Ok(Err(Error::InvalidChallenge)),
+                Err(e) => return Err(Error::DbError(e)),
+            };
+
+            let (user, current) = match users
+                .inner_join(two_factor)
+                .filter(super::schema::users::dsl::id.eq(challenge.user_id))
+                .filter(active.eq(true))
+                .filter(enabled.eq(true))
+                .first::<(User, TwoFactor)>(conn)
+            {
+                Ok(r) => r,
+                Err(diesel::result::Error::NotFound) => {
+                    diesel::delete(login_challenges.find(challenge.id)).execute(conn)?;
+
+                    return Ok(Err(Error::InvalidChallenge));
+                }
+                Err(e) => return Err(Error::DbError(e)),
+            };
+
+            if check_code(conn, &current, &msg.code)? {
+                diesel::delete(login_challenges.find(challenge.id)).execute(conn)?;
+
+                return Ok(Ok(auth_info(conn, user)?));
+            }
+
+            if challenge.attempts + 1 >= msg.max_attempts as i32 {
+                diesel::delete(login_challenges.find(challenge.id)).execute(conn)?;
+            } else {
+                diesel::update(login_challenges.find(challenge.id))
+                    .set(c::attempts.eq(challenge.attempts + 1))
+                    .execute(conn)?;
+            }
+
+            Ok(Err(Error::InvalidCode))
+        })
+        .and_then(|r| r)
+    }
+}
+
+/// Checks TOTP code or recovery code. Accepted code can't be used again.
+fn check_code(conn: &SqliteConnection, current: &TwoFactor, code: &str) -> Result<bool, Error> {
+    use super::schema::recovery_codes::dsl::{self as r, recovery_codes};
+    use super::schema::two_factor::dsl::{last_step, two_factor};
+
+    if let Some(secret) = totp::decode_secret(&current.secret) {
+        if let Some(step) = totp::verify(&secret, code, current.last_step)? {
+            diesel::update(two_factor.find(current.user_id))
+                .set(last_step.eq(step))
+                .execute(conn)?;
+
+            return Ok(true);
+        }
+    }
+
+    let used = diesel::update(
+        recovery_codes
+            .filter(r::user_id.eq(current.user_id))
+            .filter(r::code.eq(recovery_code_hash(code)))
+            .filter(r::used.eq(false)),
+    )
+    .set(r::used.eq(true))
+    .execute(conn)?;
+
+    Ok(used > 0)
+}
+
+/// Recovery codes are case insensitive and may be entered with or without separators.
+fn recovery_code_hash(code: &str) -> String {
+    let normalized = code
+        .chars()
+        .filter(char::is_ascii_alphanumeric)
+        .collect::<String>()
+        .to_lowercase();
+
+    token_hash(normalized.as_bytes())
+}
+
+/// Returns whether the user has confirmed two-factor authentication.
+pub(super) fn is_enabled(conn: &SqliteConnection, user_id: i32) -> QueryResult<bool> {
+    use super::schema::two_factor::dsl::{enabled, two_factor};
+
+    two_factor
+        .find(user_id)
+        .select(enabled)
+        .first::<bool>(conn)
+        .optional()
+        .map(|e| e.unwrap_or(false))
+}
+
+/// Removes TOTP secret, recovery codes and pending login challenges of the user.
+pub(super) fn delete_user_two_factor(conn: &SqliteConnection, user_id: i32) -> QueryResult<()> {
+    use super::schema::login_challenges::dsl::{self as c, login_challenges};
+    use super::schema::recovery_codes::dsl::{self as r, recovery_codes};
+    use super::schema::two_factor::dsl::two_factor;
+
+    diesel::delete(login_challenges.filter(c::user_id.eq(user_id))).execute(conn)?;
+    diesel::delete(recovery_codes.filter(r::user_id.eq(user_id))).execute(conn)?;
+    diesel::delete(two_factor.find(user_id)).execute(conn)?;
+
+    Ok(())
+}
+
+/// Removes expired login challenges. Returns number of removed rows.
+pub(super) fn purge_login_challenges(
+    conn: &SqliteConnection,
+    now: NaiveDateTime,
+) -> QueryResult<usize> {
+    use super::schema::login_challenges::dsl::{expires_at, login_challenges};
+
+    diesel::delete(login_challenges.filter(expires_at.le(now))).execute(conn)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::models::NewUser;
+    use crate::db::test_connection;
+
+    const SECRET: &[u8] = b"12345678901234567890";
+
+    fn enabled_two_factor(conn: &SqliteConnection) -> TwoFactor {
+        use crate::db::schema::recovery_codes::dsl::recovery_codes;
+        use crate::db::schema::two_factor::dsl::{enabled, two_factor};
+        use crate::db::schema::users::dsl::users;
+
+        diesel::insert_into(users)
+            .values(&NewUser {
+                login: "alice",
+                hash: "",
+                role: User::ROLE_CUSTOMER,
+                email: None,
+                oidc_issuer: None,
+                oidc_subject: None,
+            })
+            .execute(conn)
+            .unwrap();
+        diesel::insert_into(two_factor)
+            .values(&NewTwoFactor {
+                user_id: 1,
+                secret: &totp::encode_secret(SECRET),
+            })
+            .execute(conn)
+            .unwrap();
+        diesel::update(two_factor.find(1))
+            .set(enabled.eq(true))
+            .execute(conn)
+            .unwrap();
+        diesel::insert_into(recovery_codes)
+            .values(&NewRecoveryCode {
+                user_id: 1,
+                code: &recovery_code_hash("abcd-efgh"),
+            })
+            .execute(conn)
+            .unwrap();
+
+        reload(conn)
+    }
+
+    fn reload(conn: &SqliteConnection) -> TwoFactor {
+        use crate::db::schema::two_factor::dsl::two_factor;
+
+        two_factor.find(1).first::<TwoFactor>(conn).unwrap()
+    }
+
+    #[test]
+    fn totp_code_cant_be_replayed() {
+        let conn = test_connection();
+        let current = enabled_two_factor(&conn);
+        let code = totp::current_code(SECRET);
+
+        assert!(check_code(&conn, &current, &code).unwrap());
+        let current = reload(&conn);
+        assert!(current.last_step.is_some());
+        assert!(!check_code(&conn, &current, &code).unwrap());
+    }
+
+    #[test]
+    fn recovery_code_can_be_used_once() {
+        let conn = test_connection();
+        let current = enabled_two_factor(&conn);
+
+        assert!(!check_code(&conn, &current, "abcd-efgi").unwrap());
+        assert!(check_code(&conn, &current, "ABCD EFGH").unwrap());
+        assert!(!check_code(&conn, &current, "abcdefgh").unwrap());
+    }
+}
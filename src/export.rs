@@ -0,0 +1,265 @@
+use actix_web::error::ErrorInternalServerError;
+use actix_web::web::{Data, HttpResponse, Query};
+use actix_web::Error;
+use futures::{
+    future::{self, Either},
+    Future,
+};
+use log::error;
+use serde::{Deserialize, Serialize};
+use std::io::{Cursor, Write};
+
+use crate::auth::Auth;
+use crate::db::auth::GetUser;
+use crate::db::logs::GetLogs;
+use crate::db::models::User;
+use crate::db::songs::GetHistory;
+use crate::logs::LogEntry;
+use crate::songs::HistoryEntry;
+use crate::Actors;
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    Json,
+    Zip,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ExportQuery {
+    /// `json` jeśli nie podano.
+    #[serde(default)]
+    pub format: Option<ExportFormat>,
+}
+
+/// Wszystkie dane przechowywane o użytkowniku.
+#[derive(Debug, Serialize)]
+pub struct AccountExport {
+    /// Konto użytkownika, bez skrótu hasła.
+    pub account: User,
+    pub history: Vec<HistoryEntry>,
+    /// Próby logowania na konto, razem z nieudanymi.
+    pub logs: Vec<LogEntry>,
+}
+
+/// `GET /account/export?format=json|zip`
+///
+/// Zwraca wszystkie dane przechowywane o aktualnym użytkowniku: konto, historię wyszukiwania i
+/// logi logowania, jako plik do pobrania. Domyślnie jest to dokument JSON (`AccountExport`), a dla
+/// `format=zip` archiwum z plikami `account.csv`, `history.csv` i `logs.csv`. Wymaga tokenu sesji.
+pub fn export(
+    query: Query<ExportQuery>,
+    auth: Auth,
+    actors: Data<Actors>,
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    if let Err(e) = auth.require_session() {
+        return Either::A(future::err(e));
+    }
+
+    let format = query.format.unwrap_or(ExportFormat::Json);
+    let account = actors
+        .db
+        .send(GetUser { id: auth.id })
+        .map_err(ErrorInternalServerError)
+        .and_then(|r| r.map_err(Error::from));
+    let history = actors
+        .db
+        .send(GetHistory {
+            user_id: Some(auth.id),
+            page: None,
+        })
+        .map_err(ErrorInternalServerError)
+        .and_then(|r| r.map_err(Error::from).map(|page| page.items));
+    let logs = actors
+        .db
+        .send(GetLogs {
+            login: Some(auth.username.clone()),
+        })
+        .map_err(ErrorInternalServerError)
+        .and_then(|r| r.map_err(Error::from));
+
+    Either::B(
+        account
+            .join3(history, logs)
+            .and_then(move |(account, history, logs)| {
+                let export = AccountExport {
+                    account,
+                    history,
+                    logs,
+                };
+
+                match format {
+                    ExportFormat::Json => Ok(attachment("szaklon-export.json")
+                        .content_type("application/json")
+                        .json(export)),
+                    ExportFormat::Zip => match zip_export(&export) {
+                        Ok(zip) => Ok(attachment("szaklon-export.zip")
+                            .content_type("application/zip")
+                            .body(zip)),
+                        Err(e) => {
+                            error!("Failed to create data export: {}", e);
+
+                            Err(ErrorInternalServerError(""))
+                        }
+                    },
+                }
+            }),
+    )
+}
+
+fn attachment(filename: &str) -> actix_web::dev::HttpResponseBuilder {
+    let mut response = HttpResponse::Ok();
+    response.header(
+        "Content-Disposition",
+        format!("attachment; filename=\"{}\"", filename),
+    );
+
+    response
+}
+
+/// ZIP archive with a CSV file for every part of the export.
+fn zip_export(export: &AccountExport) -> Result<Vec<u8>, failure::Error> {
+    let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+    let options = zip::write::FileOptions::default();
+
+    zip.start_file("account.csv", options)?;
+    zip.write_all(&to_csv(std::iter::once(&export.account))?)?;
+    zip.start_file("history.csv", options)?;
+    zip.write_all(&to_csv(&export.history)?)?;
+    zip.start_file("logs.csv", options)?;
+    zip.write_all(&to_csv(&export.logs)?)?;
+
+    Ok(zip.finish()?.into_inner())
+}
+
+fn to_csv<I, T>(rows: I) -> Result<Vec<u8>, failure::Error>
+where
+    I: IntoIterator<Item = T>,
+    T: Serialize,
+{
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    for row in rows {
+        writer.serialize(row)?;
+    }
+
+    writer
+        .into_inner()
+        .map_err(|e| failure::err_msg(e.error().to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_user;
+    use crate::testing::{self, authorization, call};
+    use actix_web::http::header;
+    use actix_web::test::{init_service, read_body, TestRequest};
+    use actix_web::{web, App};
+    use diesel::connection::SimpleConnection;
+    use std::io::Read;
+
+    const ALICE: [u8; 32] = [1; 32];
+
+    /// Alice with a password hash, one search and two logins, Bob with his own search and login.
+    fn seed(conn: &diesel::SqliteConnection) {
+        let alice = test_user(conn, "alice", User::ROLE_CUSTOMER);
+        test_user(conn, "bob", User::ROLE_CUSTOMER);
+        testing::session(conn, alice, ALICE);
+        conn.batch_execute(
+            "UPDATE users SET hash = 'secret-hash', email = 'alice@example.com' WHERE id = 1;
+             INSERT INTO songs (id, artist, title, genre, url)
+                 VALUES (1, 'Artist', 'Title', 'Rock', 'https://example.com');
+             INSERT INTO history (user_id, song_id, matched_at)
+                 VALUES (1, 1, '2019-06-01 12:00:00'), (2, 1, '2019-06-01 12:00:00');
+             INSERT INTO logs (login, logging_time, logging_succession, ip_addr, user_agent)
+                 VALUES ('alice', '2019-06-01 11:00:00', 0, '192.0.2.1', ''),
+                        ('alice', '2019-06-01 11:01:00', 1, '192.0.2.1', ''),
+                        ('bob', '2019-06-01 11:02:00', 1, '192.0.2.2', '');",
+        )
+        .unwrap();
+    }
+
+    fn export_body(format: &str) -> (String, Vec<u8>) {
+        let (mut system, actors) = testing::actors(seed);
+        let mut app = init_service(
+            App::new()
+                .data(testing::config(""))
+                .data(actors)
+                .service(web::resource("/account/export").route(web::get().to_async(export))),
+        );
+        let request = TestRequest::get()
+            .uri(&format!("/account/export?format={}", format))
+            .header("Authorization", authorization(ALICE))
+            .to_request();
+
+        let response = call(&mut system, &mut app, request);
+        assert!(response.status().is_success());
+        let content_type = response
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_string();
+
+        (content_type, read_body(response).to_vec())
+    }
+
+    #[test]
+    fn json_export_contains_own_data_without_hash() {
+        let (content_type, body) = export_body("json");
+        assert_eq!(content_type, "application/json");
+        assert!(!String::from_utf8_lossy(&body).contains("secret-hash"));
+
+        let export: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(export["account"]["login"], "alice");
+        assert_eq!(export["account"]["email"], "alice@example.com");
+        assert!(export["account"].get("hash").is_none());
+        assert_eq!(export["history"].as_array().unwrap().len(), 1);
+        assert_eq!(export["logs"].as_array().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn zip_export_contains_csv_files() {
+        let (content_type, body) = export_body("zip");
+        assert_eq!(content_type, "application/zip");
+
+        let mut zip = zip::ZipArchive::new(Cursor::new(body)).unwrap();
+        let mut file = |name| {
+            let mut content = String::new();
+            zip.by_name(name)
+                .unwrap()
+                .read_to_string(&mut content)
+                .unwrap();
+            content
+        };
+
+        let account = file("account.csv");
+        assert!(account.contains("alice"));
+        assert!(!account.contains("hash"));
+        assert_eq!(file("history.csv").lines().count(), 2);
+        assert_eq!(file("logs.csv").lines().count(), 3);
+    }
+
+    #[test]
+    fn user_csv_skips_hash() {
+        let user = User {
+            id: 1,
+            login: "alice".to_string(),
+            hash: "secret-hash".to_string(),
+            role: User::ROLE_CUSTOMER.to_string(),
+            active: true,
+            email: None,
+            oidc_issuer: None,
+            oidc_subject: None,
+        };
+
+        let csv = String::from_utf8(to_csv(std::iter::once(&user)).unwrap()).unwrap();
+        let mut lines = csv.lines();
+        assert_eq!(
+            lines.next(),
+            Some("id,login,role,active,email,oidc_issuer,oidc_subject")
+        );
+        assert_eq!(lines.next(), Some("1,alice,CUSTOMER,true,,,"));
+    }
+}
@@ -0,0 +1,198 @@
+use actix::prelude::*;
+use actix_web::{dev::Body, http::StatusCode, web::HttpResponse, ResponseError};
+use failure_derive::Fail;
+use futures::{
+    future::{self, Either},
+    Future,
+};
+use log::warn;
+use serde::Deserialize;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use crate::db::passwords::{verify_password, PasswordHashConfig};
+use crate::utils::PerfLog;
+
+/// Password hashing runs on its own threads, so it doesn't block database access. Number of
+/// threads is limited by `max_memory` (in KiB) divided by `mem_cost` of new hashes. When
+/// `queue_limit` requests already wait for a free thread, next ones are rejected.
+#[derive(Clone, Copy, Debug, Deserialize)]
+pub struct HasherConfig {
+    #[serde(default = "default_threads")]
+    pub threads: usize,
+    #[serde(default = "default_max_memory")]
+    pub max_memory: u32,
+    #[serde(default = "default_queue_limit")]
+    pub queue_limit: usize,
+}
+
+impl Default for HasherConfig {
+    fn default() -> Self {
+        Self {
+            threads: default_threads(),
+            max_memory: default_max_memory(),
+            queue_limit: default_queue_limit(),
+        }
+    }
+}
+
+fn default_threads() -> usize {
+    2
+}
+
+fn default_max_memory() -> u32 {
+    512 * 1024 // 512 MiB
+}
+
+fn default_queue_limit() -> usize {
+    16
+}
+
+#[derive(Debug, Fail)]
+pub enum Error {
+    #[fail(display = "Too many requests are waiting for password hashing")]
+    Busy,
+    #[fail(display = "Error while hashing")]
+    HashError(#[cause] argon2::Error),
+    #[fail(display = "Hasher is not available")]
+    MailboxError(#[cause] MailboxError),
+}
+
+impl ResponseError for Error {
+    fn error_response(&self) -> HttpResponse<Body> {
+        match self {
+            Error::Busy => HttpResponse::build(StatusCode::SERVICE_UNAVAILABLE)
+                .header("Retry-After", "1")
+                .body(self.to_string()),
+            Error::HashError(_) => HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR),
+            Error::MailboxError(_) => HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR),
+        }
+    }
+}
+
+pub struct HashExecutor;
+
+impl Actor for HashExecutor {
+    type Context = SyncContext<Self>;
+}
+
+struct HashPassword {
+    password: Vec<u8>,
+    config: PasswordHashConfig,
+}
+
+impl Message for HashPassword {
+    type Result = Result<String, argon2::Error>;
+}
+
+impl Handler<HashPassword> for HashExecutor {
+    type Result = Result<String, argon2::Error>;
+
+    fn handle(&mut self, msg: HashPassword, _: &mut Self::Context) -> Self::Result {
+        let p = PerfLog::new();
+        let hash = msg.config.hash(&msg.password);
+        p.log("Hash time");
+
+        hash
+    }
+}
+
+struct VerifyPassword {
+    hash: String,
+    password: Vec<u8>,
+}
+
+impl Message for VerifyPassword {
+    type Result = Result<bool, argon2::Error>;
+}
+
+impl Handler<VerifyPassword> for HashExecutor {
+    type Result = Result<bool, argon2::Error>;
+
+    fn handle(&mut self, msg: VerifyPassword, _: &mut Self::Context) -> Self::Result {
+        let p = PerfLog::new();
+        let valid = verify_password(&msg.hash, &msg.password);
+        p.log("Verify time");
+
+        valid
+    }
+}
+
+/// Handle to the hashing threads, counting requests which are running or waiting.
+#[derive(Clone)]
+pub struct Hasher {
+    addr: Addr<HashExecutor>,
+    pending: Arc<AtomicUsize>,
+    limit: usize,
+}
+
+/// Frees the place in the queue when the request completes or is dropped.
+struct Slot(Arc<AtomicUsize>);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Hasher {
+    pub fn start(config: &HasherConfig, hash_config: &PasswordHashConfig) -> Self {
+        let by_memory = (config.max_memory / hash_config.mem_cost.max(1)).max(1) as usize;
+        let threads = config.threads.min(by_memory).max(1);
+        if threads < config.threads {
+            warn!(
+                "Using {} password hashing threads instead of {} because of max_memory",
+                threads, config.threads
+            );
+        }
+
+        Self {
+            addr: SyncArbiter::start(threads, || HashExecutor),
+            pending: Arc::new(AtomicUsize::new(0)),
+            limit: threads + config.queue_limit,
+        }
+    }
+
+    pub fn hash(
+        &self,
+        password: Vec<u8>,
+        config: PasswordHashConfig,
+    ) -> impl Future<Item = String, Error = Error> {
+        self.send(HashPassword { password, config })
+    }
+
+    /// Checks the password, users without password (empty hash) never match.
+    pub fn verify(
+        &self,
+        hash: String,
+        password: Vec<u8>,
+    ) -> impl Future<Item = bool, Error = Error> {
+        if hash.is_empty() {
+            return Either::A(future::ok(false));
+        }
+
+        Either::B(self.send(VerifyPassword { hash, password }))
+    }
+
+    fn send<M, T>(&self, msg: M) -> impl Future<Item = T, Error = Error>
+    where
+        M: Message<Result = Result<T, argon2::Error>> + Send + 'static,
+        T: Send + 'static,
+        HashExecutor: Handler<M>,
+    {
+        if self.pending.fetch_add(1, Ordering::SeqCst) >= self.limit {
+            self.pending.fetch_sub(1, Ordering::SeqCst);
+            return Either::A(future::err(Error::Busy));
+        }
+        let slot = Slot(self.pending.clone());
+
+        Either::B(self.addr.send(msg).then(move |res| {
+            drop(slot);
+
+            match res {
+                Ok(res) => res.map_err(Error::HashError),
+                Err(e) => Err(Error::MailboxError(e)),
+            }
+        }))
+    }
+}
@@ -1,4 +1,4 @@
-use actix_web::error::{ErrorForbidden, ErrorInternalServerError};
+use actix_web::error::ErrorInternalServerError;
 use actix_web::web::{Data, Json};
 use actix_web::Error;
 use futures::{
@@ -6,9 +6,11 @@ use futures::{
     Future,
 };
 use serde::Serialize;
+use std::collections::HashSet;
 
 use crate::auth::Auth;
 use crate::db::logs::GetLogs;
+use crate::permissions::{Authorized, LogsRead};
 use crate::Actors;
 use chrono::NaiveDateTime;
 
@@ -24,24 +26,182 @@ pub struct LogEntry {
     pub browser: String,
 }
 
+/// Wpis logu z oznaczeniami ułatwiającymi wykrycie podejrzanych logowań.
+#[derive(Debug, Serialize)]
+pub struct AccountLogEntry {
+    #[serde(flatten)]
+    pub entry: LogEntry,
+    /// Nieudana próba logowania.
+    pub failed: bool,
+    /// Pierwsze udane logowanie z tego adresu IP, systemu i przeglądarki.
+    pub new_device: bool,
+    /// Wpis jest nowszy niż poprzednie udane logowanie, czyli pojawił się od poprzedniej sesji.
+    pub recent: bool,
+}
+
 /// `GET /logs`
 ///
-/// Zwraca logi użytkownika.
+/// Zwraca logi użytkownika. Wymaga uprawnienia `logs:read`.
 pub fn logs(
-    auth: Auth,
+    _auth: Authorized<LogsRead>,
     actors: Data<Actors>,
 ) -> impl Future<Item = Json<Vec<LogEntry>>, Error = Error> {
-    if !auth.is_admin {
-        Either::A(future::err(ErrorForbidden("not admin")))
-    } else {
-        let msg = GetLogs;
-
-        Either::B(
-            actors
-                .db
-                .send(msg)
-                .map_err(ErrorInternalServerError)
-                .and_then(|r| r.map_err(Error::from).map(Json)),
-        )
+    let msg = GetLogs { login: None };
+
+    actors
+        .db
+        .send(msg)
+        .map_err(ErrorInternalServerError)
+        .and_then(|r| r.map_err(Error::from).map(Json))
+}
+
+/// `GET /account/logs`
+///
+/// Zwraca logi logowania na konto aktualnego użytkownika, razem z nieudanymi próbami, w kolejności
+/// chronologicznej. Każdy wpis ma dodatkowe pola `failed`, `new_device` i `recent`, patrz
+/// `AccountLogEntry`. Wymaga tokenu sesji.
+pub fn account_logs(
+    auth: Auth,
+    actors: Data<Actors>,
+) -> impl Future<Item = Json<Vec<AccountLogEntry>>, Error = Error> {
+    if let Err(e) = auth.require_session() {
+        return Either::A(future::err(e));
+    }
+
+    let msg = GetLogs {
+        login: Some(auth.username.clone()),
+    };
+
+    Either::B(
+        actors
+            .db
+            .send(msg)
+            .map_err(ErrorInternalServerError)
+            .and_then(|r| r.map_err(Error::from))
+            .map(|entries| Json(flag_entries(entries))),
+    )
+}
+
+/// Sorts entries chronologically and marks failed attempts, logins from new devices and entries
+/// newer than the previous successful login.
+fn flag_entries(mut entries: Vec<LogEntry>) -> Vec<AccountLogEntry> {
+    entries.sort_by_key(|e| e.logging_time);
+
+    // The last successful login usually started the current session
+    let previous_login = entries
+        .iter()
+        .filter(|e| e.logging_succession)
+        .map(|e| e.logging_time)
+        .rev()
+        .nth(1);
+
+    let mut devices = HashSet::new();
+    entries
+        .into_iter()
+        .map(|entry| {
+            let new_device = entry.logging_succession
+                && devices.insert((
+                    entry.ip_addr.clone(),
+                    entry.os.clone(),
+                    entry.browser.clone(),
+                ));
+            let recent = match previous_login {
+                Some(time) => entry.logging_time > time,
+                None => true,
+            };
+
+            AccountLogEntry {
+                failed: !entry.logging_succession,
+                new_device,
+                recent,
+                entry,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(minute: u32, success: bool, ip_addr: &str) -> LogEntry {
+        LogEntry {
+            id: minute as i32,
+            login: "alice".to_string(),
+            logging_time: NaiveDateTime::parse_from_str("2019-06-01 12:00:00", "%Y-%m-%d %H:%M:%S")
+                .unwrap()
+                + chrono::Duration::minutes(minute.into()),
+            logging_succession: success,
+            ip_addr: ip_addr.to_string(),
+            os: "Linux".to_string(),
+            browser: "Firefox".to_string(),
+        }
+    }
+
+    /// `(failed, new_device, recent)` of every entry.
+    fn flags(entries: Vec<LogEntry>) -> Vec<(bool, bool, bool)> {
+        flag_entries(entries)
+            .iter()
+            .map(|e| (e.failed, e.new_device, e.recent))
+            .collect()
+    }
+
+    #[test]
+    fn single_login_is_new_and_recent() {
+        assert_eq!(
+            flags(vec![entry(0, true, "192.0.2.1")]),
+            vec![(false, true, true)]
+        );
+    }
+
+    #[test]
+    fn everything_before_first_login_is_recent() {
+        assert_eq!(
+            flags(vec![
+                entry(0, false, "192.0.2.1"),
+                entry(1, true, "192.0.2.1")
+            ]),
+            vec![(true, false, true), (false, true, true)]
+        );
+    }
+
+    #[test]
+    fn repeated_device_is_not_new() {
+        let mut other_browser = entry(2, true, "192.0.2.1");
+        other_browser.browser = "Chrome".to_string();
+
+        assert_eq!(
+            flags(vec![
+                entry(0, true, "192.0.2.1"),
+                entry(1, true, "192.0.2.1"),
+                other_browser,
+                entry(3, true, "192.0.2.2"),
+            ]),
+            vec![
+                (false, true, false),
+                (false, false, false),
+                (false, true, false),
+                (false, true, true),
+            ]
+        );
+    }
+
+    #[test]
+    fn failed_attempts_since_previous_session_are_recent() {
+        // Entries come unsorted from the database
+        assert_eq!(
+            flags(vec![
+                entry(3, true, "192.0.2.1"),
+                entry(0, false, "192.0.2.2"),
+                entry(1, true, "192.0.2.1"),
+                entry(2, false, "192.0.2.2"),
+            ]),
+            vec![
+                (true, false, false),
+                (false, true, false),
+                (true, false, true),
+                (false, false, true),
+            ]
+        );
     }
 }
@@ -14,28 +14,53 @@
 #[macro_use]
 extern crate diesel;
 
-use actix::{Addr, SyncArbiter};
+use actix::{Actor, Addr, SyncArbiter};
 use actix_web::web::PayloadConfig;
 use actix_web::{middleware, web, App, HttpServer};
+use diesel::connection::SimpleConnection;
 use diesel::prelude::{Connection, SqliteConnection};
 use failure::ResultExt;
-use log::error;
-use parking_lot::Mutex;
+use log::{error, info};
 use serde::Deserialize;
-use std::collections::HashMap;
-use std::sync::Arc;
 
-use crate::db::models::User;
+use bootstrap::BootstrapAdminConfig;
+use db::lockout::LockoutConfig;
+use db::passwords::PasswordHashConfig;
+use db::sessions::SessionTimeouts;
 use db::DbExecutor;
+use hasher::{Hasher, HasherConfig};
+use oidc::{OidcClient, OidcConfig};
 use openssl::ssl::{SslAcceptor, SslFiletype, SslMethod};
+use password_reset::PasswordResetConfig;
+use policy::PolicyConfig;
+use signed_tokens::SignedTokensConfig;
+use std::net::IpAddr;
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use two_factor::TwoFactorConfig;
 
+pub mod api_keys;
 pub mod auth;
+mod bootstrap;
 mod db;
+pub mod export;
+mod hasher;
 mod init;
 pub mod logs;
+pub mod oidc;
+pub mod pagination;
+pub mod password_reset;
+pub mod permissions;
+pub mod policy;
 pub mod routes;
+pub mod sessions;
+mod signed_tokens;
 pub mod songs;
+#[cfg(test)]
+mod testing;
+mod totp;
+pub mod two_factor;
 mod utils;
 
 #[derive(Clone, Debug, Deserialize)]
@@ -50,15 +75,96 @@ pub struct Config {
     pub tls_cert_chain_file: Option<PathBuf>,
     #[serde(default)]
     pub tls_bind_addr: Option<String>,
+    /// Reverse proxies allowed to set the client address in `Forwarded` or `X-Forwarded-For`.
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpAddr>,
     pub db_path: String,
     pub db_threads: usize,
     pub max_song_size: usize,
     #[serde(default)]
     pub max_songs_to_train: Option<usize>,
+    /// Maximum lifetime of a session in seconds.
+    #[serde(default = "default_session_lifetime")]
+    pub session_lifetime: i64,
+    /// Session expires if it isn't used for this many seconds.
+    #[serde(default = "default_session_idle_timeout")]
+    pub session_idle_timeout: i64,
+    /// Session token is valid for this many seconds since it was issued, then it has to be
+    /// exchanged for a new one with the refresh token.
+    #[serde(default = "default_access_token_lifetime")]
+    pub access_token_lifetime: i64,
+    /// Refresh token is valid for this many seconds since it was issued.
+    #[serde(default = "default_refresh_token_lifetime")]
+    pub refresh_token_lifetime: i64,
+    /// How often (in seconds) expired sessions are removed from the database.
+    #[serde(default = "default_session_sweep_interval")]
+    pub session_sweep_interval: u64,
+    #[serde(default)]
+    pub session_mode: SessionMode,
+    #[serde(default)]
+    pub signed_tokens: SignedTokensConfig,
+    pub password_reset: PasswordResetConfig,
+    #[serde(default)]
+    pub lockout: LockoutConfig,
+    #[serde(default)]
+    pub password_hash: PasswordHashConfig,
+    #[serde(default)]
+    pub hasher: HasherConfig,
+    #[serde(default)]
+    pub two_factor: TwoFactorConfig,
+    #[serde(default)]
+    pub policy: PolicyConfig,
+    #[serde(default)]
+    pub bootstrap_admin: BootstrapAdminConfig,
+    /// OpenID Connect login is enabled only if this section is present.
+    #[serde(default)]
+    pub oidc: Option<OidcConfig>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SessionMode {
+    /// Sessions are stored in the database.
+    #[default]
+    Database,
+    /// Stateless signed tokens, configured in `[signed_tokens]`.
+    Signed,
+}
+
+impl Config {
+    pub fn session_timeouts(&self) -> SessionTimeouts {
+        SessionTimeouts {
+            lifetime: chrono::Duration::seconds(self.session_lifetime),
+            idle_timeout: chrono::Duration::seconds(self.session_idle_timeout),
+            access_lifetime: chrono::Duration::seconds(self.access_token_lifetime),
+            refresh_lifetime: chrono::Duration::seconds(self.refresh_token_lifetime),
+        }
+    }
+}
+
+fn default_session_lifetime() -> i64 {
+    30 * 24 * 60 * 60 // 30 days
+}
+
+fn default_session_idle_timeout() -> i64 {
+    7 * 24 * 60 * 60 // 7 days
+}
+
+fn default_access_token_lifetime() -> i64 {
+    15 * 60 // 15 minutes
+}
+
+fn default_refresh_token_lifetime() -> i64 {
+    60 * 24 * 60 * 60 // 60 days
+}
+
+fn default_session_sweep_interval() -> u64 {
+    60 * 60
 }
 
 pub struct Actors {
     db: Addr<DbExecutor>,
+    hasher: Hasher,
 }
 
 fn main() -> Result<(), failure::Error> {
@@ -66,8 +172,21 @@ fn main() -> Result<(), failure::Error> {
 
     let config: Config =
         toml::from_str(&std::fs::read_to_string("config.toml").context("config.toml is missing")?)?;
+    if config.session_mode == SessionMode::Signed {
+        config.signed_tokens.validate()?;
+    }
 
     let connection = SqliteConnection::establish(&config.db_path).expect("Failed to open connection to db");
+
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    match args.first().map(String::as_str) {
+        Some("create-admin") => {
+            return bootstrap::create_admin_command(&config, &connection, &args[1..])
+        }
+        Some(command) => failure::bail!("Unknown command {}", command),
+        None => (),
+    }
+
     let _ = connection.transaction(|| {
         init::init(&config, &connection).map_err(|e| {
             error!("Failed to initzialize system: {}", e);
@@ -75,32 +194,97 @@ fn main() -> Result<(), failure::Error> {
             diesel::result::Error::RollbackTransaction
         })
     });
+    let indexed = db::search::sync_search_index(&connection)?;
+    if indexed > 0 {
+        info!("Added {} songs to the search index", indexed);
+    }
+    bootstrap::bootstrap_admin(&config, &connection)?;
     std::mem::drop(connection);
 
     let _sys = actix::System::new("szaklon");
 
-    let sessions = Arc::new(Mutex::new(HashMap::<[u8; 32], User>::new()));
-
     let database_url = config.db_path.clone();
     let db_addr = SyncArbiter::start(config.db_threads, move || {
-        DbExecutor(
-            SqliteConnection::establish(&database_url).expect("Failed to open connection to db"),
-        )
+        let connection =
+            SqliteConnection::establish(&database_url).expect("Failed to open connection to db");
+        // Login attempts are logged in immediate transactions, parallel ones have to wait
+        connection
+            .batch_execute("PRAGMA busy_timeout = 5000;")
+            .expect("Failed to set busy timeout");
+
+        DbExecutor(connection)
     });
 
+    sessions::SessionSweeper {
+        db: db_addr.clone(),
+        timeouts: config.session_timeouts(),
+        interval: Duration::from_secs(config.session_sweep_interval),
+    }
+    .start();
+
+    let hasher = Hasher::start(&config.hasher, &config.password_hash);
+
+    let reset_delivery = password_reset::delivery_from_config(&config.password_reset)?;
+    let oidc_client = config.oidc.clone().map(|c| Arc::new(OidcClient::new(c)));
+
     let c = config.clone();
     let mut srv_builder = HttpServer::new(move || {
         App::new()
             .wrap(middleware::Logger::default())
             .wrap(middleware::cors::Cors::new())
-            .data(sessions.clone())
             .data(c.clone())
+            .data(reset_delivery.clone())
+            .data(oidc_client.clone())
             .data(Actors {
                 db: db_addr.clone(),
+                hasher: hasher.clone(),
             })
             .service(web::resource("/login").route(web::post().to_async(auth::login)))
+            .service(
+                web::resource("/login/2fa")
+                    .route(web::post().to_async(two_factor::login_two_factor)),
+            )
+            .service(web::resource("/oidc/login").route(web::get().to_async(oidc::login)))
+            .service(web::resource("/oidc/callback").route(web::get().to_async(oidc::callback)))
+            .service(
+                web::resource("/token/refresh").route(web::post().to_async(auth::refresh_token)),
+            )
             .service(web::resource("/signup").route(web::post().to_async(auth::signup)))
             .service(web::resource("/account").route(web::delete().to_async(auth::delete_account)))
+            .service(web::resource("/account/logs").route(web::get().to_async(logs::account_logs)))
+            .service(web::resource("/account/export").route(web::get().to_async(export::export)))
+            .service(
+                web::resource("/account/password")
+                    .route(web::post().to_async(auth::change_password)),
+            )
+            .service(
+                web::resource("/account/2fa").route(web::delete().to_async(two_factor::disable)),
+            )
+            .service(
+                web::resource("/account/2fa/enroll")
+                    .route(web::post().to_async(two_factor::enroll)),
+            )
+            .service(
+                web::resource("/account/2fa/confirm")
+                    .route(web::post().to_async(two_factor::confirm)),
+            )
+            .service(
+                web::resource("/password_reset/request")
+                    .route(web::post().to(password_reset::request_reset)),
+            )
+            .service(
+                web::resource("/password_reset/confirm")
+                    .route(web::post().to_async(password_reset::confirm_reset)),
+            )
+            .service(
+                web::resource("/sessions")
+                    .route(web::get().to_async(sessions::sessions))
+                    .route(web::delete().to_async(sessions::delete_sessions)),
+            )
+            .service(
+                web::resource("/sessions/{id}")
+                    .route(web::delete().to_async(sessions::delete_session)),
+            )
             .service(
                 web::resource("/account/{id}")
                     .route(web::delete().to_async(auth::delete_account_admin)),
@@ -114,14 +298,70 @@ fn main() -> Result<(), failure::Error> {
             .service(web::resource("/history/all").route(web::get().to_async(songs::history_all)))
             .service(web::resource("/popular/{n}").route(web::get().to_async(songs::popular)))
             .service(web::resource("/songs").route(web::post().to_async(songs::songs)))
+            .service(web::resource("/songs/search").route(web::get().to_async(songs::search)))
+            .service(
+                web::resource("/songs/{id}")
+                    .route(web::get().to_async(songs::song))
+                    .route(web::patch().to_async(songs::patch_song))
+                    .route(web::delete().to_async(songs::delete_song)),
+            )
+            .service(
+                web::resource("/songs/{id}/revisions")
+                    .route(web::get().to_async(songs::song_revisions)),
+            )
+            .service(
+                web::resource("/songs/{id}/revisions/{revision_id}/restore")
+                    .route(web::post().to_async(songs::restore_song_revision)),
+            )
             .service(web::resource("/edit_song").route(web::post().to_async(songs::edit_song)))
             .service(web::resource("/add_song").route(web::post().to_async(songs::add_song)))
             .service(web::resource("/genres").route(web::get().to_async(songs::genres)))
             .service(web::resource("/artists").route(web::get().to_async(songs::artists)))
             .service(web::resource("/users").route(web::get().to_async(auth::users)))
+            .service(
+                web::resource("/users/password_hashes")
+                    .route(web::get().to_async(auth::password_hashes)),
+            )
+            .service(
+                web::resource("/users/{id}").route(web::delete().to_async(auth::erase_account)),
+            )
+            .service(
+                web::resource("/users/{id}/sessions")
+                    .route(web::get().to_async(sessions::user_sessions_admin))
+                    .route(web::delete().to_async(sessions::delete_sessions_admin)),
+            )
+            .service(
+                web::resource("/users/{id}/sessions/{session_id}")
+                    .route(web::delete().to_async(sessions::delete_session_admin)),
+            )
+            .service(web::resource("/users/{id}/role").route(web::put().to_async(auth::set_role)))
+            .service(
+                web::resource("/users/{id}/2fa")
+                    .route(web::delete().to_async(two_factor::disable_admin)),
+            )
+            .service(
+                web::resource("/users/{id}/reactivate")
+                    .route(web::post().to_async(auth::reactivate_account)),
+            )
+            .service(
+                web::resource("/api_keys")
+                    .route(web::get().to_async(api_keys::keys))
+                    .route(web::post().to_async(api_keys::create_key)),
+            )
+            .service(
+                web::resource("/api_keys/{id}").route(web::delete().to_async(api_keys::revoke_key)),
+            )
+            .service(web::resource("/roles").route(web::get().to_async(permissions::roles)))
+            .service(
+                web::resource("/roles/{role}")
+                    .route(web::put().to_async(permissions::set_role_permissions)),
+            )
+            .service(
+                web::resource("/lockouts/clear").route(web::post().to_async(auth::clear_lockout)),
+            )
             .service(web::resource("/check_session").route(web::get().to(auth::check_session)))
             .service(web::resource("/logs").route(web::get().to_async(logs::logs)))
-            .route("/logout", web::post().to(auth::logout))
+            .route("/logout", web::post().to_async(auth::logout))
     })
     .bind(&config.bind_addr)?;
 
@@ -0,0 +1,976 @@
+//! OpenID Connect login: authorization code flow with PKCE.
+//!
+//! Provider metadata is read from the discovery document and cached together with the signing
+//! keys (JWKS). Keys are fetched again when an ID token is signed with an unknown key, so the
+//! provider can rotate them. Only RS256 signed ID tokens are accepted.
+
+use actix_web::error::{ErrorInternalServerError, ErrorUnauthorized};
+use actix_web::web::{Data, Json, Query};
+use actix_web::{dev::Body, web::HttpResponse, HttpRequest, ResponseError};
+use failure::Fail;
+use futures::{
+    future::{self, Either},
+    Future,
+};
+use openssl::bn::BigNum;
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::rsa::Rsa;
+use openssl::sign::Verifier;
+use reqwest::r#async::{Client, RequestBuilder};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::sync::{Arc, RwLock};
+
+use crate::auth::{client_info, decode_token, finish_login, generate_token, LoginResult};
+use crate::db::oidc::{CreateOidcLogin, OidcSignIn, TakeOidcLogin};
+use crate::signed_tokens::{decode_json, decode_part};
+use crate::{Actors, Config};
+
+/// Accepted difference between our clock and the clock of the provider, in seconds.
+const CLOCK_SKEW: i64 = 60;
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct OidcConfig {
+    /// Discovery document is read from `{issuer}/.well-known/openid-configuration`.
+    pub issuer: String,
+    pub client_id: String,
+    /// Not needed for public clients.
+    #[serde(default)]
+    pub client_secret: Option<String>,
+    /// Address registered at the provider, the user is sent back there with `code` and `state`.
+    pub redirect_uri: String,
+    #[serde(default = "default_scopes")]
+    pub scopes: String,
+    /// ID token claim which tells whether the user is an admin, e.g. `groups`. If set, the role
+    /// of the user is updated on every login.
+    #[serde(default)]
+    pub admin_claim: Option<String>,
+    /// Value of `admin_claim` given to admins. The claim can also be a list of values or `true`.
+    #[serde(default)]
+    pub admin_value: Option<String>,
+    /// How long (in seconds) the user has to log in at the provider.
+    #[serde(default = "default_login_lifetime")]
+    pub login_lifetime: i64,
+}
+
+fn default_scopes() -> String {
+    "openid profile email".to_string()
+}
+
+fn default_login_lifetime() -> i64 {
+    10 * 60
+}
+
+#[derive(Debug, Fail)]
+pub enum Error {
+    #[fail(display = "OpenID Connect login is not configured")]
+    NotConfigured,
+    #[fail(display = "Identity provider error: {}", _0)]
+    Provider(String),
+    #[fail(display = "Invalid authorization code")]
+    InvalidCode,
+    #[fail(display = "Invalid ID token: {}", _0)]
+    InvalidIdToken(&'static str),
+}
+
+impl ResponseError for Error {
+    fn error_response(&self) -> HttpResponse<Body> {
+        match self {
+            Error::NotConfigured => HttpResponse::NotFound().body(self.to_string()),
+            Error::Provider(_) => HttpResponse::BadGateway().body(self.to_string()),
+            Error::InvalidCode | Error::InvalidIdToken(_) => {
+                HttpResponse::Unauthorized().body(self.to_string())
+            }
+        }
+    }
+}
+
+fn provider_error(e: reqwest::Error) -> Error {
+    Error::Provider(e.to_string())
+}
+
+#[derive(Debug, Deserialize)]
+struct ProviderMetadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Jwks {
+    keys: Vec<Jwk>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct Jwk {
+    kty: String,
+    #[serde(default)]
+    kid: Option<String>,
+    #[serde(default, rename = "use")]
+    usage: Option<String>,
+    #[serde(default)]
+    n: Option<String>,
+    #[serde(default)]
+    e: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Header {
+    alg: String,
+    #[serde(default)]
+    kid: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Audience {
+    One(String),
+    Many(Vec<String>),
+}
+
+#[derive(Debug, Deserialize)]
+struct Claims {
+    iss: String,
+    sub: String,
+    aud: Audience,
+    exp: i64,
+    #[serde(default)]
+    azp: Option<String>,
+    #[serde(default)]
+    nonce: Option<String>,
+    #[serde(default)]
+    preferred_username: Option<String>,
+    #[serde(default)]
+    email: Option<String>,
+    #[serde(default)]
+    email_verified: Option<bool>,
+}
+
+/// Verified identity of the user.
+struct Identity {
+    subject: String,
+    login: String,
+    email: Option<String>,
+    admin: Option<bool>,
+}
+
+/// Client of the configured identity provider, shared by all workers.
+pub struct OidcClient {
+    config: OidcConfig,
+    http: Client,
+    metadata: RwLock<Option<Arc<ProviderMetadata>>>,
+    keys: RwLock<Option<Arc<Vec<Jwk>>>>,
+}
+
+impl OidcClient {
+    pub fn new(config: OidcConfig) -> Self {
+        Self {
+            config,
+            http: Client::new(),
+            metadata: RwLock::new(None),
+            keys: RwLock::new(None),
+        }
+    }
+
+    fn metadata(self: Arc<Self>) -> impl Future<Item = Arc<ProviderMetadata>, Error = Error> {
+        if let Some(metadata) = self.metadata.read().unwrap().clone() {
+            return Either::A(future::ok(metadata));
+        }
+
+        let url = format!(
+            "{}/.well-known/openid-configuration",
+            self.config.issuer.trim_end_matches('/')
+        );
+
+        Either::B(
+            fetch_json::<ProviderMetadata>(self.http.get(&url)).and_then(move |metadata| {
+                if metadata.issuer != self.config.issuer {
+                    return Err(Error::Provider(
+                        "Issuer in the discovery document doesn't match".to_string(),
+                    ));
+                }
+
+                let metadata = Arc::new(metadata);
+                *self.metadata.write().unwrap() = Some(metadata.clone());
+
+                Ok(metadata)
+            }),
+        )
+    }
+
+    /// Signing keys of the provider, fetched again if `refresh` is set.
+    fn keys(self: Arc<Self>, refresh: bool) -> impl Future<Item = Arc<Vec<Jwk>>, Error = Error> {
+        if !refresh {
+            if let Some(keys) = self.keys.read().unwrap().clone() {
+                return Either::A(future::ok(keys));
+            }
+        }
+
+        Either::B(self.clone().metadata().and_then(move |metadata| {
+            fetch_json::<Jwks>(self.http.get(&metadata.jwks_uri)).map(move |jwks| {
+                let keys = Arc::new(jwks.keys);
+                *self.keys.write().unwrap() = Some(keys.clone());
+
+                keys
+            })
+        }))
+    }
+
+    fn authorization_url(
+        self: Arc<Self>,
+        state: String,
+        nonce: String,
+        code_challenge: String,
+    ) -> impl Future<Item = String, Error = Error> {
+        self.clone().metadata().and_then(move |metadata| {
+            let mut url = url::Url::parse(&metadata.authorization_endpoint)
+                .map_err(|e| Error::Provider(e.to_string()))?;
+            url.query_pairs_mut()
+                .append_pair("response_type", "code")
+                .append_pair("client_id", &self.config.client_id)
+                .append_pair("redirect_uri", &self.config.redirect_uri)
+                .append_pair("scope", &self.config.scopes)
+                .append_pair("state", &state)
+                .append_pair("nonce", &nonce)
+                .append_pair("code_challenge", &code_challenge)
+                .append_pair("code_challenge_method", "S256");
+
+            Ok(url.into_string())
+        })
+    }
+
+    /// Exchanges authorization code for an ID token.
+    fn exchange_code(
+        self: Arc<Self>,
+        code: String,
+        verifier: String,
+    ) -> impl Future<Item = String, Error = Error> {
+        self.clone().metadata().and_then(move |metadata| {
+            let config = &self.config;
+            let mut form = vec![
+                ("grant_type", "authorization_code".to_string()),
+                ("code", code),
+                ("redirect_uri", config.redirect_uri.clone()),
+                ("client_id", config.client_id.clone()),
+                ("code_verifier", verifier),
+            ];
+            if let Some(secret) = &config.client_secret {
+                form.push(("client_secret", secret.clone()));
+            }
+
+            self.http
+                .post(&metadata.token_endpoint)
+                .form(&form)
+                .send()
+                .map_err(provider_error)
+                .and_then(|res| {
+                    // The provider rejected the code (or the verifier)
+                    if res.status().is_client_error() {
+                        return Either::A(future::err(Error::InvalidCode));
+                    }
+
+                    Either::B(
+                        future::result(res.error_for_status())
+                            .and_then(|mut res| res.json::<TokenResponse>())
+                            .map_err(provider_error)
+                            .map(|res| res.id_token),
+                    )
+                })
+        })
+    }
+
+    fn verify_id_token(
+        self: Arc<Self>,
+        token: String,
+        nonce: String,
+    ) -> impl Future<Item = Identity, Error = Error> {
+        let parts = token.split('.').collect::<Vec<_>>();
+        if parts.len() != 3 {
+            return Either::A(future::err(Error::InvalidIdToken("malformed token")));
+        }
+        let message = format!("{}.{}", parts[0], parts[1]);
+        let parsed = decode_json::<Header>(parts[0]).and_then(|header| {
+            Ok((
+                header,
+                decode_json::<serde_json::Value>(parts[1])?,
+                decode_part(parts[2])?,
+            ))
+        });
+        let (header, claims, signature) = match parsed {
+            Ok(parsed) => parsed,
+            Err(_) => return Either::A(future::err(Error::InvalidIdToken("malformed token"))),
+        };
+        // Never let the token choose the algorithm
+        if header.alg != "RS256" {
+            return Either::A(future::err(Error::InvalidIdToken("unsupported algorithm")));
+        }
+
+        let client = self.clone();
+
+        Either::B(
+            self.clone()
+                .keys(false)
+                .and_then(move |keys| match find_key(&keys, &header) {
+                    Some(key) => Either::A(future::ok(key)),
+                    // The provider may have rotated its keys
+                    None => Either::B(client.keys(true).and_then(move |keys| {
+                        find_key(&keys, &header).ok_or(Error::InvalidIdToken("unknown signing key"))
+                    })),
+                })
+                .and_then(move |key| {
+                    if !verify_signature(&key, message.as_bytes(), &signature) {
+                        return Err(Error::InvalidIdToken("invalid signature"));
+                    }
+
+                    self.identity(claims, &nonce)
+                }),
+        )
+    }
+
+    fn identity(&self, value: serde_json::Value, nonce: &str) -> Result<Identity, Error> {
+        let config = &self.config;
+        let claims: Claims = serde_json::from_value(value.clone())
+            .map_err(|_| Error::InvalidIdToken("missing claims"))?;
+
+        if claims.iss != config.issuer {
+            return Err(Error::InvalidIdToken("wrong issuer"));
+        }
+        let audience_ok = match &claims.aud {
+            Audience::One(aud) => aud == &config.client_id,
+            Audience::Many(aud) => {
+                aud.contains(&config.client_id)
+                    && (aud.len() == 1 || claims.azp.as_ref() == Some(&config.client_id))
+            }
+        };
+        if !audience_ok {
+            return Err(Error::InvalidIdToken("wrong audience"));
+        }
+        if claims.exp + CLOCK_SKEW <= chrono::offset::Utc::now().timestamp() {
+            return Err(Error::InvalidIdToken("token expired"));
+        }
+        if claims.nonce.as_ref().map(AsRef::as_ref) != Some(nonce) {
+            return Err(Error::InvalidIdToken("wrong nonce"));
+        }
+        if claims.sub.is_empty() {
+            return Err(Error::InvalidIdToken("missing subject"));
+        }
+
+        let admin = config.admin_claim.as_ref().map(|claim| {
+            let admin_value = config.admin_value.as_ref();
+            match value.get(claim) {
+                Some(serde_json::Value::Bool(b)) => *b,
+                Some(serde_json::Value::String(s)) => Some(s) == admin_value,
+                Some(serde_json::Value::Array(values)) => values
+                    .iter()
+                    .any(|v| v.as_str().is_some() && v.as_str() == admin_value.map(AsRef::as_ref)),
+                _ => false,
+            }
+        });
+
+        // Unverified addresses can't be used for password resets
+        let email = match claims.email_verified {
+            Some(false) => None,
+            _ => claims.email,
+        };
+        let login = claims
+            .preferred_username
+            .or_else(|| {
+                email
+                    .as_ref()
+                    .and_then(|e| e.split('@').next())
+                    .map(String::from)
+            })
+            .unwrap_or_default();
+
+        Ok(Identity {
+            subject: claims.sub,
+            login,
+            email,
+            admin,
+        })
+    }
+}
+
+fn fetch_json<T: DeserializeOwned>(
+    request: RequestBuilder,
+) -> impl Future<Item = T, Error = Error> {
+    request
+        .send()
+        .and_then(|res| res.error_for_status())
+        .and_then(|mut res| res.json::<T>())
+        .map_err(provider_error)
+}
+
+fn find_key(keys: &[Jwk], header: &Header) -> Option<Jwk> {
+    let mut candidates = keys
+        .iter()
+        .filter(|k| k.kty == "RSA" && k.usage.as_ref().map(AsRef::as_ref) != Some("enc"));
+
+    match &header.kid {
+        Some(kid) => candidates.find(|k| k.kid.as_ref() == Some(kid)).cloned(),
+        // Without `kid` the provider has to have only one key
+        None => match (candidates.next(), candidates.next()) {
+            (Some(key), None) => Some(key.clone()),
+            _ => None,
+        },
+    }
+}
+
+fn verify_signature(key: &Jwk, message: &[u8], signature: &[u8]) -> bool {
+    let verify = || -> Result<bool, failure::Error> {
+        let n = decode_part(
+            key.n
+                .as_ref()
+                .ok_or_else(|| failure::err_msg("missing n"))?,
+        )?;
+        let e = decode_part(
+            key.e
+                .as_ref()
+                .ok_or_else(|| failure::err_msg("missing e"))?,
+        )?;
+        let rsa = Rsa::from_public_components(BigNum::from_slice(&n)?, BigNum::from_slice(&e)?)?;
+        let key = PKey::from_rsa(rsa)?;
+
+        let mut verifier = Verifier::new(MessageDigest::sha256(), &key)?;
+        verifier.update(message)?;
+
+        Ok(verifier.verify(signature)?)
+    };
+
+    verify().unwrap_or(false)
+}
+
+#[derive(Debug, Serialize)]
+pub struct OidcLoginResponse {
+    /// Adres dostawcy tożsamości, na który należy przekierować użytkownika.
+    pub url: String,
+    /// Należy go zapamiętać i porównać z parametrem `state` po powrocie użytkownika.
+    pub state: String,
+}
+
+/// Parametry, z którymi dostawca tożsamości przekierowuje użytkownika na `redirect_uri`.
+#[derive(Debug, Deserialize)]
+pub struct CallbackQuery {
+    #[serde(default)]
+    pub code: Option<String>,
+    pub state: String,
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+/// `GET /oidc/login`
+///
+/// Rozpoczyna logowanie przez zewnętrznego dostawcę tożsamości (OpenID Connect, sekcja `[oidc]`
+/// pliku konfiguracyjnego). Zwraca adres, na który należy przekierować użytkownika, i `state`,
+/// który klient powinien zapamiętać i porównać z tym, z którym użytkownik wróci. Logowanie należy
+/// dokończyć w ciągu `login_lifetime` sekund.
+///
+/// Zwraca Not Found, jeśli logowanie przez OpenID Connect nie jest skonfigurowane, i Bad Gateway,
+/// jeśli nie udało się pobrać konfiguracji dostawcy.
+pub fn login(
+    actors: Data<Actors>,
+    oidc: Data<Option<Arc<OidcClient>>>,
+) -> impl Future<Item = Json<OidcLoginResponse>, Error = actix_web::Error> {
+    let client = match oidc.as_ref() {
+        Some(client) => client.clone(),
+        None => return Either::A(future::err(Error::NotConfigured.into())),
+    };
+
+    let state = generate_token();
+    let verifier = encode(&generate_token());
+    let nonce = encode(&generate_token());
+    let code_challenge = encode(&openssl::sha::sha256(verifier.as_bytes()));
+
+    let msg = CreateOidcLogin {
+        state,
+        verifier,
+        nonce: nonce.clone(),
+        lifetime: chrono::Duration::seconds(client.config.login_lifetime),
+    };
+    let state = encode(&state);
+    let db = actors.db.clone();
+
+    Either::B(
+        client
+            .authorization_url(state.clone(), nonce, code_challenge)
+            .from_err()
+            .and_then(move |url| {
+                db.send(msg)
+                    .map_err(ErrorInternalServerError)
+                    .and_then(|r| r.map_err(actix_web::Error::from))
+                    .map(move |_| Json(OidcLoginResponse { url, state }))
+            }),
+    )
+}
+
+/// `GET /oidc/callback?code=...&state=...`
+///
+/// Kończy logowanie przez dostawcę tożsamości. Przyjmuje parametry, z którymi dostawca
+/// przekierował użytkownika na `redirect_uri`, więc `redirect_uri` może wskazywać bezpośrednio na
+/// ten adres albo na stronę klienta, która go wywoła. Zwraca to samo co `POST /login`.
+///
+/// Przy pierwszym logowaniu tworzone jest konto bez hasła powiązane z kontem u dostawcy, z nazwą
+/// z `preferred_username` (z dodanym numerem, jeśli jest zajęta). Jeśli nazwa nie spełnia reguł
+/// z sekcji `[policy]` (np. jest zastrzeżona), używana jest nazwa `user`. Jeśli ustawiono
+/// `admin_claim`, rola użytkownika (`ADMIN` lub `CUSTOMER`) jest ustalana przy każdym logowaniu
+/// na podstawie tokenu.
+///
+/// Zwraca BadRequest w przypadku nieznanego lub wygasłego `state`, Unauthorized gdy dostawca
+/// odrzucił logowanie lub token jest nieprawidłowy, Forbidden dla usuniętego konta i Bad Gateway
+/// w przypadku błędu dostawcy.
+pub fn callback(
+    query: Query<CallbackQuery>,
+    actors: Data<Actors>,
+    config: Data<Config>,
+    oidc: Data<Option<Arc<OidcClient>>>,
+    request: HttpRequest,
+) -> impl Future<Item = Json<LoginResult>, Error = actix_web::Error> {
+    let client = match oidc.as_ref() {
+        Some(client) => client.clone(),
+        None => return Either::A(future::err(Error::NotConfigured.into())),
+    };
+
+    let query = query.into_inner();
+    let state = match decode_token(&query.state, base64::URL_SAFE_NO_PAD) {
+        Some(state) => state,
+        None => return Either::A(future::err(crate::db::oidc::Error::InvalidState.into())),
+    };
+    let remote = client_info(&request, &config);
+    let db = actors.db.clone();
+
+    Either::B(
+        actors
+            .db
+            .send(TakeOidcLogin { state })
+            .map_err(ErrorInternalServerError)
+            .and_then(|r| r.map_err(actix_web::Error::from))
+            .and_then(move |login| {
+                let code = match (query.code, query.error) {
+                    (_, Some(error)) => {
+                        return Either::A(future::err(ErrorUnauthorized(format!(
+                            "Login failed at the identity provider: {}",
+                            error
+                        ))))
+                    }
+                    (Some(code), None) => code,
+                    (None, None) => return Either::A(future::err(Error::InvalidCode.into())),
+                };
+
+                let issuer = client.config.issuer.clone();
+                let nonce = login.nonce;
+
+                Either::B(
+                    client
+                        .clone()
+                        .exchange_code(code, login.verifier)
+                        .and_then(move |token| client.verify_id_token(token, nonce))
+                        .from_err()
+                        .and_then(move |identity| {
+                            let msg = OidcSignIn {
+                                issuer,
+                                subject: identity.subject,
+                                login: identity.login,
+                                email: identity.email,
+                                admin: identity.admin,
+                                ip_addr: remote.ip_addr.clone(),
+                                user_agent: remote.user_agent.clone(),
+                                policy: config.policy.clone(),
+                            };
+
+                            db.send(msg)
+                                .map_err(ErrorInternalServerError)
+                                .and_then(|r| r.map_err(actix_web::Error::from))
+                                .and_then(move |info| finish_login(db, info, remote, &config))
+                                .map(Json)
+                        }),
+                )
+            }),
+    )
+}
+
+fn encode(data: &[u8]) -> String {
+    base64::encode_config(data, base64::URL_SAFE_NO_PAD)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::block_on;
+    use openssl::pkey::Private;
+    use openssl::sign::Signer;
+    use serde_json::{json, Value};
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::Mutex;
+
+    const CLIENT_ID: &str = "szaklon";
+    const NONCE: &str = "nonce";
+
+    /// Identity provider serving the discovery document, JWKS and a token endpoint which returns
+    /// `id_token` for code `good`.
+    struct MockIdp {
+        issuer: String,
+        jwks: Arc<Mutex<Value>>,
+        id_token: Arc<Mutex<String>>,
+    }
+
+    impl MockIdp {
+        fn start(jwks: Value) -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let issuer = format!("http://{}", listener.local_addr().unwrap());
+            let idp = MockIdp {
+                issuer: issuer.clone(),
+                jwks: Arc::new(Mutex::new(jwks)),
+                id_token: Arc::new(Mutex::new(String::new())),
+            };
+
+            let (jwks, id_token) = (idp.jwks.clone(), idp.id_token.clone());
+            std::thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let mut stream = stream.unwrap();
+                    let (path, body) = read_request(&mut stream);
+                    let (status, response) = match path.as_str() {
+                        "/.well-known/openid-configuration" => (
+                            "200 OK",
+                            json!({
+                                "issuer": issuer,
+                                "authorization_endpoint": format!("{}/authorize", issuer),
+                                "token_endpoint": format!("{}/token", issuer),
+                                "jwks_uri": format!("{}/jwks", issuer),
+                            }),
+                        ),
+                        "/jwks" => ("200 OK", jwks.lock().unwrap().clone()),
+                        "/token" if body.contains("code=good") => {
+                            ("200 OK", json!({ "id_token": *id_token.lock().unwrap() }))
+                        }
+                        "/token" => ("400 Bad Request", json!({ "error": "invalid_grant" })),
+                        _ => ("404 Not Found", json!({})),
+                    };
+
+                    let response = response.to_string();
+                    let _ = write!(
+                        stream,
+                        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+                         Connection: close\r\n\r\n{}",
+                        status,
+                        response.len(),
+                        response
+                    );
+                }
+            });
+
+            idp
+        }
+
+        fn client(&self) -> Arc<OidcClient> {
+            Arc::new(OidcClient::new(OidcConfig {
+                issuer: self.issuer.clone(),
+                client_id: CLIENT_ID.to_string(),
+                client_secret: None,
+                redirect_uri: "https://szaklon.example/oidc/callback".to_string(),
+                scopes: default_scopes(),
+                admin_claim: Some("groups".to_string()),
+                admin_value: Some("szaklon-admins".to_string()),
+                login_lifetime: default_login_lifetime(),
+            }))
+        }
+
+        fn claims(&self) -> Value {
+            json!({
+                "iss": self.issuer,
+                "sub": "1234",
+                "aud": CLIENT_ID,
+                "exp": chrono::offset::Utc::now().timestamp() + 300,
+                "nonce": NONCE,
+                "preferred_username": "alice",
+                "email": "alice@example.com",
+                "email_verified": true,
+                "groups": ["users", "szaklon-admins"],
+            })
+        }
+    }
+
+    /// Returns path and body of the request.
+    fn read_request(stream: &mut TcpStream) -> (String, String) {
+        let mut request = Vec::new();
+        let mut buf = [0u8; 4096];
+        let header_end = loop {
+            let n = stream.read(&mut buf).unwrap();
+            request.extend_from_slice(&buf[..n]);
+            if let Some(i) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+                break i + 4;
+            }
+            if n == 0 {
+                break request.len();
+            }
+        };
+
+        let head = String::from_utf8_lossy(&request[..header_end]).to_string();
+        let content_length = head
+            .lines()
+            .filter_map(|line| {
+                let mut parts = line.splitn(2, ':');
+                match (parts.next(), parts.next()) {
+                    (Some(name), Some(value)) if name.eq_ignore_ascii_case("content-length") => {
+                        value.trim().parse::<usize>().ok()
+                    }
+                    _ => None,
+                }
+            })
+            .next()
+            .unwrap_or(0);
+        while request.len() < header_end + content_length {
+            let n = stream.read(&mut buf).unwrap();
+            if n == 0 {
+                break;
+            }
+            request.extend_from_slice(&buf[..n]);
+        }
+
+        let path = head.split(' ').nth(1).unwrap_or("").to_string();
+        let body = String::from_utf8_lossy(&request[header_end..]).to_string();
+
+        (path, body)
+    }
+
+    fn rsa_key() -> PKey<Private> {
+        PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap()
+    }
+
+    fn jwk(key: &PKey<Private>, kid: &str) -> Value {
+        let rsa = key.rsa().unwrap();
+
+        json!({
+            "kty": "RSA",
+            "kid": kid,
+            "use": "sig",
+            "n": encode(&rsa.n().to_vec()),
+            "e": encode(&rsa.e().to_vec()),
+        })
+    }
+
+    fn sign(key: &PKey<Private>, header: Value, claims: &Value) -> String {
+        let message = format!(
+            "{}.{}",
+            encode(header.to_string().as_bytes()),
+            encode(claims.to_string().as_bytes())
+        );
+        let mut signer = Signer::new(MessageDigest::sha256(), key).unwrap();
+        signer.update(message.as_bytes()).unwrap();
+
+        format!("{}.{}", message, encode(&signer.sign_to_vec().unwrap()))
+    }
+
+    fn rs256(kid: &str) -> Value {
+        json!({ "alg": "RS256", "typ": "JWT", "kid": kid })
+    }
+
+    fn verify(client: &Arc<OidcClient>, token: String) -> Result<Identity, Error> {
+        block_on(client.clone().verify_id_token(token, NONCE.to_string()))
+    }
+
+    fn rejection(client: &Arc<OidcClient>, token: String) -> &'static str {
+        match verify(client, token) {
+            Err(Error::InvalidIdToken(reason)) => reason,
+            Err(e) => panic!("expected invalid token, got {}", e),
+            Ok(_) => panic!("invalid token was accepted"),
+        }
+    }
+
+    fn setup() -> (PKey<Private>, MockIdp, Arc<OidcClient>) {
+        let key = rsa_key();
+        let idp = MockIdp::start(json!({ "keys": [jwk(&key, "1")] }));
+        let client = idp.client();
+
+        (key, idp, client)
+    }
+
+    #[test]
+    fn valid_token_is_accepted() {
+        let (key, idp, client) = setup();
+
+        let identity = verify(&client, sign(&key, rs256("1"), &idp.claims())).unwrap();
+
+        assert_eq!(identity.subject, "1234");
+        assert_eq!(identity.login, "alice");
+        assert_eq!(identity.email, Some("alice@example.com".to_string()));
+        assert_eq!(identity.admin, Some(true));
+    }
+
+    #[test]
+    fn code_is_exchanged_at_token_endpoint() {
+        let (key, idp, client) = setup();
+        *idp.id_token.lock().unwrap() = sign(&key, rs256("1"), &idp.claims());
+
+        let identity = block_on(
+            client
+                .clone()
+                .exchange_code("good".to_string(), "verifier".to_string())
+                .and_then(move |token| client.verify_id_token(token, NONCE.to_string())),
+        )
+        .unwrap();
+        assert_eq!(identity.subject, "1234");
+
+        match block_on(
+            idp.client()
+                .exchange_code("bad".to_string(), "verifier".to_string()),
+        ) {
+            Err(Error::InvalidCode) => (),
+            r => panic!("expected InvalidCode, got {:?}", r.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn algorithm_is_pinned() {
+        let (key, idp, client) = setup();
+        let claims = idp.claims();
+
+        let hs256 = sign(&key, json!({ "alg": "HS256", "kid": "1" }), &claims);
+        assert_eq!(rejection(&client, hs256), "unsupported algorithm");
+
+        let unsigned = format!(
+            "{}.{}.",
+            encode(json!({ "alg": "none" }).to_string().as_bytes()),
+            encode(claims.to_string().as_bytes())
+        );
+        assert_eq!(rejection(&client, unsigned), "unsupported algorithm");
+    }
+
+    #[test]
+    fn signature_is_checked() {
+        let (key, idp, client) = setup();
+
+        let forged = sign(&rsa_key(), rs256("1"), &idp.claims());
+        assert_eq!(rejection(&client, forged), "invalid signature");
+
+        let token = sign(&key, rs256("1"), &idp.claims());
+        let parts = token.split('.').collect::<Vec<_>>();
+        let mut claims = idp.claims();
+        claims["sub"] = json!("admin");
+        let tampered = format!(
+            "{}.{}.{}",
+            parts[0],
+            encode(claims.to_string().as_bytes()),
+            parts[2]
+        );
+        assert_eq!(rejection(&client, tampered), "invalid signature");
+    }
+
+    #[test]
+    fn unknown_key_is_rejected() {
+        let (_, idp, client) = setup();
+
+        let token = sign(&rsa_key(), rs256("2"), &idp.claims());
+        assert_eq!(rejection(&client, token), "unknown signing key");
+    }
+
+    #[test]
+    fn rotated_keys_are_fetched_again() {
+        let (key, idp, client) = setup();
+        verify(&client, sign(&key, rs256("1"), &idp.claims())).unwrap();
+
+        let new_key = rsa_key();
+        *idp.jwks.lock().unwrap() = json!({ "keys": [jwk(&new_key, "2")] });
+
+        verify(&client, sign(&new_key, rs256("2"), &idp.claims())).unwrap();
+    }
+
+    #[test]
+    fn issuer_is_checked() {
+        let (key, idp, client) = setup();
+        let mut claims = idp.claims();
+        claims["iss"] = json!("https://evil.example");
+
+        assert_eq!(
+            rejection(&client, sign(&key, rs256("1"), &claims)),
+            "wrong issuer"
+        );
+    }
+
+    #[test]
+    fn audience_is_checked() {
+        let (key, idp, client) = setup();
+        let token = |aud: Value, azp: Option<&str>| {
+            let mut claims = idp.claims();
+            claims["aud"] = aud;
+            if let Some(azp) = azp {
+                claims["azp"] = json!(azp);
+            }
+            sign(&key, rs256("1"), &claims)
+        };
+
+        assert_eq!(
+            rejection(&client, token(json!("other"), None)),
+            "wrong audience"
+        );
+        assert_eq!(
+            rejection(&client, token(json!([CLIENT_ID, "other"]), None)),
+            "wrong audience"
+        );
+        assert_eq!(
+            rejection(&client, token(json!([CLIENT_ID, "other"]), Some("other"))),
+            "wrong audience"
+        );
+        assert!(verify(&client, token(json!([CLIENT_ID]), None)).is_ok());
+        assert!(verify(&client, token(json!([CLIENT_ID, "other"]), Some(CLIENT_ID))).is_ok());
+    }
+
+    #[test]
+    fn expiry_is_checked() {
+        let (key, idp, client) = setup();
+        let mut claims = idp.claims();
+        claims["exp"] = json!(chrono::offset::Utc::now().timestamp() - CLOCK_SKEW);
+
+        assert_eq!(
+            rejection(&client, sign(&key, rs256("1"), &claims)),
+            "token expired"
+        );
+    }
+
+    #[test]
+    fn nonce_is_checked() {
+        let (key, idp, client) = setup();
+        let mut claims = idp.claims();
+        claims["nonce"] = json!("other");
+        assert_eq!(
+            rejection(&client, sign(&key, rs256("1"), &claims)),
+            "wrong nonce"
+        );
+
+        claims.as_object_mut().unwrap().remove("nonce");
+        assert_eq!(
+            rejection(&client, sign(&key, rs256("1"), &claims)),
+            "wrong nonce"
+        );
+    }
+
+    #[test]
+    fn subject_is_required() {
+        let (key, idp, client) = setup();
+        let mut claims = idp.claims();
+        claims["sub"] = json!("");
+
+        assert_eq!(
+            rejection(&client, sign(&key, rs256("1"), &claims)),
+            "missing subject"
+        );
+    }
+
+    #[test]
+    fn unverified_email_is_dropped() {
+        let (key, idp, client) = setup();
+        let mut claims = idp.claims();
+        claims["email_verified"] = json!(false);
+        claims["groups"] = json!(["users"]);
+
+        let identity = verify(&client, sign(&key, rs256("1"), &claims)).unwrap();
+        assert_eq!(identity.email, None);
+        assert_eq!(identity.admin, Some(false));
+    }
+}
@@ -0,0 +1,154 @@
+//! Offset based pagination shared by list endpoints.
+
+use actix_web::error::ErrorBadRequest;
+use actix_web::Error;
+use serde::{Deserialize, Serialize};
+
+const DEFAULT_LIMIT: i64 = 50;
+const MAX_LIMIT: i64 = 500;
+
+/// Parametry stronicowania podawane w query stringu, np. `?limit=20&cursor=40`.
+#[derive(Debug, Deserialize)]
+pub struct PageQuery {
+    /// Liczba elementów na stronie, domyślnie 50, najwyżej 500.
+    #[serde(default)]
+    pub limit: Option<i64>,
+    /// Wartość `next_cursor` z poprzedniej strony, brak oznacza pierwszą stronę.
+    #[serde(default)]
+    pub cursor: Option<String>,
+}
+
+impl PageQuery {
+    /// Returns BadRequest if the cursor or the limit are invalid.
+    pub fn page(&self) -> Result<PageRequest, Error> {
+        let limit = self.limit.unwrap_or(DEFAULT_LIMIT);
+        if limit < 1 {
+            return Err(ErrorBadRequest("Limit has to be positive"));
+        }
+
+        let offset = match &self.cursor {
+            Some(cursor) => match cursor.parse::<i64>() {
+                Ok(offset) if offset >= 0 => offset,
+                _ => return Err(ErrorBadRequest("Invalid cursor")),
+            },
+            None => 0,
+        };
+
+        Ok(PageRequest {
+            offset,
+            limit: limit.min(MAX_LIMIT),
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct PageRequest {
+    pub offset: i64,
+    pub limit: i64,
+}
+
+/// Strona wyników.
+#[derive(Debug, Serialize)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    /// Liczba wszystkich elementów spełniających kryteria.
+    pub total: i64,
+    /// Kursor następnej strony, `null` na ostatniej stronie.
+    pub next_cursor: Option<String>,
+}
+
+impl<T> Page<T> {
+    pub fn new(items: Vec<T>, total: i64, page: PageRequest) -> Self {
+        let end = page.offset + items.len() as i64;
+        let next_cursor = if end < total {
+            Some(end.to_string())
+        } else {
+            None
+        };
+
+        Self {
+            items,
+            total,
+            next_cursor,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn page(limit: Option<i64>, cursor: Option<&str>) -> Result<PageRequest, Error> {
+        PageQuery {
+            limit,
+            cursor: cursor.map(String::from),
+        }
+        .page()
+    }
+
+    fn status(result: Result<PageRequest, Error>) -> u16 {
+        result
+            .unwrap_err()
+            .as_response_error()
+            .error_response()
+            .status()
+            .as_u16()
+    }
+
+    #[test]
+    fn defaults() {
+        let page = page(None, None).unwrap();
+
+        assert_eq!(page.offset, 0);
+        assert_eq!(page.limit, DEFAULT_LIMIT);
+    }
+
+    #[test]
+    fn limit_has_to_be_positive() {
+        assert_eq!(status(page(Some(0), None)), 400);
+        assert_eq!(status(page(Some(-5), None)), 400);
+        assert_eq!(page(Some(1), None).unwrap().limit, 1);
+    }
+
+    #[test]
+    fn limit_is_clamped() {
+        assert_eq!(page(Some(500), None).unwrap().limit, 500);
+        assert_eq!(page(Some(501), None).unwrap().limit, MAX_LIMIT);
+        assert_eq!(page(Some(i64::MAX), None).unwrap().limit, MAX_LIMIT);
+    }
+
+    #[test]
+    fn cursor_is_offset() {
+        assert_eq!(page(None, Some("40")).unwrap().offset, 40);
+        assert_eq!(page(None, Some("0")).unwrap().offset, 0);
+    }
+
+    #[test]
+    fn invalid_cursor() {
+        assert_eq!(status(page(None, Some("abc"))), 400);
+        assert_eq!(status(page(None, Some("-1"))), 400);
+        assert_eq!(status(page(None, Some(""))), 400);
+        assert_eq!(status(page(None, Some("1.5"))), 400);
+    }
+
+    #[test]
+    fn next_cursor() {
+        let first = PageRequest {
+            offset: 0,
+            limit: 2,
+        };
+        let last = PageRequest {
+            offset: 4,
+            limit: 2,
+        };
+
+        assert_eq!(
+            Page::new(vec![1, 2], 5, first).next_cursor,
+            Some("2".to_string())
+        );
+        assert_eq!(Page::new(vec![5], 5, last).next_cursor, None);
+        assert_eq!(Page::<i32>::new(vec![], 0, first).next_cursor, None);
+        // Past the end
+        assert_eq!(Page::<i32>::new(vec![], 3, last).next_cursor, None);
+    }
+}
@@ -0,0 +1,270 @@
+use actix::Arbiter;
+use actix_web::error::{ErrorBadRequest, ErrorInternalServerError};
+use actix_web::web::{self, Data, Json};
+use actix_web::Error;
+use failure::bail;
+use futures::{
+    future::{self, Either},
+    Future,
+};
+use lettre::smtp::authentication::Credentials;
+use lettre::{ClientSecurity, ClientTlsParameters, SmtpClient, Transport};
+use lettre_email::EmailBuilder;
+use log::{error, info};
+use serde::Deserialize;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::auth::{decode_token, generate_token, User};
+use crate::db::auth::{ConfirmPasswordReset, RequestPasswordReset};
+use crate::{Actors, Config};
+
+/// There's no default, the `[password_reset]` section has to choose the delivery explicitly.
+#[derive(Clone, Debug, Deserialize)]
+pub struct PasswordResetConfig {
+    /// How long (in seconds) reset token is valid.
+    #[serde(default = "default_token_lifetime")]
+    pub token_lifetime: i64,
+    /// Link sent to the user, `{token}` is replaced with the reset token. If not set, only the
+    /// token is sent.
+    #[serde(default)]
+    pub url: Option<String>,
+    #[serde(flatten)]
+    pub delivery: DeliveryConfig,
+}
+
+fn default_token_lifetime() -> i64 {
+    60 * 60
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "delivery", rename_all = "lowercase")]
+pub enum DeliveryConfig {
+    Smtp(SmtpConfig),
+    /// Appends messages to a file, for development only. Anyone who can read the file can reset
+    /// passwords of all users with an email address.
+    File {
+        path: PathBuf,
+    },
+}
+
+/// The password can be given in `SZAKLON_SMTP_PASSWORD` environment variable instead of the
+/// configuration file, the variable takes precedence. If `username` is set, the password is
+/// required.
+#[derive(Clone, Debug, Deserialize)]
+pub struct SmtpConfig {
+    pub server: String,
+    #[serde(default)]
+    pub port: Option<u16>,
+    #[serde(default)]
+    pub security: SmtpSecurity,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+    pub from: String,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpSecurity {
+    /// TLS wrapped connection, port 465 by default.
+    #[default]
+    Tls,
+    /// Required STARTTLS, port 587 by default.
+    StartTls,
+    /// Unencrypted connection, port 25 by default. Use only for testing.
+    None,
+}
+
+/// Sposób dostarczenia tokenu resetowania hasła do użytkownika.
+pub trait ResetDelivery: Send + Sync {
+    fn deliver(&self, email: &str, login: &str, message: &str) -> Result<(), failure::Error>;
+}
+
+pub struct SmtpDelivery(pub SmtpConfig);
+
+impl ResetDelivery for SmtpDelivery {
+    fn deliver(&self, email: &str, login: &str, message: &str) -> Result<(), failure::Error> {
+        let config = &self.0;
+
+        let email = EmailBuilder::new()
+            .to((email, login))
+            .from(config.from.as_str())
+            .subject("Szaklon - resetowanie hasła")
+            .text(message)
+            .build()?;
+
+        let tls_parameters = || -> Result<_, failure::Error> {
+            Ok(ClientTlsParameters::new(
+                config.server.clone(),
+                native_tls::TlsConnector::new()?,
+            ))
+        };
+        let (security, default_port) = match config.security {
+            SmtpSecurity::Tls => (ClientSecurity::Wrapper(tls_parameters()?), 465),
+            SmtpSecurity::StartTls => (ClientSecurity::Required(tls_parameters()?), 587),
+            SmtpSecurity::None => (ClientSecurity::None, 25),
+        };
+
+        let mut client = SmtpClient::new(
+            (config.server.as_str(), config.port.unwrap_or(default_port)),
+            security,
+        )?;
+        if let (Some(username), Some(password)) = (&config.username, &config.password) {
+            client = client.credentials(Credentials::new(username.clone(), password.clone()));
+        }
+
+        client.transport().send(email.into())?;
+
+        Ok(())
+    }
+}
+
+pub struct FileDelivery(pub PathBuf);
+
+impl ResetDelivery for FileDelivery {
+    fn deliver(&self, email: &str, login: &str, message: &str) -> Result<(), failure::Error> {
+        // The message contains the token, so it's never logged
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.0)?;
+        writeln!(
+            file,
+            "{}\t{}\t{}\t{}",
+            chrono::offset::Utc::now().to_rfc3339(),
+            login,
+            email,
+            message
+        )?;
+        info!(
+            "Password reset for {} <{}> written to {}",
+            login,
+            email,
+            self.0.display()
+        );
+
+        Ok(())
+    }
+}
+
+impl SmtpConfig {
+    fn with_env(&self) -> Result<Self, failure::Error> {
+        let password = std::env::var("SZAKLON_SMTP_PASSWORD")
+            .ok()
+            .or_else(|| self.password.clone());
+        if self.username.is_some() && password.is_none() {
+            bail!("SMTP password is missing, set SZAKLON_SMTP_PASSWORD");
+        }
+
+        Ok(Self {
+            password,
+            ..self.clone()
+        })
+    }
+}
+
+pub fn delivery_from_config(
+    config: &PasswordResetConfig,
+) -> Result<Arc<dyn ResetDelivery>, failure::Error> {
+    Ok(match &config.delivery {
+        DeliveryConfig::Smtp(smtp) => Arc::new(SmtpDelivery(smtp.with_env()?)),
+        DeliveryConfig::File { path } => Arc::new(FileDelivery(path.clone())),
+    })
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ResetRequestData {
+    pub login: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ResetConfirmData {
+    pub token: String,
+    pub new_password: String,
+}
+
+/// `POST /password_reset/request`
+///
+/// Wysyła token resetowania hasła na adres email użytkownika. Zawsze zwraca 200 bez czekania na
+/// wysłanie wiadomości, niezależnie od tego czy użytkownik istnieje. Token jest ważny przez
+/// `token_lifetime` sekund z sekcji `[password_reset]` pliku konfiguracyjnego.
+pub fn request_reset(
+    data: Json<ResetRequestData>,
+    actors: Data<Actors>,
+    config: Data<Config>,
+    delivery: Data<Arc<dyn ResetDelivery>>,
+) {
+    let token = generate_token();
+    let msg = RequestPasswordReset {
+        name: data.into_inner().login,
+        token,
+        lifetime: chrono::Duration::seconds(config.password_reset.token_lifetime),
+    };
+
+    let token = base64::encode_config(&token, base64::URL_SAFE_NO_PAD);
+    let message = match &config.password_reset.url {
+        Some(url) => format!(
+            "Aby ustawić nowe hasło, otwórz link: {}",
+            url.replace("{token}", &token)
+        ),
+        None => format!("Token resetowania hasła: {}", token),
+    };
+
+    // Response time can't depend on whether the account exists, so nothing is awaited
+    Arbiter::spawn(
+        actors
+            .db
+            .send(msg)
+            .map_err(Error::from)
+            .and_then(|r| r.map_err(Error::from))
+            .and_then(move |user| match user {
+                Some(User {
+                    login,
+                    email: Some(email),
+                    ..
+                }) => Either::A(
+                    web::block(move || delivery.deliver(&email, &login, &message))
+                        .map_err(Error::from),
+                ),
+                _ => Either::B(future::ok(())),
+            })
+            .map_err(|e| error!("Failed to deliver password reset token: {}", e)),
+    );
+}
+
+/// `POST /password_reset/confirm`
+///
+/// Ustawia nowe hasło przy pomocy tokenu resetowania hasła i kończy wszystkie sesje użytkownika.
+/// Token może zostać użyty tylko raz. W przypadku nieprawidłowego lub wygasłego tokenu zwraca
+/// BadRequest. Nowe hasło musi spełniać reguły z sekcji `[policy]`, błędy mają taki sam format
+/// jak w `POST /signup`.
+pub fn confirm_reset(
+    data: Json<ResetConfirmData>,
+    config: Data<Config>,
+    actors: Data<Actors>,
+) -> impl Future<Item = (), Error = Error> {
+    let data = data.into_inner();
+    if let Err(e) = config.policy.check_password(&data.new_password, None) {
+        return Either::A(future::err(e.into()));
+    }
+
+    let token = match decode_token(&data.token, base64::URL_SAFE_NO_PAD) {
+        Some(token) => token,
+        None => return Either::A(future::err(ErrorBadRequest("Invalid token"))),
+    };
+
+    let db = actors.db.clone();
+
+    Either::B(
+        actors
+            .hasher
+            .hash(data.new_password.into(), config.password_hash)
+            .from_err()
+            .and_then(move |hash| {
+                db.send(ConfirmPasswordReset { token, hash })
+                    .map_err(ErrorInternalServerError)
+                    .and_then(|r| r.map_err(Error::from))
+            }),
+    )
+}
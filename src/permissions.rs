@@ -0,0 +1,172 @@
+//! Named permissions required by handlers. Roles are mapped to permissions in the
+//! `role_permissions` table, API key scopes are names of permissions.
+
+use actix_web::dev::Payload;
+use actix_web::error::{ErrorBadRequest, ErrorForbidden, ErrorInternalServerError};
+use actix_web::web::{Data, Json, Path};
+use actix_web::{Error, FromRequest, HttpRequest};
+use futures::{
+    future::{self, Either},
+    Future,
+};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::marker::PhantomData;
+use std::ops::Deref;
+
+use crate::auth::Auth;
+use crate::db::permissions::{GetRolePermissions, SetRolePermissions};
+use crate::Actors;
+
+pub trait Permission {
+    const NAME: &'static str;
+}
+
+/// Adding and editing songs.
+pub struct SongsWrite;
+/// Reading search history of all users.
+pub struct HistoryRead;
+pub struct UsersRead;
+/// Managing accounts, roles, lockouts and two-factor authentication of other users.
+pub struct UsersWrite;
+pub struct LogsRead;
+pub struct ApiKeysManage;
+
+impl Permission for SongsWrite {
+    const NAME: &'static str = "songs:write";
+}
+
+impl Permission for HistoryRead {
+    const NAME: &'static str = "history:read";
+}
+
+impl Permission for UsersRead {
+    const NAME: &'static str = "users:read";
+}
+
+impl Permission for UsersWrite {
+    const NAME: &'static str = "users:write";
+}
+
+impl Permission for LogsRead {
+    const NAME: &'static str = "logs:read";
+}
+
+impl Permission for ApiKeysManage {
+    const NAME: &'static str = "api_keys:manage";
+}
+
+pub const ALL: &[&str] = &[
+    SongsWrite::NAME,
+    HistoryRead::NAME,
+    UsersRead::NAME,
+    UsersWrite::NAME,
+    LogsRead::NAME,
+    ApiKeysManage::NAME,
+];
+
+/// Ekstraktor danych uwierzytelniających, który dodatkowo wymaga uprawnienia `P`. Jeśli
+/// użytkownik go nie ma, zwraca Forbidden.
+pub struct Authorized<P> {
+    pub auth: Auth,
+    permission: PhantomData<P>,
+}
+
+impl<P> Deref for Authorized<P> {
+    type Target = Auth;
+
+    fn deref(&self) -> &Auth {
+        &self.auth
+    }
+}
+
+impl<P: Permission + 'static> FromRequest for Authorized<P> {
+    type Error = Error;
+    type Future = Box<dyn Future<Item = Self, Error = Error>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        Box::new(Auth::from_request(req, payload).and_then(|auth| {
+            if auth.has_permission(P::NAME) {
+                Ok(Authorized {
+                    auth,
+                    permission: PhantomData,
+                })
+            } else {
+                Err(ErrorForbidden(format!("Missing permission {}", P::NAME)))
+            }
+        }))
+    }
+}
+
+/// Lista uprawnień nadawanych roli.
+#[derive(Debug, Deserialize)]
+pub struct RolePermissionsData {
+    pub permissions: Vec<String>,
+}
+
+/// `GET /roles`
+///
+/// Zwraca uprawnienia wszystkich ról, np. `{"ADMIN": ["logs:read", ...], "EDITOR":
+/// ["songs:write"]}`. Role bez uprawnień (np. `CUSTOMER`) nie są zwracane. Wymaga uprawnienia
+/// `users:read`.
+pub fn roles(
+    _auth: Authorized<UsersRead>,
+    actors: Data<Actors>,
+) -> impl Future<Item = Json<BTreeMap<String, Vec<String>>>, Error = Error> {
+    actors
+        .db
+        .send(GetRolePermissions)
+        .map_err(ErrorInternalServerError)
+        .and_then(|r| r.map_err(Error::from))
+        .map(|rows| {
+            let mut roles = BTreeMap::new();
+            for row in rows {
+                roles
+                    .entry(row.role)
+                    .or_insert_with(Vec::new)
+                    .push(row.permission);
+            }
+
+            Json(roles)
+        })
+}
+
+/// `PUT /roles/{role}`
+///
+/// Zastępuje uprawnienia roli, tworząc ją jeśli nie istnieje. Nazwa roli może zawierać tylko
+/// wielkie litery i podkreślenia. Pusta lista uprawnień usuwa rolę, ale użytkownicy, którzy ją
+/// mają, zachowują ją (bez uprawnień). Zmiana obowiązuje od razu we wszystkich sesjach, poza
+/// podpisanymi tokenami (`session_mode = "signed"`). Wymaga uprawnienia `users:write`.
+///
+/// Zwraca BadRequest w przypadku nieprawidłowej nazwy roli lub nieznanego uprawnienia i Conflict
+/// przy próbie odebrania roli `ADMIN` uprawnienia `users:write`.
+pub fn set_role_permissions(
+    role: Path<String>,
+    data: Json<RolePermissionsData>,
+    _auth: Authorized<UsersWrite>,
+    actors: Data<Actors>,
+) -> impl Future<Item = (), Error = Error> {
+    let role = role.into_inner();
+    if role.is_empty() || !role.chars().all(|c| c.is_ascii_uppercase() || c == '_') {
+        return Either::A(future::err(ErrorBadRequest("Invalid role name")));
+    }
+
+    let mut permissions = data.into_inner().permissions;
+    if let Some(unknown) = permissions.iter().find(|p| !ALL.contains(&p.as_str())) {
+        return Either::A(future::err(ErrorBadRequest(format!(
+            "Unknown permission {}",
+            unknown
+        ))));
+    }
+    permissions.sort();
+    permissions.dedup();
+
+    Either::B(
+        actors
+            .db
+            .send(SetRolePermissions { role, permissions })
+            .map_err(ErrorInternalServerError)
+            .and_then(|r| r.map_err(Error::from)),
+    )
+}
@@ -0,0 +1,418 @@
+//! Rules for new logins and passwords, configured in the `[policy]` section.
+
+use actix_web::{dev::Body, web::HttpResponse, ResponseError};
+use failure::Fail;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+use crate::db::auth::normalize_username;
+
+lazy_static! {
+    static ref COMMON_PASSWORDS: HashSet<&'static str> = include_str!("../common_passwords.txt")
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .collect();
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct PolicyConfig {
+    #[serde(default = "default_login_min_length")]
+    pub login_min_length: usize,
+    #[serde(default = "default_login_max_length")]
+    pub login_max_length: usize,
+    /// Character classes allowed in logins: `letters`, `digits` and `symbols`
+    /// (`login_symbols`).
+    #[serde(default = "default_login_classes")]
+    pub login_classes: Vec<CharClass>,
+    #[serde(default = "default_login_symbols")]
+    pub login_symbols: String,
+    /// Logins which can't be registered, compared after normalization.
+    #[serde(default = "default_reserved_logins")]
+    pub reserved_logins: Vec<String>,
+    #[serde(default = "default_password_min_length")]
+    pub password_min_length: usize,
+    /// Minimal estimated entropy of the password in bits, see `password_entropy`.
+    #[serde(default = "default_password_min_entropy")]
+    pub password_min_entropy: f64,
+    /// Reject passwords from the bundled list of common passwords.
+    #[serde(default = "default_reject_common_passwords")]
+    pub reject_common_passwords: bool,
+}
+
+impl Default for PolicyConfig {
+    fn default() -> Self {
+        Self {
+            login_min_length: default_login_min_length(),
+            login_max_length: default_login_max_length(),
+            login_classes: default_login_classes(),
+            login_symbols: default_login_symbols(),
+            reserved_logins: default_reserved_logins(),
+            password_min_length: default_password_min_length(),
+            password_min_entropy: default_password_min_entropy(),
+            reject_common_passwords: default_reject_common_passwords(),
+        }
+    }
+}
+
+fn default_login_min_length() -> usize {
+    3
+}
+
+fn default_login_max_length() -> usize {
+    32
+}
+
+fn default_login_classes() -> Vec<CharClass> {
+    vec![CharClass::Letters, CharClass::Digits, CharClass::Symbols]
+}
+
+fn default_login_symbols() -> String {
+    "._-".to_string()
+}
+
+fn default_reserved_logins() -> Vec<String> {
+    [
+        "admin",
+        "administrator",
+        "root",
+        "system",
+        "support",
+        "szaklon",
+    ]
+    .iter()
+    .map(|s| s.to_string())
+    .collect()
+}
+
+fn default_password_min_length() -> usize {
+    8
+}
+
+fn default_password_min_entropy() -> f64 {
+    40.0
+}
+
+fn default_reject_common_passwords() -> bool {
+    true
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CharClass {
+    Letters,
+    Digits,
+    Symbols,
+}
+
+/// Naruszona reguła, np. `{"field": "password", "rule": "min_length", "message": "..."}`.
+#[derive(Debug, Serialize)]
+pub struct Violation {
+    /// `login` lub `password`.
+    pub field: &'static str,
+    /// `min_length`, `max_length`, `characters`, `reserved`, `min_entropy`, `common` lub
+    /// `contains_login`.
+    pub rule: &'static str,
+    pub message: String,
+}
+
+/// Treść odpowiedzi BadRequest, gdy login lub hasło nie spełniają reguł z sekcji `[policy]`
+/// pliku konfiguracyjnego. Zawiera wszystkie naruszone reguły.
+#[derive(Debug, Fail, Serialize)]
+#[fail(display = "Login or password doesn't meet the policy")]
+pub struct PolicyError {
+    pub violations: Vec<Violation>,
+}
+
+impl ResponseError for PolicyError {
+    fn error_response(&self) -> HttpResponse<Body> {
+        HttpResponse::BadRequest().json(self)
+    }
+
+    // The default implementation replaces the body with the display message
+    fn render_response(&self) -> HttpResponse<Body> {
+        self.error_response()
+    }
+}
+
+impl PolicyConfig {
+    /// Checks login and password of a new account.
+    pub fn check_signup(&self, login: &str, password: &str) -> Result<(), PolicyError> {
+        let mut violations = self.login_violations(login);
+        violations.extend(self.password_violations(password, Some(login)));
+
+        into_result(violations)
+    }
+
+    /// Checks login of a new account created without a password, e.g. by OpenID Connect.
+    pub fn check_login(&self, login: &str) -> Result<(), PolicyError> {
+        into_result(self.login_violations(login))
+    }
+
+    /// Checks new password of an existing account, `login` is `None` if it isn't known.
+    pub fn check_password(&self, password: &str, login: Option<&str>) -> Result<(), PolicyError> {
+        into_result(self.password_violations(password, login))
+    }
+
+    fn login_violations(&self, login: &str) -> Vec<Violation> {
+        let login = normalize_username(login);
+        let length = login.chars().count();
+        let mut violations = Vec::new();
+        let mut violation = |rule, message: String| {
+            violations.push(Violation {
+                field: "login",
+                rule,
+                message,
+            })
+        };
+
+        if length < self.login_min_length {
+            violation(
+                "min_length",
+                format!(
+                    "Login has to be at least {} characters long",
+                    self.login_min_length
+                ),
+            );
+        }
+        if length > self.login_max_length {
+            violation(
+                "max_length",
+                format!(
+                    "Login can't be longer than {} characters",
+                    self.login_max_length
+                ),
+            );
+        }
+        if !login.chars().all(|c| self.login_char_allowed(c)) {
+            violation(
+                "characters",
+                "Login contains characters which are not allowed".to_string(),
+            );
+        }
+        if self
+            .reserved_logins
+            .iter()
+            .any(|r| normalize_username(r) == login)
+        {
+            violation("reserved", "Login is reserved".to_string());
+        }
+
+        violations
+    }
+
+    fn login_char_allowed(&self, c: char) -> bool {
+        self.login_classes.iter().any(|class| match class {
+            CharClass::Letters => c.is_alphabetic(),
+            CharClass::Digits => c.is_numeric(),
+            CharClass::Symbols => self.login_symbols.contains(c),
+        })
+    }
+
+    fn password_violations(&self, password: &str, login: Option<&str>) -> Vec<Violation> {
+        let mut violations = Vec::new();
+        let mut violation = |rule, message: String| {
+            violations.push(Violation {
+                field: "password",
+                rule,
+                message,
+            })
+        };
+
+        if password.chars().count() < self.password_min_length {
+            violation(
+                "min_length",
+                format!(
+                    "Password has to be at least {} characters long",
+                    self.password_min_length
+                ),
+            );
+        }
+        if password_entropy(password) < self.password_min_entropy {
+            violation(
+                "min_entropy",
+                "Password is too simple, use more characters or more kinds of characters"
+                    .to_string(),
+            );
+        }
+        if self.reject_common_passwords
+            && COMMON_PASSWORDS.contains(password.to_lowercase().as_str())
+        {
+            violation("common", "Password is too common".to_string());
+        }
+        if let Some(login) = login {
+            let login = normalize_username(login);
+            if !login.is_empty() && password.to_lowercase().contains(&login) {
+                violation(
+                    "contains_login",
+                    "Password can't contain the login".to_string(),
+                );
+            }
+        }
+
+        violations
+    }
+}
+
+fn into_result(violations: Vec<Violation>) -> Result<(), PolicyError> {
+    if violations.is_empty() {
+        Ok(())
+    } else {
+        Err(PolicyError { violations })
+    }
+}
+
+/// Rough estimate of the password entropy: length times log2 of the size of the alphabet built
+/// from the character classes used in the password. Repeated characters count once.
+fn password_entropy(password: &str) -> f64 {
+    let chars = password.chars().collect::<HashSet<_>>();
+    let mut alphabet = 0;
+    if chars.iter().any(char::is_ascii_lowercase) {
+        alphabet += 26;
+    }
+    if chars.iter().any(char::is_ascii_uppercase) {
+        alphabet += 26;
+    }
+    if chars.iter().any(char::is_ascii_digit) {
+        alphabet += 10;
+    }
+    if chars
+        .iter()
+        .any(|c| c.is_ascii() && !c.is_ascii_alphanumeric())
+    {
+        alphabet += 33;
+    }
+    if chars.iter().any(|c| !c.is_ascii()) {
+        alphabet += 100;
+    }
+
+    if alphabet == 0 {
+        return 0.0;
+    }
+
+    chars.len() as f64 * f64::from(alphabet).log2()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PASSWORD: &str = "Zx9!qLm#2vTr";
+
+    fn rules(result: Result<(), PolicyError>) -> Vec<(&'static str, &'static str)> {
+        match result {
+            Ok(()) => Vec::new(),
+            Err(e) => e.violations.iter().map(|v| (v.field, v.rule)).collect(),
+        }
+    }
+
+    fn signup(login: &str, password: &str) -> Vec<(&'static str, &'static str)> {
+        rules(PolicyConfig::default().check_signup(login, password))
+    }
+
+    fn password(password: &str) -> Vec<(&'static str, &'static str)> {
+        rules(PolicyConfig::default().check_password(password, None))
+    }
+
+    #[test]
+    fn valid_signup() {
+        assert_eq!(signup("alice_2", PASSWORD), vec![]);
+        assert_eq!(signup("Żaneta", PASSWORD), vec![]);
+    }
+
+    #[test]
+    fn login_length() {
+        assert_eq!(signup("al", PASSWORD), vec![("login", "min_length")]);
+        assert_eq!(signup(&"a".repeat(32), PASSWORD), vec![]);
+        assert_eq!(
+            signup(&"a".repeat(33), PASSWORD),
+            vec![("login", "max_length")]
+        );
+    }
+
+    #[test]
+    fn login_characters() {
+        assert_eq!(
+            signup("alice smith", PASSWORD),
+            vec![("login", "characters")]
+        );
+        assert_eq!(signup("alice!", PASSWORD), vec![("login", "characters")]);
+
+        let config = PolicyConfig {
+            login_classes: vec![CharClass::Letters],
+            ..PolicyConfig::default()
+        };
+        assert_eq!(
+            rules(config.check_signup("alice2", PASSWORD)),
+            vec![("login", "characters")]
+        );
+    }
+
+    #[test]
+    fn reserved_login() {
+        assert_eq!(signup("Admin", PASSWORD), vec![("login", "reserved")]);
+
+        let config = PolicyConfig {
+            reserved_logins: vec!["Moderator".to_string()],
+            ..PolicyConfig::default()
+        };
+        assert_eq!(
+            rules(config.check_signup("moderator", PASSWORD)),
+            vec![("login", "reserved")]
+        );
+        assert_eq!(rules(config.check_signup("admin", PASSWORD)), vec![]);
+    }
+
+    #[test]
+    fn password_length() {
+        assert_eq!(password("Zx9!qLm"), vec![("password", "min_length")]);
+    }
+
+    #[test]
+    fn password_entropy_is_checked() {
+        assert_eq!(password("aaaaaaaaaaaa"), vec![("password", "min_entropy")]);
+        assert!(password_entropy("aaaaaaaaaaaa") < password_entropy("abcdefghijkl"));
+        assert_eq!(password_entropy(""), 0.0);
+    }
+
+    #[test]
+    fn common_password() {
+        assert_eq!(password("Password1"), vec![("password", "common")]);
+
+        let config = PolicyConfig {
+            reject_common_passwords: false,
+            ..PolicyConfig::default()
+        };
+        assert_eq!(rules(config.check_password("Password1", None)), vec![]);
+    }
+
+    #[test]
+    fn password_containing_login() {
+        let with_login = format!("Alice-{}", PASSWORD);
+
+        assert_eq!(
+            signup("alice", &with_login),
+            vec![("password", "contains_login")]
+        );
+        assert_eq!(
+            rules(PolicyConfig::default().check_password(&with_login, Some("ALICE"))),
+            vec![("password", "contains_login")]
+        );
+        assert_eq!(password(&with_login), vec![]);
+    }
+
+    #[test]
+    fn all_violations_are_reported() {
+        assert_eq!(
+            signup("root", "root"),
+            vec![
+                ("login", "reserved"),
+                ("password", "min_length"),
+                ("password", "min_entropy"),
+                ("password", "common"),
+                ("password", "contains_login"),
+            ]
+        );
+    }
+}
@@ -1,7 +1,23 @@
+pub use crate::api_keys::{create_key, keys, revoke_key};
 pub use crate::auth::{
-    check_session, delete_account, delete_account_admin, login, logout, signup, users,
+    change_password, check_session, clear_lockout, delete_account, delete_account_admin,
+    erase_account, login, logout, password_hashes, reactivate_account, refresh_token, set_role,
+    signup, users,
+};
+pub use crate::export::export;
+pub use crate::logs::{account_logs, logs};
+pub use crate::oidc::{callback as oidc_callback, login as oidc_login};
+pub use crate::password_reset::{confirm_reset, request_reset};
+pub use crate::permissions::{roles, set_role_permissions};
+pub use crate::sessions::{
+    delete_session, delete_session_admin, delete_sessions, delete_sessions_admin, sessions,
+    user_sessions_admin,
 };
-pub use crate::logs::logs;
 pub use crate::songs::{
-    add_song, artists, edit_song, genres, history, history_all, popular, recognize, songs,
+    add_song, artists, delete_song, edit_song, genres, history, history_all, patch_song, popular,
+    recognize, restore_song_revision, search, song, song_revisions, songs,
+};
+pub use crate::two_factor::{
+    confirm as confirm_two_factor, disable as disable_two_factor,
+    disable_admin as disable_two_factor_admin, enroll as enroll_two_factor, login_two_factor,
 };
@@ -0,0 +1,396 @@
+use actix::prelude::*;
+use actix_web::error::{ErrorInternalServerError, ErrorNotImplemented};
+use actix_web::web::{Data, Json, Path};
+use actix_web::Error;
+use chrono::NaiveDateTime;
+use futures::{
+    future::{self, Either},
+    Future,
+};
+use log::{error, info};
+use serde::Serialize;
+use std::time::Duration;
+
+use crate::auth::{Auth, Credential};
+use crate::db::sessions::{
+    DeleteUserSession, DeleteUserSessions, GetUserSessions, PurgeSessions, SessionTimeouts,
+};
+use crate::db::DbExecutor;
+use crate::permissions::{Authorized, UsersRead, UsersWrite};
+use crate::{Actors, Config, SessionMode};
+
+/// Actor periodically removing expired sessions from the database.
+pub struct SessionSweeper {
+    pub db: Addr<DbExecutor>,
+    pub timeouts: SessionTimeouts,
+    pub interval: Duration,
+}
+
+impl Actor for SessionSweeper {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(self.interval, |act, _| {
+            let msg = PurgeSessions {
+                timeouts: act.timeouts,
+            };
+
+            Arbiter::spawn(act.db.send(msg).then(|res| {
+                match res {
+                    Ok(Ok(0)) => (),
+                    Ok(Ok(n)) => info!("Removed {} expired sessions and refresh tokens", n),
+                    Ok(Err(e)) => error!("Failed to remove expired sessions: {}", e),
+                    Err(e) => error!("Failed to remove expired sessions: {}", e),
+                }
+
+                Ok(())
+            }));
+        });
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct SessionEntry {
+    pub id: i32,
+    /// ISO 8601 / RFC 3339 format. Sesja jest tworzona od nowa przy każdym odświeżeniu tokenu.
+    pub created_at: NaiveDateTime,
+    pub last_active: NaiveDateTime,
+    pub ip_addr: String,
+    pub os: String,
+    pub browser: String,
+    /// Czy jest to sesja, z której wysłano zapytanie.
+    pub current: bool,
+}
+
+/// `GET /sessions`
+///
+/// Zwraca aktywne sesje aktualnego użytkownika, od najnowszej. Gdy `session_mode = "signed"`,
+/// sesje nie są zapisywane i zwracany jest Not Implemented, tak jak we wszystkich endpointach
+/// `/sessions` i `/users/{id}/sessions`.
+pub fn sessions(
+    auth: Auth,
+    config: Data<Config>,
+    actors: Data<Actors>,
+) -> impl Future<Item = Json<Vec<SessionEntry>>, Error = Error> {
+    let current = match auth.credential {
+        Credential::Session(token) => Some(token),
+        _ => None,
+    };
+    if let Err(e) = auth.require_session().and(stored_sessions(&config)) {
+        return Either::A(future::err(e));
+    }
+
+    Either::B(user_sessions(auth.id, current, &config, &actors))
+}
+
+/// `DELETE /sessions/{id}`
+///
+/// Kończy wybraną sesję aktualnego użytkownika (razem z jej tokenem odświeżania). Zwraca Not Found,
+/// jeśli sesja nie istnieje lub należy do innego użytkownika.
+pub fn delete_session(
+    id: Path<i32>,
+    auth: Auth,
+    config: Data<Config>,
+    actors: Data<Actors>,
+) -> impl Future<Item = (), Error = Error> {
+    if let Err(e) = auth.require_session().and(stored_sessions(&config)) {
+        return Either::A(future::err(e));
+    }
+
+    Either::B(delete_user_session(auth.id, *id, &actors))
+}
+
+/// `DELETE /sessions`
+///
+/// Wylogowuje aktualnego użytkownika ze wszystkich sesji, łącznie z aktualną.
+pub fn delete_sessions(
+    auth: Auth,
+    config: Data<Config>,
+    actors: Data<Actors>,
+) -> impl Future<Item = (), Error = Error> {
+    if let Err(e) = auth.require_session().and(stored_sessions(&config)) {
+        return Either::A(future::err(e));
+    }
+
+    Either::B(delete_user_sessions(auth.id, &actors))
+}
+
+/// `GET /users/{id}/sessions`
+///
+/// Zwraca aktywne sesje wybranego użytkownika, tak jak `GET /sessions`. Wymaga uprawnienia
+/// `users:read`.
+pub fn user_sessions_admin(
+    id: Path<i32>,
+    _auth: Authorized<UsersRead>,
+    config: Data<Config>,
+    actors: Data<Actors>,
+) -> impl Future<Item = Json<Vec<SessionEntry>>, Error = Error> {
+    match stored_sessions(&config) {
+        Ok(()) => Either::A(user_sessions(*id, None, &config, &actors)),
+        Err(e) => Either::B(future::err(e)),
+    }
+}
+
+/// `DELETE /users/{id}/sessions/{session_id}`
+///
+/// Kończy wybraną sesję użytkownika. Wymaga uprawnienia `users:write`. Zwraca Not Found, jeśli
+/// sesja nie istnieje lub należy do innego użytkownika.
+pub fn delete_session_admin(
+    path: Path<(i32, i32)>,
+    _auth: Authorized<UsersWrite>,
+    config: Data<Config>,
+    actors: Data<Actors>,
+) -> impl Future<Item = (), Error = Error> {
+    match stored_sessions(&config) {
+        Ok(()) => Either::A(delete_user_session(path.0, path.1, &actors)),
+        Err(e) => Either::B(future::err(e)),
+    }
+}
+
+/// `DELETE /users/{id}/sessions`
+///
+/// Kończy wszystkie sesje użytkownika. Wymaga uprawnienia `users:write`.
+pub fn delete_sessions_admin(
+    id: Path<i32>,
+    _auth: Authorized<UsersWrite>,
+    config: Data<Config>,
+    actors: Data<Actors>,
+) -> impl Future<Item = (), Error = Error> {
+    match stored_sessions(&config) {
+        Ok(()) => Either::A(delete_user_sessions(*id, &actors)),
+        Err(e) => Either::B(future::err(e)),
+    }
+}
+
+/// Signed tokens aren't recorded, so they can't be listed or revoked without knowing them.
+fn stored_sessions(config: &Config) -> Result<(), Error> {
+    if config.session_mode == SessionMode::Signed {
+        return Err(ErrorNotImplemented(
+            "Sessions aren't stored when signed tokens are used",
+        ));
+    }
+
+    Ok(())
+}
+
+fn user_sessions(
+    user_id: i32,
+    current: Option<[u8; 32]>,
+    config: &Config,
+    actors: &Actors,
+) -> impl Future<Item = Json<Vec<SessionEntry>>, Error = Error> {
+    let msg = GetUserSessions {
+        user_id,
+        current,
+        timeouts: config.session_timeouts(),
+    };
+
+    actors
+        .db
+        .send(msg)
+        .map_err(ErrorInternalServerError)
+        .and_then(|r| r.map_err(Error::from).map(Json))
+}
+
+fn delete_user_session(
+    user_id: i32,
+    session_id: i32,
+    actors: &Actors,
+) -> impl Future<Item = (), Error = Error> {
+    actors
+        .db
+        .send(DeleteUserSession {
+            user_id,
+            session_id,
+        })
+        .map_err(ErrorInternalServerError)
+        .and_then(|r| r.map_err(Error::from))
+}
+
+fn delete_user_sessions(user_id: i32, actors: &Actors) -> impl Future<Item = (), Error = Error> {
+    actors
+        .db
+        .send(DeleteUserSessions { user_id })
+        .map_err(ErrorInternalServerError)
+        .and_then(|r| r.map_err(Error::from))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::models::User;
+    use crate::db::test_user;
+    use crate::signed_tokens::{self, Claims};
+    use crate::testing::{self, authorization, call, call_json};
+    use actix_web::http::StatusCode;
+    use actix_web::test::{init_service, TestRequest};
+    use actix_web::{web, App};
+    use serde_json::Value;
+
+    const ALICE: [u8; 32] = [1; 32];
+    const ALICE_OTHER: [u8; 32] = [2; 32];
+    const BOB: [u8; 32] = [3; 32];
+    const ADMIN: [u8; 32] = [4; 32];
+
+    macro_rules! app {
+        ($config:expr, $actors:expr) => {
+            init_service(
+                App::new()
+                    .data($config)
+                    .data($actors)
+                    .service(
+                        web::resource("/sessions")
+                            .route(web::get().to_async(sessions))
+                            .route(web::delete().to_async(delete_sessions)),
+                    )
+                    .service(
+                        web::resource("/sessions/{id}")
+                            .route(web::delete().to_async(delete_session)),
+                    )
+                    .service(
+                        web::resource("/users/{id}/sessions")
+                            .route(web::get().to_async(user_sessions_admin))
+                            .route(web::delete().to_async(delete_sessions_admin)),
+                    )
+                    .service(
+                        web::resource("/users/{id}/sessions/{session_id}")
+                            .route(web::delete().to_async(delete_session_admin)),
+                    ),
+            )
+        };
+    }
+
+    fn get(path: &str, token: &str) -> TestRequest {
+        TestRequest::get().uri(path).header("Authorization", token)
+    }
+
+    fn delete(path: &str, token: &str) -> TestRequest {
+        TestRequest::delete()
+            .uri(path)
+            .header("Authorization", token)
+    }
+
+    /// Alice (ID 1) with two sessions, Bob (ID 2) and an admin (ID 3) with one.
+    fn seed(conn: &diesel::SqliteConnection) {
+        let alice = test_user(conn, "alice", User::ROLE_CUSTOMER);
+        let bob = test_user(conn, "bob", User::ROLE_CUSTOMER);
+        let admin = test_user(conn, "admin", User::ROLE_ADMIN);
+        testing::session(conn, alice, ALICE);
+        testing::session(conn, alice, ALICE_OTHER);
+        testing::session(conn, bob, BOB);
+        testing::session(conn, admin, ADMIN);
+    }
+
+    #[test]
+    fn sessions_are_listed_and_ended() {
+        let (mut system, actors) = testing::actors(seed);
+        let mut app = app!(testing::config(""), actors);
+        let alice = authorization(ALICE);
+
+        let entries: Vec<Value> =
+            call_json(&mut system, &mut app, get("/sessions", &alice).to_request());
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries.iter().filter(|e| e["current"] == true).count(), 1);
+        assert_eq!(entries[0]["ip_addr"], "192.0.2.1");
+
+        let other_id = entries.iter().find(|e| e["current"] == false).unwrap()["id"].clone();
+        let other = delete(&format!("/sessions/{}", other_id), &alice).to_request();
+        assert_eq!(call(&mut system, &mut app, other).status(), StatusCode::OK);
+        let entries: Vec<Value> =
+            call_json(&mut system, &mut app, get("/sessions", &alice).to_request());
+        assert_eq!(entries.len(), 1);
+
+        // Sessions of other users can't be ended
+        let bob = delete("/sessions/1", &authorization(BOB)).to_request();
+        assert_eq!(
+            call(&mut system, &mut app, bob).status(),
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[test]
+    fn logging_out_everywhere_ends_only_own_sessions() {
+        let (mut system, actors) = testing::actors(seed);
+        let mut app = app!(testing::config(""), actors);
+
+        let logout = delete("/sessions", &authorization(ALICE)).to_request();
+        assert_eq!(call(&mut system, &mut app, logout).status(), StatusCode::OK);
+
+        for token in &[ALICE, ALICE_OTHER] {
+            let request = get("/sessions", &authorization(*token)).to_request();
+            assert_eq!(
+                call(&mut system, &mut app, request).status(),
+                StatusCode::UNAUTHORIZED
+            );
+        }
+        let bob = get("/sessions", &authorization(BOB)).to_request();
+        assert_eq!(call(&mut system, &mut app, bob).status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn admin_ends_sessions_of_user() {
+        let (mut system, actors) = testing::actors(seed);
+        let mut app = app!(testing::config(""), actors);
+        let admin = authorization(ADMIN);
+
+        let forbidden = delete("/users/2/sessions", &authorization(ALICE)).to_request();
+        assert_eq!(
+            call(&mut system, &mut app, forbidden).status(),
+            StatusCode::FORBIDDEN
+        );
+
+        let entries: Vec<Value> = call_json(
+            &mut system,
+            &mut app,
+            get("/users/1/sessions", &admin).to_request(),
+        );
+        assert_eq!(entries.len(), 2);
+
+        let one = delete(&format!("/users/1/sessions/{}", entries[0]["id"]), &admin).to_request();
+        assert_eq!(call(&mut system, &mut app, one).status(), StatusCode::OK);
+        let all = delete("/users/1/sessions", &admin).to_request();
+        assert_eq!(call(&mut system, &mut app, all).status(), StatusCode::OK);
+
+        let alice = get("/sessions", &authorization(ALICE_OTHER)).to_request();
+        assert_eq!(
+            call(&mut system, &mut app, alice).status(),
+            StatusCode::UNAUTHORIZED
+        );
+    }
+
+    #[test]
+    fn session_endpoints_are_not_implemented_for_signed_tokens() {
+        let config = testing::config(
+            r#"
+            session_mode = "signed"
+            [signed_tokens]
+            signing_key = "1"
+            keys = { "1" = "MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY=" }
+            "#,
+        );
+        let claims = Claims::new(
+            3,
+            "admin".to_string(),
+            User::ROLE_ADMIN.to_string(),
+            vec!["users:read".to_string(), "users:write".to_string()],
+            60,
+        );
+        let token = signed_tokens::sign(&config.signed_tokens, &claims).unwrap();
+        let (mut system, actors) = testing::actors(seed);
+        let mut app = app!(config, actors);
+
+        for request in [
+            get("/sessions", &token),
+            delete("/sessions", &token),
+            delete("/sessions/1", &token),
+            get("/users/1/sessions", &token),
+            delete("/users/1/sessions", &token),
+            delete("/users/1/sessions/1", &token),
+        ] {
+            assert_eq!(
+                call(&mut system, &mut app, request.to_request()).status(),
+                StatusCode::NOT_IMPLEMENTED
+            );
+        }
+    }
+}
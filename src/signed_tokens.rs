@@ -0,0 +1,350 @@
+//! Stateless session tokens: JWT signed with HMAC-SHA256 (HS256).
+//!
+//! Tokens are signed with the key named by `signing_key` and can be verified with any key from
+//! `keys`, chosen by the `kid` header. To rotate keys, add a new key, switch `signing_key` to it
+//! and remove the old key after `lifetime` seconds.
+
+use actix_web::{dev::Body, web::HttpResponse, ResponseError};
+use failure::{format_err, Fail};
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+const ALGORITHM: &str = "HS256";
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct SignedTokensConfig {
+    /// How long (in seconds) the token is valid.
+    #[serde(default = "default_lifetime")]
+    pub lifetime: i64,
+    /// `kid` of the key used to sign new tokens.
+    #[serde(default)]
+    pub signing_key: String,
+    /// Base64 encoded secrets by their `kid`.
+    #[serde(default)]
+    pub keys: HashMap<String, String>,
+}
+
+impl Default for SignedTokensConfig {
+    fn default() -> Self {
+        Self {
+            lifetime: default_lifetime(),
+            signing_key: String::new(),
+            keys: HashMap::new(),
+        }
+    }
+}
+
+fn default_lifetime() -> i64 {
+    24 * 60 * 60
+}
+
+impl SignedTokensConfig {
+    /// Checks that the signing key is configured and all keys are valid.
+    pub fn validate(&self) -> Result<(), failure::Error> {
+        if !self.keys.contains_key(&self.signing_key) {
+            return Err(format_err!(
+                "Signing key `{}` is missing from signed_tokens.keys",
+                self.signing_key
+            ));
+        }
+
+        for (kid, key) in &self.keys {
+            match base64::decode(key) {
+                Ok(ref k) if k.len() >= 32 => (),
+                _ => {
+                    return Err(format_err!(
+                        "Key `{}` has to be base64 encoded and at least 32 bytes long",
+                        kid
+                    ))
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Fail)]
+pub enum TokenError {
+    #[fail(display = "Invalid token")]
+    Invalid,
+    #[fail(display = "Unknown signing key")]
+    UnknownKey,
+    #[fail(display = "Session expired")]
+    Expired,
+}
+
+impl ResponseError for TokenError {
+    fn error_response(&self) -> HttpResponse<Body> {
+        HttpResponse::Unauthorized().body(self.to_string())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Header {
+    alg: String,
+    typ: String,
+    kid: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Claims {
+    /// User ID.
+    pub sub: i32,
+    pub name: String,
+    pub role: String,
+    /// Permissions of the user at the time of logging in.
+    pub perms: Vec<String>,
+    pub iat: i64,
+    pub exp: i64,
+    /// Random token ID, used to revoke the token.
+    pub jti: String,
+}
+
+impl Claims {
+    pub fn new(
+        user_id: i32,
+        name: String,
+        role: String,
+        permissions: Vec<String>,
+        lifetime: i64,
+    ) -> Self {
+        let now = chrono::offset::Utc::now().timestamp();
+
+        let mut jti = [0u8; 16];
+        rand::thread_rng().fill(&mut jti);
+
+        Claims {
+            sub: user_id,
+            name,
+            role,
+            perms: permissions,
+            iat: now,
+            exp: now + lifetime,
+            jti: base64::encode_config(&jti, base64::URL_SAFE_NO_PAD),
+        }
+    }
+}
+
+pub fn sign(config: &SignedTokensConfig, claims: &Claims) -> Result<String, failure::Error> {
+    let key = config
+        .keys
+        .get(&config.signing_key)
+        .ok_or_else(|| format_err!("Signing key is not configured"))?;
+
+    let header = Header {
+        alg: ALGORITHM.to_string(),
+        typ: "JWT".to_string(),
+        kid: config.signing_key.clone(),
+    };
+
+    let message = format!(
+        "{}.{}",
+        encode_part(&serde_json::to_vec(&header)?),
+        encode_part(&serde_json::to_vec(claims)?)
+    );
+    let signature = hmac(&base64::decode(key)?, message.as_bytes())?;
+
+    Ok(format!("{}.{}", message, encode_part(&signature)))
+}
+
+/// Checks signature and expiration time of the token. Revocation has to be checked separately.
+pub fn verify(config: &SignedTokensConfig, token: &str) -> Result<Claims, TokenError> {
+    let mut parts = token.rsplitn(2, '.');
+    let (signature, message) = match (parts.next(), parts.next()) {
+        (Some(s), Some(m)) => (s, m),
+        _ => return Err(TokenError::Invalid),
+    };
+    let mut parts = message.splitn(2, '.');
+    let (header, claims) = match (parts.next(), parts.next()) {
+        (Some(h), Some(c)) => (h, c),
+        _ => return Err(TokenError::Invalid),
+    };
+
+    let header: Header = decode_json(header)?;
+    // Never let the token choose the algorithm
+    if header.alg != ALGORITHM {
+        return Err(TokenError::Invalid);
+    }
+
+    let key = config.keys.get(&header.kid).ok_or(TokenError::UnknownKey)?;
+    let key = base64::decode(key).map_err(|_| TokenError::UnknownKey)?;
+
+    let expected = hmac(&key, message.as_bytes()).map_err(|_| TokenError::Invalid)?;
+    let signature = decode_part(signature)?;
+    if signature.len() != expected.len() || !openssl::memcmp::eq(&signature, &expected) {
+        return Err(TokenError::Invalid);
+    }
+
+    let claims: Claims = decode_json(claims)?;
+    if claims.exp <= chrono::offset::Utc::now().timestamp() {
+        return Err(TokenError::Expired);
+    }
+
+    Ok(claims)
+}
+
+/// Signed tokens consist of three dot separated parts, unlike database session tokens.
+pub fn is_signed_token(token: &str) -> bool {
+    token.contains('.')
+}
+
+fn hmac(key: &[u8], message: &[u8]) -> Result<Vec<u8>, openssl::error::ErrorStack> {
+    let key = PKey::hmac(key)?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+    signer.update(message)?;
+
+    signer.sign_to_vec()
+}
+
+fn encode_part(data: &[u8]) -> String {
+    base64::encode_config(data, base64::URL_SAFE_NO_PAD)
+}
+
+pub(crate) fn decode_part(part: &str) -> Result<Vec<u8>, TokenError> {
+    base64::decode_config(part, base64::URL_SAFE_NO_PAD).map_err(|_| TokenError::Invalid)
+}
+
+pub(crate) fn decode_json<T: serde::de::DeserializeOwned>(part: &str) -> Result<T, TokenError> {
+    serde_json::from_slice(&decode_part(part)?).map_err(|_| TokenError::Invalid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY=";
+
+    fn config() -> SignedTokensConfig {
+        let mut keys = HashMap::new();
+        keys.insert("k1".to_string(), KEY.to_string());
+
+        SignedTokensConfig {
+            lifetime: 3600,
+            signing_key: "k1".to_string(),
+            keys,
+        }
+    }
+
+    fn claims(lifetime: i64) -> Claims {
+        Claims::new(
+            7,
+            "alice".to_string(),
+            "CUSTOMER".to_string(),
+            vec!["songs:read".to_string()],
+            lifetime,
+        )
+    }
+
+    /// Token with given header, signed with `KEY` regardless of the header.
+    fn forge(header: &Header, claims: &Claims) -> String {
+        let message = format!(
+            "{}.{}",
+            encode_part(&serde_json::to_vec(header).unwrap()),
+            encode_part(&serde_json::to_vec(claims).unwrap())
+        );
+        let signature = hmac(&base64::decode(KEY).unwrap(), message.as_bytes()).unwrap();
+
+        format!("{}.{}", message, encode_part(&signature))
+    }
+
+    fn header(alg: &str, kid: &str) -> Header {
+        Header {
+            alg: alg.to_string(),
+            typ: "JWT".to_string(),
+            kid: kid.to_string(),
+        }
+    }
+
+    fn error(token: &str) -> String {
+        verify(&config(), token).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn round_trip() {
+        let claims = claims(60);
+        let token = sign(&config(), &claims).unwrap();
+        assert!(is_signed_token(&token));
+
+        let verified = verify(&config(), &token).unwrap();
+        assert_eq!(verified.sub, claims.sub);
+        assert_eq!(verified.name, claims.name);
+        assert_eq!(verified.perms, claims.perms);
+        assert_eq!(verified.exp, claims.exp);
+        assert_eq!(verified.jti, claims.jti);
+    }
+
+    #[test]
+    fn tampered_payload_is_rejected() {
+        let token = sign(&config(), &claims(60)).unwrap();
+        let parts = token.split('.').collect::<Vec<_>>();
+
+        let mut admin = claims(60);
+        admin.role = "ADMIN".to_string();
+        let payload = encode_part(&serde_json::to_vec(&admin).unwrap());
+
+        assert_eq!(
+            error(&format!("{}.{}.{}", parts[0], payload, parts[2])),
+            "Invalid token"
+        );
+    }
+
+    #[test]
+    fn tampered_signature_is_rejected() {
+        let token = sign(&config(), &claims(60)).unwrap();
+        let (message, signature) = token.split_at(token.rfind('.').unwrap() + 1);
+
+        let mut signature = decode_part(signature).unwrap();
+        signature[0] ^= 1;
+        assert_eq!(
+            error(&format!("{}{}", message, encode_part(&signature))),
+            "Invalid token"
+        );
+        assert_eq!(
+            error(&format!("{}{}", message, encode_part(&signature[1..]))),
+            "Invalid token"
+        );
+        assert_eq!(error(message), "Invalid token");
+    }
+
+    #[test]
+    fn other_algorithms_are_rejected() {
+        let claims = claims(60);
+
+        assert_eq!(
+            error(&forge(&header("HS512", "k1"), &claims)),
+            "Invalid token"
+        );
+
+        let unsigned = forge(&header("none", "k1"), &claims);
+        let unsigned = &unsigned[..=unsigned.rfind('.').unwrap()];
+        assert_eq!(error(unsigned), "Invalid token");
+    }
+
+    #[test]
+    fn unknown_key_is_rejected() {
+        let token = forge(&header(ALGORITHM, "k2"), &claims(60));
+
+        assert_eq!(error(&token), "Unknown signing key");
+    }
+
+    #[test]
+    fn expired_token_is_rejected() {
+        let token = sign(&config(), &claims(0)).unwrap();
+        assert_eq!(error(&token), "Session expired");
+
+        let token = sign(&config(), &claims(-60)).unwrap();
+        assert_eq!(error(&token), "Session expired");
+    }
+
+    #[test]
+    fn malformed_token_is_rejected() {
+        assert_eq!(error(""), "Invalid token");
+        assert_eq!(error("a.b"), "Invalid token");
+        assert_eq!(error("not json.at.all"), "Invalid token");
+    }
+}
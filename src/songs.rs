@@ -1,7 +1,9 @@
 use actix_web::client::{self as awc, Client};
-use actix_web::error::{ErrorBadRequest, ErrorForbidden, ErrorInternalServerError};
-use actix_web::http::StatusCode;
-use actix_web::web::{Bytes, Data, Json, Path};
+use actix_web::error::{
+    ErrorBadRequest, ErrorForbidden, ErrorInternalServerError, ErrorPreconditionFailed,
+};
+use actix_web::http::{header, StatusCode};
+use actix_web::web::{Bytes, Data, HttpRequest, HttpResponse, Json, Path, Query};
 use actix_web::Error;
 use chrono::NaiveDateTime;
 use diesel::{
@@ -15,13 +17,17 @@ use futures::{
 use serde::{Deserialize, Serialize};
 
 use crate::auth::Auth;
+use crate::db::search::SearchSongs;
 use crate::db::songs::{
-    EditSong, GetAllArtists, GetAllGenres, GetHistory, GetMostPopular, Recognize,
+    DeleteSong, EditSong, GetAllArtists, GetAllGenres, GetAllSongs, GetHistory, GetMostPopular,
+    GetSong, GetSongRevisions, PatchSong, Recognize, RestoreSongRevision,
 };
+use crate::pagination::{Page, PageQuery};
+use crate::permissions::{Authorized, HistoryRead, Permission, SongsWrite};
 use crate::{Actors, Config};
 
 pub use crate::db::models::Song;
-pub use crate::db::songs::{AddSong, GetAllSongs as SongsFilter};
+pub use crate::db::songs::{AddSong, EditedSong, SongPatch, SongsFilter, SongsSort, SortOrder};
 use crate::db::DbExecutor;
 use actix::Addr;
 use actix_web::dev::Body;
@@ -35,6 +41,66 @@ pub struct SongRef {
     pub mse: f64,
 }
 
+/// Sortowanie utworów, domyślnie `?sort=id&order=asc`.
+#[derive(Debug, Deserialize)]
+pub struct SongsSortQuery {
+    /// `id`, `artist`, `title` lub `genre`.
+    #[serde(default)]
+    pub sort: Option<SongsSort>,
+    /// `asc` lub `desc`.
+    #[serde(default)]
+    pub order: Option<SortOrder>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SongDetails {
+    #[serde(flatten)]
+    pub song: Song,
+    /// Ile razy utwór został rozpoznany.
+    pub recognitions: i64,
+}
+
+/// Wartości utworu sprzed zmiany.
+#[derive(Debug, Serialize, Queryable)]
+pub struct SongRevision {
+    pub id: i32,
+    /// Wersja utworu, której dotyczą te wartości.
+    pub version: i32,
+    pub artist: String,
+    pub title: String,
+    pub genre: String,
+    pub url: String,
+    pub featured: bool,
+    /// Użytkownik, który zmienił utwór, `null` jeśli jego konto zostało usunięte.
+    pub user_id: Option<i32>,
+    pub login: Option<String>,
+    /// ISO 8601 / RFC 3339 format
+    pub changed_at: NaiveDateTime,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SearchQuery {
+    pub q: String,
+}
+
+/// Utwór znaleziony przez `GET /songs/search`.
+#[derive(Debug, Serialize)]
+pub struct SearchResult {
+    #[serde(flatten)]
+    pub song: Song,
+    /// Trafność wyniku, większa oznacza lepsze dopasowanie.
+    pub score: f64,
+    pub highlight: SearchHighlight,
+}
+
+/// Pola utworu jako HTML, w którym dopasowane słowa są otoczone `<mark>` i `</mark>`.
+#[derive(Debug, Serialize)]
+pub struct SearchHighlight {
+    pub artist: String,
+    pub title: String,
+    pub genre: String,
+}
+
 #[derive(Debug, Serialize, Queryable)]
 pub struct HistoryEntry {
     pub id: i32,
@@ -105,44 +171,63 @@ pub fn recognize(
         .map(Json)
 }
 
-/// `GET /history`
+/// `GET /history?limit=..&cursor=..`
 ///
-/// Zwraca historię wyszukiwania używkonika.
+/// Zwraca historię wyszukiwania używkonika, od najstarszych wpisów, stronicowaną zgodnie z
+/// `PageQuery`.
 pub fn history(
+    query: Query<PageQuery>,
     auth: Auth,
     actors: Data<Actors>,
-) -> impl Future<Item = Json<Vec<HistoryEntry>>, Error = Error> {
+) -> impl Future<Item = Json<Page<HistoryEntry>>, Error = Error> {
+    if !auth.has_scope(HistoryRead::NAME) {
+        return Either::A(future::err(ErrorForbidden("Missing API key scope")));
+    }
+    let page = match query.page() {
+        Ok(page) => page,
+        Err(e) => return Either::A(future::err(e)),
+    };
+
     let msg = GetHistory {
         user_id: Some(auth.id),
+        page: Some(page),
     };
 
-    actors
-        .db
-        .send(msg)
-        .map_err(ErrorInternalServerError)
-        .and_then(|r| r.map_err(Error::from).map(Json))
+    Either::B(
+        actors
+            .db
+            .send(msg)
+            .map_err(ErrorInternalServerError)
+            .and_then(|r| r.map_err(Error::from).map(Json)),
+    )
 }
 
-/// `GET /history/all`
+/// `GET /history/all?limit=..&cursor=..`
 ///
-/// Zwraca całą historię wyszukiwania. Wymaga uprawnień administratora.
+/// Zwraca całą historię wyszukiwania, stronicowaną zgodnie z `PageQuery`. Wymaga uprawnienia
+/// `history:read`.
 pub fn history_all(
-    auth: Auth,
+    query: Query<PageQuery>,
+    _auth: Authorized<HistoryRead>,
     actors: Data<Actors>,
-) -> impl Future<Item = Json<Vec<HistoryEntry>>, Error = Error> {
-    if !auth.is_admin {
-        Either::A(future::err(ErrorForbidden("not admin")))
-    } else {
-        let msg = GetHistory { user_id: None };
+) -> impl Future<Item = Json<Page<HistoryEntry>>, Error = Error> {
+    let page = match query.page() {
+        Ok(page) => page,
+        Err(e) => return Either::A(future::err(e)),
+    };
 
-        Either::B(
-            actors
-                .db
-                .send(msg)
-                .map_err(ErrorInternalServerError)
-                .and_then(|r| r.map_err(Error::from).map(Json)),
-        )
-    }
+    let msg = GetHistory {
+        user_id: None,
+        page: Some(page),
+    };
+
+    Either::B(
+        actors
+            .db
+            .send(msg)
+            .map_err(ErrorInternalServerError)
+            .and_then(|r| r.map_err(Error::from).map(Json)),
+    )
 }
 
 /// `GET /popular/{n}`
@@ -161,73 +246,275 @@ pub fn popular(
         .and_then(|r| r.map_err(Error::from).map(Json))
 }
 
-/// `POST /songs`
+/// `POST /songs?sort=..&order=..&limit=..&cursor=..`
 ///
-/// Zwraca wszystkie utwory.
+/// Zwraca utwory spełniające kryteria z `SongsFilter`, posortowane zgodnie z `SongsSortQuery` i
+/// stronicowane zgodnie z `PageQuery`.
 pub fn songs(
     filter: Json<SongsFilter>,
+    sort: Query<SongsSortQuery>,
+    query: Query<PageQuery>,
     actors: Data<Actors>,
-) -> impl Future<Item = Json<Vec<Song>>, Error = Error> {
-    let msg = filter.into_inner();
+) -> impl Future<Item = Json<Page<Song>>, Error = Error> {
+    let page = match query.page() {
+        Ok(page) => page,
+        Err(e) => return Either::A(future::err(e)),
+    };
+
+    let msg = GetAllSongs {
+        filter: filter.into_inner(),
+        sort: sort.sort.unwrap_or(SongsSort::Id),
+        order: sort.order.unwrap_or(SortOrder::Asc),
+        page,
+    };
+
+    Either::B(
+        actors
+            .db
+            .send(msg)
+            .map_err(ErrorInternalServerError)
+            .and_then(|r| r.map_err(Error::from).map(Json)),
+    )
+}
+
+/// `GET /songs/search?q=..&limit=..&cursor=..`
+///
+/// Wyszukuje utwory po wykonawcy, tytule i gatunku, od najlepiej dopasowanych. Wielkość liter i
+/// znaki diakrytyczne są pomijane, a słowa zapytania mogą być początkami słów, np. `zolta` lub
+/// `zol` znajdzie `Żółta`. Jeśli nic nie pasuje, szukane są też słowa różniące się o jedną lub
+/// dwie litery, aby znaleźć utwory mimo literówek. Wyniki są stronicowane zgodnie z `PageQuery`.
+pub fn search(
+    search: Query<SearchQuery>,
+    query: Query<PageQuery>,
+    actors: Data<Actors>,
+) -> impl Future<Item = Json<Page<SearchResult>>, Error = Error> {
+    let page = match query.page() {
+        Ok(page) => page,
+        Err(e) => return Either::A(future::err(e)),
+    };
+
+    let msg = SearchSongs {
+        query: search.into_inner().q,
+        page,
+    };
+
+    Either::B(
+        actors
+            .db
+            .send(msg)
+            .map_err(ErrorInternalServerError)
+            .and_then(|r| r.map_err(Error::from).map(Json)),
+    )
+}
 
+/// `GET /songs/{id}`
+///
+/// Zwraca utwór razem z liczbą jego rozpoznań, a w nagłówku `ETag` jego wersję. Zwraca Not Found,
+/// jeśli utwór nie istnieje lub został usunięty.
+pub fn song(
+    id: Path<i32>,
+    actors: Data<Actors>,
+) -> impl Future<Item = HttpResponse, Error = Error> {
     actors
         .db
-        .send(msg)
+        .send(GetSong { id: *id })
         .map_err(ErrorInternalServerError)
-        .and_then(|r| r.map_err(Error::from).map(Json))
+        .and_then(|r| r.map_err(Error::from))
+        .map(|details| {
+            HttpResponse::Ok()
+                .header(header::ETAG, etag(&details.song))
+                .json(details)
+        })
+}
+
+/// `PATCH /songs/{id}`
+///
+/// Zmienia tylko podane pola utworu, np. `{"title": "Arahja"}`. Jeśli podano nagłówek `If-Match`
+/// z wartością `ETag` z `GET /songs/{id}`, a utwór został w międzyczasie zmieniony, zwraca
+/// Precondition Failed. Zwraca zmieniony utwór i jego nowy `ETag`, lub Not Found, jeśli utwór nie
+/// istnieje lub został usunięty. Wymaga uprawnienia `songs:write`.
+pub fn patch_song(
+    id: Path<i32>,
+    patch: Json<SongPatch>,
+    request: HttpRequest,
+    auth: Authorized<SongsWrite>,
+    actors: Data<Actors>,
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    let expected_version = match if_match_version(&request) {
+        Ok(v) => v,
+        Err(e) => return Either::A(future::err(e)),
+    };
+
+    let msg = PatchSong {
+        id: *id,
+        patch: patch.into_inner(),
+        expected_version,
+        user_id: auth.id,
+    };
+
+    Either::B(
+        actors
+            .db
+            .send(msg)
+            .map_err(ErrorInternalServerError)
+            .and_then(|r| r.map_err(Error::from))
+            .map(|song| {
+                HttpResponse::Ok()
+                    .header(header::ETAG, etag(&song))
+                    .json(song)
+            }),
+    )
+}
+
+fn etag(song: &Song) -> String {
+    format!("\"{}\"", song.version)
+}
+
+/// Version from the `If-Match` header, `None` if it's missing or `*`. Weak or unknown tags never
+/// match.
+fn if_match_version(request: &HttpRequest) -> Result<Option<i32>, Error> {
+    let value = match request.headers().get(header::IF_MATCH) {
+        Some(value) => value.to_str().unwrap_or("").trim(),
+        None => return Ok(None),
+    };
+    if value == "*" {
+        return Ok(None);
+    }
+
+    let version = if value.len() >= 2 && value.starts_with('"') && value.ends_with('"') {
+        value[1..value.len() - 1].parse().ok()
+    } else {
+        None
+    };
+
+    match version {
+        Some(version) => Ok(Some(version)),
+        None => Err(ErrorPreconditionFailed("Song was changed in the meantime")),
+    }
+}
+
+/// `DELETE /songs/{id}`
+///
+/// Oznacza utwór jako usunięty. Usunięty utwór nie jest zwracany w wynikach rozpoznawania ani
+/// wyszukiwania, a wpisy w historii wyszukiwania pozostają. Utwór nie jest usuwany z indeksu
+/// populatora, który nie udostępnia takiej operacji. Zwraca Not Found, jeśli utwór nie istnieje
+/// lub został już usunięty. Wymaga uprawnienia `songs:write`.
+pub fn delete_song(
+    id: Path<i32>,
+    _auth: Authorized<SongsWrite>,
+    actors: Data<Actors>,
+) -> impl Future<Item = (), Error = Error> {
+    actors
+        .db
+        .send(DeleteSong { id: *id })
+        .map_err(ErrorInternalServerError)
+        .and_then(|r| r.map_err(Error::from))
+}
+
+/// `GET /songs/{id}/revisions?limit=..&cursor=..`
+///
+/// Zwraca poprzednie wersje metadanych utworu, od najnowszych, razem z autorem i czasem zmiany.
+/// Wyniki są stronicowane zgodnie z `PageQuery`. Wymaga uprawnienia `songs:write`.
+pub fn song_revisions(
+    id: Path<i32>,
+    query: Query<PageQuery>,
+    _auth: Authorized<SongsWrite>,
+    actors: Data<Actors>,
+) -> impl Future<Item = Json<Page<SongRevision>>, Error = Error> {
+    let page = match query.page() {
+        Ok(page) => page,
+        Err(e) => return Either::A(future::err(e)),
+    };
+
+    let msg = GetSongRevisions { song_id: *id, page };
+
+    Either::B(
+        actors
+            .db
+            .send(msg)
+            .map_err(ErrorInternalServerError)
+            .and_then(|r| r.map_err(Error::from).map(Json)),
+    )
+}
+
+/// `POST /songs/{id}/revisions/{revision_id}/restore`
+///
+/// Przywraca metadane utworu z wybranej wersji. Aktualne wartości są zapisywane jako nowa wersja,
+/// więc przywrócenie można cofnąć. Obsługuje nagłówek `If-Match` tak jak `PATCH /songs/{id}`.
+/// Zwraca zmieniony utwór i jego nowy `ETag`. Wymaga uprawnienia `songs:write`.
+pub fn restore_song_revision(
+    path: Path<(i32, i32)>,
+    request: HttpRequest,
+    auth: Authorized<SongsWrite>,
+    actors: Data<Actors>,
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    let expected_version = match if_match_version(&request) {
+        Ok(v) => v,
+        Err(e) => return Either::A(future::err(e)),
+    };
+
+    let msg = RestoreSongRevision {
+        song_id: path.0,
+        revision_id: path.1,
+        expected_version,
+        user_id: auth.id,
+    };
+
+    Either::B(
+        actors
+            .db
+            .send(msg)
+            .map_err(ErrorInternalServerError)
+            .and_then(|r| r.map_err(Error::from))
+            .map(|song| {
+                HttpResponse::Ok()
+                    .header(header::ETAG, etag(&song))
+                    .json(song)
+            }),
+    )
 }
 
 /// `POST /edit_song`
 ///
-/// Zastępuje metadane utworu nowymi. Jeśli ID jest nieprawidłowe, zwraca Not Found.
+/// Zastępuje metadane utworu nowymi. Jeśli ID jest nieprawidłowe, zwraca Not Found. Wymaga
+/// uprawnienia `songs:write`.
 pub fn edit_song(
-    song: Json<Song>,
-    auth: Auth,
+    song: Json<EditedSong>,
+    auth: Authorized<SongsWrite>,
     actors: Data<Actors>,
 ) -> impl Future<Item = (), Error = Error> {
-    if !auth.is_admin {
-        Either::A(future::err(ErrorForbidden("not admin")))
-    } else {
-        let msg = EditSong {
-            song: song.into_inner(),
-        };
-
-        Either::B(
-            actors
-                .db
-                .send(msg)
-                .map_err(ErrorInternalServerError)
-                .and_then(|r| r.map_err(Error::from)),
-        )
-    }
+    let msg = EditSong {
+        song: song.into_inner(),
+        user_id: auth.id,
+    };
+
+    actors
+        .db
+        .send(msg)
+        .map_err(ErrorInternalServerError)
+        .and_then(|r| r.map_err(Error::from))
 }
 
 /// `POST /add_song`
 ///
 /// Dodaje nowy utwór. Zwraca BadRequest jeśli nie uda się pobrać pliku z podanego URLa. Wymaga
-/// uprawnień administratora.
+/// uprawnienia `songs:write`.
 pub fn add_song(
     songs: Json<Vec<AddSong>>,
-    auth: Auth,
+    _auth: Authorized<SongsWrite>,
     actors: Data<Actors>,
     config: Data<Config>,
 ) -> impl Future<Item = (), Error = Error> {
-    if !auth.is_admin {
-        Either::A(future::err(ErrorForbidden("not admin")))
-    } else {
-        let db = actors.db.clone();
-        let populator_url = config.populator.clone();
-
-        Either::B(
-            future::join_all(
-                songs
-                    .into_inner()
-                    .into_iter()
-                    .map(move |song| send_song(song, populator_url.clone(), db.clone())),
-            )
-            .and_then(|_| future::ok(())),
-        )
-    }
+    let db = actors.db.clone();
+    let populator_url = config.populator.clone();
+
+    future::join_all(
+        songs
+            .into_inner()
+            .into_iter()
+            .map(move |song| send_song(song, populator_url.clone(), db.clone())),
+    )
+    .and_then(|_| future::ok(()))
 }
 
 fn send_song(
@@ -299,3 +586,114 @@ pub fn artists(actors: Data<Actors>) -> impl Future<Item = Json<Vec<String>>, Er
         .map_err(ErrorInternalServerError)
         .and_then(|r| r.map_err(Error::from).map(Json))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::models::User;
+    use crate::db::songs::AddSong;
+    use crate::db::{test_song as song, test_user};
+    use crate::testing::{self, authorization, call, call_json};
+    use actix_web::test::{init_service, TestRequest};
+    use actix_web::{web, App};
+
+    const EDITOR: [u8; 32] = [1; 32];
+
+    fn if_match(value: &str) -> Result<Option<i32>, Error> {
+        if_match_version(
+            &TestRequest::default()
+                .header(header::IF_MATCH, value)
+                .to_http_request(),
+        )
+    }
+
+    fn status(result: Result<Option<i32>, Error>) -> StatusCode {
+        result
+            .unwrap_err()
+            .as_response_error()
+            .error_response()
+            .status()
+    }
+
+    #[test]
+    fn missing_header_and_wildcard_skip_the_check() {
+        let request = TestRequest::default().to_http_request();
+        assert_eq!(if_match_version(&request).unwrap(), None);
+        assert_eq!(if_match("*").unwrap(), None);
+    }
+
+    #[test]
+    fn strong_tag_round_trips_through_etag() {
+        assert_eq!(if_match(&etag(&song(3))).unwrap(), Some(3));
+        assert_eq!(if_match(" \"3\" ").unwrap(), Some(3));
+    }
+
+    #[test]
+    fn weak_and_malformed_tags_fail_the_precondition() {
+        for value in &["W/\"3\"", "3", "\"", "\"abc\"", "abc", ""] {
+            assert_eq!(status(if_match(value)), StatusCode::PRECONDITION_FAILED);
+        }
+    }
+
+    /// Admin with a session and a song in version 1.
+    fn seed(conn: &diesel::SqliteConnection) {
+        use crate::db::schema::songs::dsl::songs;
+        use diesel::prelude::*;
+
+        let admin = test_user(conn, "admin", User::ROLE_ADMIN);
+        testing::session(conn, admin, EDITOR);
+        diesel::insert_into(songs)
+            .values(&AddSong {
+                artist: "Kult".to_owned(),
+                title: "Arahja".to_owned(),
+                genre: "Rock".to_owned(),
+                url: "https://example.com".to_owned(),
+            })
+            .execute(conn)
+            .unwrap();
+    }
+
+    fn patch(title: &str, tag: &str) -> TestRequest {
+        TestRequest::patch()
+            .uri("/songs/1")
+            .header("Authorization", authorization(EDITOR))
+            .header(header::IF_MATCH, tag)
+            .header(header::CONTENT_TYPE, "application/json")
+            .set_payload(serde_json::json!({ "title": title }).to_string())
+    }
+
+    #[test]
+    fn patch_with_stale_tag_fails_the_precondition() {
+        let (mut system, actors) = testing::actors(seed);
+        let mut app = init_service(
+            App::new()
+                .data(testing::config(""))
+                .data(actors)
+                .service(web::resource("/songs/{id}").route(web::patch().to_async(patch_song))),
+        );
+
+        let response = call(
+            &mut system,
+            &mut app,
+            patch("Arahja (live)", "\"1\"").to_request(),
+        );
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers().get(header::ETAG).unwrap(), "\"2\"");
+
+        // The song is already in version 2
+        let response = call(
+            &mut system,
+            &mut app,
+            patch("Arahja (demo)", "\"1\"").to_request(),
+        );
+        assert_eq!(response.status(), StatusCode::PRECONDITION_FAILED);
+
+        let song: serde_json::Value = call_json(
+            &mut system,
+            &mut app,
+            patch("Arahja (demo)", "\"2\"").to_request(),
+        );
+        assert_eq!(song["title"], "Arahja (demo)");
+        assert_eq!(song["version"], 3);
+    }
+}
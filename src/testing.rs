@@ -0,0 +1,102 @@
+//! Helpers for tests of HTTP handlers: configuration, actors backed by an in-memory database and
+//! session tokens.
+
+use actix::{SyncArbiter, SystemRunner};
+use actix_service::Service;
+use actix_web::dev::ServiceResponse;
+use actix_web::test::read_body;
+use actix_web::Error;
+use diesel::prelude::*;
+use futures::future;
+use serde::de::DeserializeOwned;
+
+use crate::db::models::NewSession;
+use crate::db::passwords::PasswordHashConfig;
+use crate::db::sessions::token_hash;
+use crate::db::{test_connection, DbExecutor};
+use crate::hasher::{Hasher, HasherConfig};
+use crate::{Actors, Config};
+
+/// Configuration with required values only, `extra` is added at the top level.
+pub fn config(extra: &str) -> Config {
+    toml::from_str(&format!(
+        r#"
+        populator = "http://127.0.0.1:1"
+        scraper = "http://127.0.0.1:1"
+        extractor = "http://127.0.0.1:1"
+        bind_addr = "127.0.0.1:0"
+        db_path = ":memory:"
+        db_threads = 1
+        max_song_size = 1024
+        {}
+
+        [password_reset]
+        delivery = "file"
+        path = "/dev/null"
+        "#,
+        extra
+    ))
+    .unwrap()
+}
+
+/// Starts the actors, the database is prepared by `seed`. Requests have to be run with `call` on
+/// the returned system, which dispatches messages to the actors.
+pub fn actors<F>(seed: F) -> (SystemRunner, Actors)
+where
+    F: Fn(&SqliteConnection) + Send + Sync + 'static,
+{
+    let system = actix::System::new("test");
+    let db = SyncArbiter::start(1, move || {
+        let conn = test_connection();
+        seed(&conn);
+
+        DbExecutor(conn)
+    });
+    let hasher = Hasher::start(&HasherConfig::default(), &PasswordHashConfig::default());
+
+    (system, Actors { db, hasher })
+}
+
+/// Runs the request on the system started by `actors`.
+pub fn call<S, R>(system: &mut SystemRunner, app: &mut S, request: R) -> ServiceResponse
+where
+    S: Service<Request = R, Response = ServiceResponse, Error = Error>,
+{
+    system.block_on(future::lazy(|| app.call(request))).unwrap()
+}
+
+/// Runs the request and parses the response as JSON.
+pub fn call_json<S, R, T>(system: &mut SystemRunner, app: &mut S, request: R) -> T
+where
+    S: Service<Request = R, Response = ServiceResponse, Error = Error>,
+    T: DeserializeOwned,
+{
+    let response = call(system, app, request);
+    assert!(response.status().is_success(), "{:?}", response);
+
+    serde_json::from_slice(&read_body(response)).unwrap()
+}
+
+/// Creates a session of the user with given token.
+pub fn session(conn: &SqliteConnection, user_id: i32, token: [u8; 32]) {
+    use crate::db::schema::sessions::dsl::sessions;
+
+    let now = chrono::offset::Utc::now().naive_utc();
+    diesel::insert_into(sessions)
+        .values(&NewSession {
+            token: &token_hash(&token),
+            user_id,
+            created_at: now,
+            last_active: now,
+            family: None,
+            ip_addr: "192.0.2.1",
+            user_agent: "",
+        })
+        .execute(conn)
+        .unwrap();
+}
+
+/// Value of the `Authorization` header for the session token.
+pub fn authorization(token: [u8; 32]) -> String {
+    base64::encode(&token)
+}
@@ -0,0 +1,229 @@
+//! Time-based one-time passwords (RFC 6238) compatible with Google Authenticator and similar
+//! applications: HMAC-SHA1, 6 digits, 30 second time step.
+
+use openssl::error::ErrorStack;
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use rand::Rng;
+use url::Url;
+
+pub const SECRET_SIZE: usize = 20;
+const TIME_STEP: i64 = 30;
+const DIGITS: u32 = 6;
+/// Number of time steps before and after the current one accepted to compensate clock drift.
+const ALLOWED_DRIFT: i64 = 1;
+
+const BASE32: base32::Alphabet = base32::Alphabet::RFC4648 { padding: false };
+
+pub fn generate_secret() -> [u8; SECRET_SIZE] {
+    let mut secret = [0u8; SECRET_SIZE];
+    rand::thread_rng().fill(&mut secret);
+
+    secret
+}
+
+pub fn encode_secret(secret: &[u8]) -> String {
+    base32::encode(BASE32, secret)
+}
+
+pub fn decode_secret(secret: &str) -> Option<Vec<u8>> {
+    base32::decode(BASE32, secret)
+}
+
+/// URI which can be turned into a QR code and scanned by an authenticator app.
+pub fn otpauth_uri(issuer: &str, account: &str, secret: &str) -> String {
+    let mut uri = Url::parse("otpauth://totp/").expect("Invalid otpauth URI");
+    uri.set_path(&format!("{}:{}", issuer, account));
+    uri.query_pairs_mut()
+        .append_pair("secret", secret)
+        .append_pair("issuer", issuer)
+        .append_pair("algorithm", "SHA1")
+        .append_pair("digits", &DIGITS.to_string())
+        .append_pair("period", &TIME_STEP.to_string());
+
+    uri.into_string()
+}
+
+/// Checks the code against the current time. Returns time step matching the code, which must be
+/// stored and passed as `last_step` in the next call, so the same code can't be used twice.
+pub fn verify(
+    secret: &[u8],
+    code: &str,
+    last_step: Option<i64>,
+) -> Result<Option<i64>, ErrorStack> {
+    verify_at(
+        secret,
+        code,
+        last_step,
+        chrono::offset::Utc::now().timestamp(),
+    )
+}
+
+/// `verify` at given Unix time.
+fn verify_at(
+    secret: &[u8],
+    code: &str,
+    last_step: Option<i64>,
+    timestamp: i64,
+) -> Result<Option<i64>, ErrorStack> {
+    let code = code.trim();
+    if code.len() != DIGITS as usize || !code.bytes().all(|b| b.is_ascii_digit()) {
+        return Ok(None);
+    }
+
+    let current_step = timestamp / TIME_STEP;
+
+    for step in current_step - ALLOWED_DRIFT..=current_step + ALLOWED_DRIFT {
+        match last_step {
+            Some(last) if step <= last => continue,
+            _ => (),
+        }
+
+        let expected = format!(
+            "{:0width$}",
+            hotp(secret, step as u64, DIGITS)?,
+            width = DIGITS as usize
+        );
+        if openssl::memcmp::eq(expected.as_bytes(), code.as_bytes()) {
+            return Ok(Some(step));
+        }
+    }
+
+    Ok(None)
+}
+
+/// HOTP value (RFC 4226) with given number of digits for given counter.
+fn hotp(secret: &[u8], counter: u64, digits: u32) -> Result<u32, ErrorStack> {
+    let key = PKey::hmac(secret)?;
+    let mut signer = Signer::new(MessageDigest::sha1(), &key)?;
+    signer.update(&counter.to_be_bytes())?;
+    let hmac = signer.sign_to_vec()?;
+
+    let offset = (hmac[hmac.len() - 1] & 0x0f) as usize;
+    let binary = (u32::from(hmac[offset]) & 0x7f) << 24
+        | u32::from(hmac[offset + 1]) << 16
+        | u32::from(hmac[offset + 2]) << 8
+        | u32::from(hmac[offset + 3]);
+
+    Ok(binary % 10u32.pow(digits))
+}
+
+/// Code valid at the current time.
+#[cfg(test)]
+pub(crate) fn current_code(secret: &[u8]) -> String {
+    let step = chrono::offset::Utc::now().timestamp() / TIME_STEP;
+
+    format!(
+        "{:0width$}",
+        hotp(secret, step as u64, DIGITS).unwrap(),
+        width = DIGITS as usize
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &[u8] = b"12345678901234567890";
+
+    fn code_at(timestamp: i64) -> String {
+        format!(
+            "{:06}",
+            hotp(SECRET, (timestamp / TIME_STEP) as u64, DIGITS).unwrap()
+        )
+    }
+
+    #[test]
+    fn rfc4226_vectors() {
+        let expected = [
+            755224, 287082, 359152, 969429, 338314, 254676, 287922, 162583, 399871, 520489,
+        ];
+
+        for (counter, value) in expected.iter().enumerate() {
+            assert_eq!(hotp(SECRET, counter as u64, 6).unwrap(), *value);
+        }
+    }
+
+    #[test]
+    fn rfc6238_sha1_vectors() {
+        let expected = [
+            (59, 94287082),
+            (1111111109, 7081804),
+            (1111111111, 14050471),
+            (1234567890, 89005924),
+            (2000000000, 69279037),
+            (20000000000, 65353130),
+        ];
+
+        for (time, value) in expected.iter() {
+            assert_eq!(hotp(SECRET, (time / TIME_STEP) as u64, 8).unwrap(), *value);
+        }
+    }
+
+    #[test]
+    fn accepts_current_code() {
+        let now = 1234567890;
+
+        assert_eq!(
+            verify_at(SECRET, &code_at(now), None, now).unwrap(),
+            Some(now / TIME_STEP)
+        );
+        assert_eq!(
+            verify_at(SECRET, "005924", None, now).unwrap(),
+            Some(now / TIME_STEP)
+        );
+    }
+
+    #[test]
+    fn accepts_one_step_of_drift() {
+        let now = 1234567890;
+
+        for drift in &[-1, 1] {
+            let time = now + drift * TIME_STEP;
+            assert_eq!(
+                verify_at(SECRET, &code_at(time), None, now).unwrap(),
+                Some(time / TIME_STEP)
+            );
+        }
+        for drift in &[-2, 2] {
+            let time = now + drift * TIME_STEP;
+            assert_eq!(verify_at(SECRET, &code_at(time), None, now).unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn rejects_replayed_code() {
+        let now = 1234567890;
+        let code = code_at(now);
+
+        let step = verify_at(SECRET, &code, None, now).unwrap();
+        assert_eq!(verify_at(SECRET, &code, step, now).unwrap(), None);
+        assert_eq!(
+            verify_at(SECRET, &code, step, now + TIME_STEP).unwrap(),
+            None
+        );
+        // Codes older than the last accepted one are rejected too
+        let previous = code_at(now - TIME_STEP);
+        assert_eq!(verify_at(SECRET, &previous, step, now).unwrap(), None);
+        // The next code is still accepted
+        let next = code_at(now + TIME_STEP);
+        assert_eq!(
+            verify_at(SECRET, &next, step, now).unwrap(),
+            Some(now / TIME_STEP + 1)
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_code() {
+        let now = 1234567890;
+
+        assert_eq!(verify_at(SECRET, "05924", None, now).unwrap(), None);
+        assert_eq!(verify_at(SECRET, "0059245", None, now).unwrap(), None);
+        assert_eq!(verify_at(SECRET, "00592a", None, now).unwrap(), None);
+        assert_eq!(
+            verify_at(SECRET, " 005924 ", None, now).unwrap(),
+            Some(now / TIME_STEP)
+        );
+    }
+}
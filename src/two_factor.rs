@@ -0,0 +1,253 @@
+use actix_web::error::{ErrorBadRequest, ErrorInternalServerError};
+use actix_web::web::{Data, Json, Path};
+use actix_web::{Error, HttpRequest};
+use futures::{
+    future::{self, Either},
+    Future,
+};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::auth::{client_info, create_session, decode_token, Auth, LoginResponse};
+use crate::db::two_factor::{
+    ConfirmTwoFactor, DisableTwoFactor, EnrollTwoFactor, VerifyLoginChallenge,
+};
+use crate::permissions::{Authorized, UsersWrite};
+use crate::totp;
+use crate::{Actors, Config};
+
+const RECOVERY_CODES: usize = 10;
+/// Without characters which are easy to confuse (0/o, 1/l).
+const RECOVERY_CODE_ALPHABET: &[u8] = b"abcdefghijkmnpqrstuvwxyz23456789";
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct TwoFactorConfig {
+    /// Name of the service shown in authenticator apps.
+    #[serde(default = "default_issuer")]
+    pub issuer: String,
+    /// Admins without two-factor authentication enabled have no permissions.
+    #[serde(default)]
+    pub required_for_admins: bool,
+    /// How long (in seconds) the user has to enter the code after entering the password.
+    #[serde(default = "default_challenge_lifetime")]
+    pub challenge_lifetime: i64,
+    /// Number of invalid codes after which the login has to be started again.
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: u32,
+}
+
+impl Default for TwoFactorConfig {
+    fn default() -> Self {
+        Self {
+            issuer: default_issuer(),
+            required_for_admins: false,
+            challenge_lifetime: default_challenge_lifetime(),
+            max_attempts: default_max_attempts(),
+        }
+    }
+}
+
+fn default_issuer() -> String {
+    "Szaklon".to_string()
+}
+
+fn default_challenge_lifetime() -> i64 {
+    5 * 60
+}
+
+fn default_max_attempts() -> u32 {
+    5
+}
+
+/// Drugi krok logowania: token z odpowiedzi `POST /login` oraz kod z aplikacji
+/// uwierzytelniającej lub jeden z kodów odzyskiwania.
+#[derive(Debug, Deserialize)]
+pub struct ChallengeData {
+    pub challenge: String,
+    pub code: String,
+}
+
+/// Kod z aplikacji uwierzytelniającej lub kod odzyskiwania.
+#[derive(Debug, Deserialize)]
+pub struct CodeData {
+    pub code: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct EnrollResponse {
+    /// Sekret w formacie base32, do ręcznego wpisania w aplikacji uwierzytelniającej.
+    pub secret: String,
+    /// Adres `otpauth://` do zakodowania w kodzie QR.
+    pub uri: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RecoveryCodesResponse {
+    /// Jednorazowe kody, które można podać zamiast kodu z aplikacji. Są zwracane tylko raz.
+    pub recovery_codes: Vec<String>,
+}
+
+/// `POST /login/2fa`
+///
+/// Kończy logowanie użytkownika z włączonym uwierzytelnianiem dwuskładnikowym. Zwraca to samo co
+/// `POST /login` dla użytkowników bez uwierzytelniania dwuskładnikowego.
+///
+/// W przypadku nieprawidłowego kodu zwraca BadRequest. Po `max_attempts` nieprawidłowych kodach,
+/// albo po `challenge_lifetime` sekundach (sekcja `[two_factor]` pliku konfiguracyjnego) zwraca
+/// Unauthorized i logowanie trzeba rozpocząć od nowa.
+pub fn login_two_factor(
+    data: Json<ChallengeData>,
+    actors: Data<Actors>,
+    config: Data<Config>,
+    request: HttpRequest,
+) -> impl Future<Item = Json<LoginResponse>, Error = Error> {
+    let data = data.into_inner();
+    let token = match decode_token(&data.challenge, base64::STANDARD) {
+        Some(token) => token,
+        None => return Either::A(future::err(ErrorBadRequest("Invalid challenge"))),
+    };
+
+    let msg = VerifyLoginChallenge {
+        token,
+        code: data.code,
+        max_attempts: config.two_factor.max_attempts,
+    };
+
+    let client = client_info(&request, &config);
+    let db = actors.db.clone();
+
+    Either::B(
+        actors
+            .db
+            .send(msg)
+            .map_err(ErrorInternalServerError)
+            .and_then(|r| r.map_err(Error::from))
+            .and_then(move |info| create_session(db, info, client, &config).map(Json)),
+    )
+}
+
+/// `POST /account/2fa/enroll`
+///
+/// Rozpoczyna włączanie uwierzytelniania dwuskładnikowego (TOTP). Zwraca nowy sekret, który
+/// należy dodać do aplikacji uwierzytelniającej, a następnie potwierdzić kodem przez
+/// `POST /account/2fa/confirm`. Jeśli uwierzytelnianie dwuskładnikowe jest już włączone, zwraca
+/// Conflict.
+pub fn enroll(
+    auth: Auth,
+    actors: Data<Actors>,
+    config: Data<Config>,
+) -> impl Future<Item = Json<EnrollResponse>, Error = Error> {
+    if let Err(e) = auth.require_session() {
+        return Either::A(future::err(e));
+    }
+
+    let secret = totp::encode_secret(&totp::generate_secret());
+    let uri = totp::otpauth_uri(&config.two_factor.issuer, &auth.username, &secret);
+
+    let msg = EnrollTwoFactor {
+        user_id: auth.id,
+        secret: secret.clone(),
+    };
+
+    Either::B(
+        actors
+            .db
+            .send(msg)
+            .map_err(ErrorInternalServerError)
+            .and_then(|r| r.map_err(Error::from))
+            .map(move |_| Json(EnrollResponse { secret, uri })),
+    )
+}
+
+/// `POST /account/2fa/confirm`
+///
+/// Włącza uwierzytelnianie dwuskładnikowe po podaniu poprawnego kodu z aplikacji. Zwraca kody
+/// odzyskiwania, każdy z nich może zostać użyty raz zamiast kodu z aplikacji. W przypadku
+/// nieprawidłowego kodu zwraca BadRequest.
+pub fn confirm(
+    data: Json<CodeData>,
+    auth: Auth,
+    actors: Data<Actors>,
+) -> impl Future<Item = Json<RecoveryCodesResponse>, Error = Error> {
+    if let Err(e) = auth.require_session() {
+        return Either::A(future::err(e));
+    }
+
+    let recovery_codes = generate_recovery_codes();
+
+    let msg = ConfirmTwoFactor {
+        user_id: auth.id,
+        code: data.into_inner().code,
+        recovery_codes: recovery_codes.clone(),
+    };
+
+    Either::B(
+        actors
+            .db
+            .send(msg)
+            .map_err(ErrorInternalServerError)
+            .and_then(|r| r.map_err(Error::from))
+            .map(move |_| Json(RecoveryCodesResponse { recovery_codes })),
+    )
+}
+
+/// `DELETE /account/2fa`
+///
+/// Wyłącza uwierzytelnianie dwuskładnikowe. Wymaga aktualnego kodu z aplikacji lub kodu
+/// odzyskiwania, w przypadku nieprawidłowego kodu zwraca BadRequest.
+pub fn disable(
+    data: Json<CodeData>,
+    auth: Auth,
+    actors: Data<Actors>,
+) -> impl Future<Item = (), Error = Error> {
+    if let Err(e) = auth.require_session() {
+        return Either::A(future::err(e));
+    }
+
+    let msg = DisableTwoFactor {
+        user_id: auth.id,
+        code: Some(data.into_inner().code),
+    };
+
+    Either::B(
+        actors
+            .db
+            .send(msg)
+            .map_err(ErrorInternalServerError)
+            .and_then(|r| r.map_err(Error::from)),
+    )
+}
+
+/// `DELETE /users/{id}/2fa`
+///
+/// Wyłącza uwierzytelnianie dwuskładnikowe wybranego użytkownika, np. po utracie telefonu i kodów
+/// odzyskiwania. Wymaga uprawnienia `users:write`.
+pub fn disable_admin(
+    id: Path<i32>,
+    _auth: Authorized<UsersWrite>,
+    actors: Data<Actors>,
+) -> impl Future<Item = (), Error = Error> {
+    actors
+        .db
+        .send(DisableTwoFactor {
+            user_id: *id,
+            code: None,
+        })
+        .map_err(ErrorInternalServerError)
+        .and_then(|r| r.map_err(Error::from))
+}
+
+fn generate_recovery_codes() -> Vec<String> {
+    let mut rng = rand::thread_rng();
+
+    (0..RECOVERY_CODES)
+        .map(|_| {
+            let chars = (0..10)
+                .map(|_| RECOVERY_CODE_ALPHABET[rng.gen_range(0, RECOVERY_CODE_ALPHABET.len())])
+                .map(char::from)
+                .collect::<String>();
+
+            format!("{}-{}", &chars[..5], &chars[5..])
+        })
+        .collect()
+}
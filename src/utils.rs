@@ -16,3 +16,21 @@ impl PerfLog {
         log::debug!("perf: {} {}ms", name, diff.as_micros() as f64 / 1_000.0);
     }
 }
+
+/// Lowercases the text and removes diacritics, e.g. `Żółć` becomes `zolc`.
+pub fn fold_diacritics(s: &str) -> String {
+    use unicode_normalization::{char::is_combining_mark, UnicodeNormalization};
+
+    s.nfkd()
+        .filter(|c| !is_combining_mark(*c))
+        .flat_map(char::to_lowercase)
+        .map(|c| match c {
+            // Letters with strokes don't decompose
+            'ł' => 'l',
+            'đ' => 'd',
+            'ø' => 'o',
+            'ħ' => 'h',
+            c => c,
+        })
+        .collect()
+}